@@ -13,14 +13,14 @@ impl AddAndDivide {
         }
     }
 
-    pub fn process(&mut self, inputs: &Vec<Buffer>, block_size: usize, div: f32) -> Buffer {
-        let mut output = Buffer::new(block_size, "AddAndDivide".to_string());
+    pub fn process<'a, I>(&mut self, inputs: I, output: &mut Buffer, div: f32) where I: Iterator<Item = &'a Buffer> + Clone {
+        let block_size = output.get_size();
 
         let mult = (0.5 + 0.4 * (1.0 / div).sqrt()).min(1.0);
 
         for i in 0..block_size {
             let mut sum = 0.0;
-            for input in inputs {
+            for input in inputs.clone() {
                 sum += input[i];
             }
 
@@ -34,11 +34,9 @@ impl AddAndDivide {
                 sum - (sum.powf(3.0) / 3.0)
             };
 
-            output.write(sum);
-            output.tick();
+            output[i] = sum;
         }
 
         // self.hpf.process(&mut output);
-        output
     }
 }
\ No newline at end of file
@@ -118,6 +118,9 @@ impl Buffer {
     pub fn as_vec(&self) -> Vec<f32> {
         self.data.clone()
     }
+    pub fn as_slice(&self) -> &[f32] {
+        &self.data
+    }
     pub fn from_vec(data: Vec<f32>) -> Self {
         Self {
             size: data.len(),
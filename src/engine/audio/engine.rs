@@ -2,14 +2,32 @@
 // Handles audio processing callbacks and performs synthesis
 
 use std::sync::mpsc::Sender;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use crate::dsp::buffer::Buffer;
-use crate::engine::midi::{MidiInputHandler, MidiMessage};
+use crate::engine::clock::{Clock, ClockSync, TimeSignature, MAX_GENERATORS};
+use crate::engine::midi::{MidiEvent, MidiEventScheduler, MidiInputHandler, MidiMessage, MidiOutputHandler};
+use crate::engine::midi_file::MidiFile;
+use crate::engine::mixer::{Mixer, Part, MAX_PARTS};
+use crate::engine::mpe::MpeZone;
+use crate::engine::note_handler::Half;
+use crate::engine::osc::{osc_address, OscServer};
+use crate::engine::recorder::Recorder;
 use crate::engine::synthesis::Synth;
+use crate::generators::arpeggiator::ArpSettings;
+use crate::generators::{Generator, GeneratorSettings, Rate};
+use crate::modulators::matrix::{ModMatrix, ModRoute, ModSource};
 use crate::system::dev::DevInfo;
+use crate::system::controller::ControllerProfile;
+use crate::system::midi_map::{MidiMap, MidiMapping};
 use crate::system::parameter::ParameterID;
+use crate::system::preset::Preset;
+use crate::system::tuning::Tuning;
+use crate::system::velocity::VelocityCurve;
 
-use super::AudioEngineFeedbackPacket;
+use super::{AudioEngineControlPacket, AudioEngineFeedbackPacket, EngineError};
+
+// How often OSC clients get a meter update, per second
+const METER_RATE: f32 = 30.0;
 
 pub struct AudioEngine {
     // pub incoming: Receiver<AudioEngineControlPacket>,
@@ -17,64 +35,434 @@ pub struct AudioEngine {
     pub is_playing: bool,
     pub sample_position: usize,
 
-    synth: Synth,
+    parts: Vec<Part>,
+    // Part that parameter and voice changes go to
+    active_part: usize,
+    mixer: Mixer,
     midi: MidiInputHandler,
+    midi_out: MidiOutputHandler,
+    scheduler: MidiEventScheduler,
+    clock: Clock,
+    // Last bar, beat and tick sent to the manager
+    bar_beat_tick: (usize, usize, usize),
+    // What the clock's generators were made from, in the same order
+    generators: Vec<GeneratorSettings>,
+    recorder: Recorder,
+    // Parameter waiting for a CC to be assigned to it
+    midi_learn: Option<ParameterID>,
+    // Mappings made by the user, saved to disk
+    midi_map: MidiMap,
+    // Mappings of the active controller profile, these win over the user's
+    controller_map: MidiMap,
+    // Applies to every part, unless a preset brings its own
+    velocity_curve: VelocityCurve,
+    // Last MPE zone and tuning reported to the manager
+    mpe_zone: Option<MpeZone>,
+    tuning_name: String,
+    osc: Option<OscServer>,
+    meter_peak: f32,
+    meter_samples: usize,
     pub dev_info: DevInfo,
 
     pub sample_rate: f32,
-    pub buffer_size: usize
+    pub buffer_size: usize,
+    // Frames rendered ahead of what is playing, MIDI output waits for them to be heard
+    pub latency: usize
 }
 
 impl AudioEngine {
-    pub fn new(sr: f32, bs: usize, outgoing: Sender<AudioEngineFeedbackPacket>) -> AudioEngine {
+    pub fn new(sr: f32, bs: usize, midi_map: MidiMap, outgoing: Sender<AudioEngineFeedbackPacket>) -> AudioEngine {
+        let midi = match MidiInputHandler::init() {
+            Ok(midi) => midi,
+            Err(e) => {
+                println!("Starting without MIDI input: {}", e);
+                outgoing.send(AudioEngineFeedbackPacket::Error(EngineError::NoMidiInput(e.to_string()))).unwrap();
+                MidiInputHandler::null()
+            }
+        };
+
+        let osc = match OscServer::bind(osc_address()) {
+            Ok(osc) => {
+                if let Ok(addr) = osc.local_addr() {
+                    println!("Listening for OSC on {}", addr);
+                }
+                Some(osc)
+            },
+            Err(e) => {
+                println!("Starting without OSC: {}", e);
+                None
+            }
+        };
+
+        // One part listening to every channel, the way Donut works without parts
+        let mut part = Part::new(sr, bs, None);
+        part.synth.set_midi_map(midi_map.clone());
+        outgoing.send(AudioEngineFeedbackPacket::MidiMap(midi_map.clone())).unwrap();
+        outgoing.send(AudioEngineFeedbackPacket::ModMatrix(part.synth.get_mod_matrix().clone())).unwrap();
+
         AudioEngine {
             sample_position: 0,
             is_playing: false,
 
-            synth: Synth::new(sr, bs),
-            midi: MidiInputHandler::init().unwrap(),
+            parts: vec![part],
+            active_part: 0,
+            mixer: Mixer::new(bs),
+            midi,
+            midi_out: MidiOutputHandler::null(),
+            scheduler: MidiEventScheduler::new(sr, bs),
+            clock: Clock::new(120.0, sr, bs),
+            bar_beat_tick: (0, 0, 0),
+            generators: vec![],
+            recorder: Recorder::new(sr),
+            midi_learn: None,
+            midi_map,
+            controller_map: MidiMap::default(),
+            velocity_curve: VelocityCurve::default(),
+            mpe_zone: None,
+            tuning_name: String::new(),
+            osc,
+            meter_peak: 0.0,
+            meter_samples: 0,
             dev_info: DevInfo::start(bs, sr),
 
 
             sample_rate: sr,
             buffer_size: bs,
+            latency: 0,
 
             outgoing
         }
     }
 
-    pub fn process(&mut self) -> Buffer {
+    pub fn handle_packet(&mut self, packet: AudioEngineControlPacket) {
+        match packet {
+            AudioEngineControlPacket::SetParameter(id, value) => {
+                self.set_parameter(id, value);
+            },
+            AudioEngineControlPacket::SetMidiInput(port) => {
+                self.set_midi_device(port);
+            },
+            AudioEngineControlPacket::SetMidiOutput(name) => {
+                self.set_midi_output(&name);
+            },
+            AudioEngineControlPacket::SetClockSync(sync) => {
+                self.set_clock_sync(sync);
+            },
+            AudioEngineControlPacket::SetArpeggiator(settings) => {
+                self.set_arpeggiator(settings);
+            },
+            AudioEngineControlPacket::SetSequencerStep(index, step) => {
+                self.clock.sequencer.set_step(index, step);
+            },
+            AudioEngineControlPacket::SetSequencerLength(length) => {
+                self.clock.sequencer.set_length(length);
+            },
+            AudioEngineControlPacket::SetSequencerRate(rate) => {
+                self.clock.sequencer.set_rate(rate);
+            },
+            AudioEngineControlPacket::SetSequencerEnabled(enabled) => {
+                self.set_sequencer_enabled(enabled);
+            },
+            AudioEngineControlPacket::AddGenerator(settings, file) => {
+                self.add_generator(settings, file);
+            },
+            AudioEngineControlPacket::SetGenerator(index, settings, file) => {
+                self.set_generator(index, settings, file);
+            },
+            AudioEngineControlPacket::RemoveGenerator(index) => {
+                self.remove_generator(index);
+            },
+            AudioEngineControlPacket::StartRecording => {
+                self.recorder.start(self.sample_position, self.sample_rate, self.clock.bpm);
+                self.outgoing.send(AudioEngineFeedbackPacket::Recording(true)).unwrap();
+            },
+            AudioEngineControlPacket::StopRecording => {
+                self.recorder.stop();
+                self.outgoing.send(AudioEngineFeedbackPacket::Recording(false)).unwrap();
+            },
+            AudioEngineControlPacket::SaveTake(name) => {
+                self.save_take(name);
+            },
+            AudioEngineControlPacket::LoadTakeIntoSequencer(rate) => {
+                self.load_take_into_sequencer(rate);
+            },
+            AudioEngineControlPacket::SetMpeZone(zone) => {
+                self.synth().set_mpe_zone(zone);
+            },
+            AudioEngineControlPacket::SetMidiChannels(mask) => {
+                self.set_midi_channels(mask);
+            },
+            // Lands at the start of the next block, and goes where MIDI input goes
+            AudioEngineControlPacket::Midi(message) => {
+                self.scheduler.push(self.sample_position, message);
+            },
+            AudioEngineControlPacket::LearnMidi(parameter) => {
+                self.midi_learn = parameter;
+            },
+            AudioEngineControlPacket::SetMidiMapping(mapping) => {
+                self.set_midi_mapping(mapping);
+            },
+            AudioEngineControlPacket::RemoveMidiMapping(parameter) => {
+                self.midi_map.remove(parameter);
+                self.midi_map_changed();
+            },
+            AudioEngineControlPacket::SetModRoute(route) => {
+                let mut mod_matrix = self.synth().get_mod_matrix().clone();
+                mod_matrix.set(route);
+                self.set_mod_matrix(mod_matrix);
+            },
+            AudioEngineControlPacket::RemoveModRoute(source, destination) => {
+                let mut mod_matrix = self.synth().get_mod_matrix().clone();
+                mod_matrix.remove(source, destination);
+                self.set_mod_matrix(mod_matrix);
+            },
+            AudioEngineControlPacket::SetBendRange(semitones) => {
+                self.synth().set_bend_range(semitones);
+            },
+            AudioEngineControlPacket::SetHalfParameter(half, id, value) => {
+                self.set_half_parameter(half, id, value);
+            },
+            AudioEngineControlPacket::SetKeyboardMode(mode, split_point) => {
+                self.synth().set_keyboard_mode(mode, split_point);
+            },
+            AudioEngineControlPacket::SetPolyphony(polyphony) => {
+                self.synth().set_polyphony(polyphony);
+            },
+            AudioEngineControlPacket::SetVoiceMode(mode, priority) => {
+                self.synth().set_voice_mode(mode, priority);
+            },
+            AudioEngineControlPacket::SetGlide(time, mode) => {
+                self.synth().set_glide(time, mode);
+            },
+            AudioEngineControlPacket::SetControllerProfile(profile) => {
+                self.set_controller_profile(profile);
+            },
+            AudioEngineControlPacket::SetTuning(tuning) => {
+                self.synth().set_tuning(*tuning);
+            },
+            AudioEngineControlPacket::ResetTuning => {
+                self.synth().set_tuning(Tuning::equal());
+            },
+            AudioEngineControlPacket::SetVelocityCurve(curve) => {
+                for part in self.parts.iter_mut() {
+                    part.synth.set_velocity_curve(curve.clone());
+                }
+                self.velocity_curve = curve;
+            },
+            AudioEngineControlPacket::AddPart(channel) => {
+                self.add_part(channel);
+            },
+            AudioEngineControlPacket::RemovePart(index) => {
+                self.remove_part(index);
+            },
+            AudioEngineControlPacket::SelectPart(index) => {
+                self.select_part(index);
+            },
+            AudioEngineControlPacket::SetPartChannel(index, channel) => {
+                if let Some(part) = self.parts.get_mut(index) {
+                    part.set_channel(channel);
+                }
+            },
+            AudioEngineControlPacket::SetPartLevel(index, level) => {
+                if let Some(part) = self.parts.get_mut(index) {
+                    part.set_level(level);
+                }
+            },
+            AudioEngineControlPacket::SetPartPan(index, pan) => {
+                if let Some(part) = self.parts.get_mut(index) {
+                    part.set_pan(pan);
+                }
+            },
+            // The manager reads it and sends it back as a SetPreset
+            AudioEngineControlPacket::LoadPreset(path, half) => {
+                self.outgoing.send(AudioEngineFeedbackPacket::LoadPreset(path, half)).unwrap();
+            },
+            AudioEngineControlPacket::SetPreset(preset, half) => {
+                self.set_preset(*preset, half);
+            },
+            AudioEngineControlPacket::TogglePlayback => {
+                self.toggle_playback();
+            },
+            AudioEngineControlPacket::StartPlayback => {
+                self.start_playback();
+            },
+            AudioEngineControlPacket::PausePlayback => {
+                self.pause_playback();
+            },
+            AudioEngineControlPacket::StopPlayback => {
+                self.stop_playback();
+            },
+            AudioEngineControlPacket::ResetPlayback => {
+                self.reset_playback();
+            },
+            AudioEngineControlPacket::Locate(position) => {
+                self.locate(position);
+            },
+            AudioEngineControlPacket::SetLoop(range) => {
+                self.clock.set_loop(range);
+            },
+            AudioEngineControlPacket::SetTimeSignature(signature) => {
+                self.clock.time_signature = TimeSignature::new(signature.numerator, signature.denominator);
+            },
+            _ => {
+                println!("Unhandled packet: {:?}", packet);
+            }
+        }
+    }
+
+    // Applies whatever came in over OSC since the last call
+    pub fn run_osc(&mut self) {
+        let Some(osc) = self.osc.as_mut() else {
+            return;
+        };
+
+        for packet in osc.poll() {
+            self.handle_packet(packet);
+        }
+    }
+
+    fn update_meter(&mut self) {
+        let Some(osc) = self.osc.as_mut() else {
+            return;
+        };
+        if !osc.has_clients() {
+            return;
+        }
+
+        let (left, right) = self.mixer.get_output();
+        for i in 0..left.get_size() {
+            self.meter_peak = self.meter_peak.max(left[i].abs()).max(right[i].abs());
+        }
+
+        self.meter_samples += self.buffer_size;
+        if self.meter_samples as f32 >= self.sample_rate / METER_RATE {
+            osc.send_meter(self.meter_peak);
+            self.meter_peak = 0.0;
+            self.meter_samples = 0;
+        }
+    }
+
+    pub fn process(&mut self) -> (&Buffer, &Buffer) {
         let start = Instant::now();
-        
-        let messages = self.midi.run();
 
-        for message in messages {
-            match message {
-                MidiMessage::NoteOn(note, velocity) => {
-                    self.synth.note_on(note, velocity);
+        for info in self.midi.run() {
+            self.scheduler.schedule(info, self.sample_position);
+        }
+
+        let mut incoming = vec![];
+        for event in self.scheduler.take_block(self.sample_position, self.buffer_size) {
+            self.record(self.sample_position + event.offset, &event.message);
+
+            match event.message {
+                MidiMessage::Clock | MidiMessage::Start | MidiMessage::Continue | MidiMessage::Stop | MidiMessage::SongPosition(_) => {
+                    self.receive_transport(&event);
                 },
-                MidiMessage::NoteOff(note, _) => {
-                    self.synth.note_off(note);
+                MidiMessage::ActiveSensing => {},
+                MidiMessage::MidiCC(channel, cc, _) if self.midi_learn.is_some() => {
+                    self.learn(channel, cc);
                 },
-                MidiMessage::MidiCC(cc, value) => {
-                    println!("Received midi CC message: {:?}", message);
-                    self.synth.handle_cc(cc, value);
+                MidiMessage::ModWheel(channel, _) if self.midi_learn.is_some() => {
+                    self.learn(channel, 1);
                 },
-                _ => {
-                    println!("Unhandled message: {:?}", message);
-                }
+                // Held notes the arpeggiator plays for us
+                _ if self.clock.handle_message(&event.message) => {},
+                _ => incoming.push(event)
             }
         }
 
-        let output = self.synth.process();
+        let mut events = vec![];
+        if self.clock.tick().is_some() {
+            // Followers jump back along with the loop, before the pulses that come after it
+            if let Some(offset) = self.clock.looped {
+                let start = self.clock.loop_range.map_or(0, |(start, _)| start);
+                self.send_midi(&MidiMessage::SongPosition((start * 4 / self.clock.ppq).min(0x3FFF) as u16), offset);
+            }
+            for offset in self.clock.clock_pulses.clone() {
+                self.send_midi(&MidiMessage::Clock, offset);
+            }
 
+            // Generated notes go to the synth and are echoed to the MIDI output
+            events = self.clock.get_events();
+            for event in events.iter() {
+                self.send_midi(&event.message, event.offset);
+            }
+        }
+
+        // The synth wants them in order, generated and incoming events at the same offset keep theirs
+        events.extend(incoming);
+        events.sort_by_key(|event| event.offset);
+        self.mixer.process(&mut self.parts, &events);
+        self.report_parameter_changes();
         self.sample_position += self.buffer_size;
-        // self.outgoing.send(AudioEngineFeedbackPacket::Block(output)).unwrap();
+
+        if self.synth().get_mpe_zone() != self.mpe_zone {
+            self.mpe_zone = self.synth().get_mpe_zone();
+            self.outgoing.send(AudioEngineFeedbackPacket::MpeZone(self.mpe_zone)).unwrap();
+        }
+        let tuning = &self.parts[self.active_part].synth.get_tuning().name;
+        if *tuning != self.tuning_name {
+            self.tuning_name = tuning.clone();
+            self.outgoing.send(AudioEngineFeedbackPacket::Tuning(self.tuning_name.clone())).unwrap();
+        }
+        self.update_meter();
+
+        if self.clock.get_bar_beat_tick() != self.bar_beat_tick {
+            self.bar_beat_tick = self.clock.get_bar_beat_tick();
+            let (bar, beat, tick) = self.bar_beat_tick;
+            self.outgoing.send(AudioEngineFeedbackPacket::Position(bar, beat, tick)).unwrap();
+        }
 
         self.dev_info.update(self.buffer_size, self.sample_rate, start);
         self.outgoing.send(AudioEngineFeedbackPacket::DebugInfo(self.dev_info.clone())).unwrap();
 
-        output
+        self.mixer.get_output()
+    }
+
+    fn synth(&mut self) -> &mut Synth {
+        &mut self.parts[self.active_part].synth
+    }
+
+    pub fn add_part(&mut self, channel: Option<u8>) {
+        if self.parts.len() >= MAX_PARTS {
+            return;
+        }
+
+        let mut part = Part::new(self.sample_rate, self.buffer_size, channel);
+        part.synth.set_midi_map(self.midi_map.merged(&self.controller_map));
+        part.synth.set_velocity_curve(self.velocity_curve.clone());
+        self.parts.push(part);
+        self.select_part(self.parts.len() - 1);
+    }
+
+    // The last part can't be removed
+    pub fn remove_part(&mut self, index: usize) {
+        if self.parts.len() < 2 || index >= self.parts.len() {
+            return;
+        }
+
+        self.parts.remove(index);
+        if self.active_part >= index && self.active_part > 0 {
+            self.active_part -= 1;
+        }
+        self.select_part(self.active_part);
+    }
+
+    // Tells the manager about the patch of the part that is now being edited
+    pub fn select_part(&mut self, index: usize) {
+        if index >= self.parts.len() {
+            return;
+        }
+        self.active_part = index;
+
+        let mod_matrix = self.synth().get_mod_matrix().clone();
+        self.outgoing.send(AudioEngineFeedbackPacket::ModMatrix(mod_matrix)).unwrap();
+
+        self.mpe_zone = self.synth().get_mpe_zone();
+        self.outgoing.send(AudioEngineFeedbackPacket::MpeZone(self.mpe_zone)).unwrap();
+
+        for (id, value) in self.synth().get_parameters().to_vec() {
+            self.outgoing.send(AudioEngineFeedbackPacket::ParameterChanged(id, value)).unwrap();
+        }
     }
 
     pub fn set_block_size(&mut self, block_size: usize) {
@@ -82,18 +470,341 @@ impl AudioEngine {
             return;
         }
 
-        println!("Setting block size to: {}", block_size);
-        self.synth.set_block_size(block_size);
+        for part in self.parts.iter_mut() {
+            part.synth.set_block_size(block_size);
+        }
+        self.mixer.set_block_size(block_size);
+        self.scheduler.set_latency(block_size);
+        self.clock.set_block_size(block_size);
         self.buffer_size = block_size;
     }
 
+    fn learn(&mut self, channel: u8, cc: u8) {
+        let Some(parameter) = self.midi_learn.take() else {
+            return;
+        };
+
+        self.midi_map.learn(parameter, channel, cc);
+        let Some(mapping) = self.midi_map.get(parameter).cloned() else {
+            return;
+        };
+
+        self.outgoing.send(AudioEngineFeedbackPacket::MidiLearned(mapping.clone())).unwrap();
+        self.set_midi_mapping(mapping);
+    }
+
+    // Whatever was mapped last wins, over other mappings on its controller and the controller profile
+    fn set_midi_mapping(&mut self, mapping: MidiMapping) {
+        self.controller_map.mappings.retain(|m| m.parameter != mapping.parameter && !m.overlaps(&mapping));
+        self.midi_map.set(mapping);
+        self.midi_map_changed();
+    }
+
+    fn midi_map_changed(&mut self) {
+        let midi_map = self.midi_map.merged(&self.controller_map);
+        for part in self.parts.iter_mut() {
+            part.synth.set_midi_map(midi_map.clone());
+        }
+        self.outgoing.send(AudioEngineFeedbackPacket::MidiMap(self.midi_map.clone())).unwrap();
+    }
+
+    pub fn set_mod_matrix(&mut self, mod_matrix: ModMatrix) {
+        self.synth().set_mod_matrix(mod_matrix.clone());
+        self.outgoing.send(AudioEngineFeedbackPacket::ModMatrix(mod_matrix)).unwrap();
+    }
+
+    pub fn set_controller_profile(&mut self, profile: Option<ControllerProfile>) {
+        if let Some(profile) = profile.as_ref() {
+            println!("Using controller profile: {}", profile.name);
+        }
+
+        self.controller_map = profile.map(|p| p.midi_map()).unwrap_or_default();
+        let midi_map = self.midi_map.merged(&self.controller_map);
+        for part in self.parts.iter_mut() {
+            part.synth.set_midi_map(midi_map.clone());
+        }
+    }
+
+    fn receive_transport(&mut self, event: &MidiEvent) {
+        if self.clock.sync != ClockSync::External {
+            return;
+        }
+
+        // The master moving us lets go of what was playing, like a locate does
+        if matches!(event.message, MidiMessage::Start | MidiMessage::SongPosition(_)) {
+            self.release_notes();
+        }
+
+        let was_playing = self.clock.is_playing;
+        self.clock.receive(&event.message, event.offset);
+
+        // Pass the master clock on to whatever is connected to our output
+        self.send_midi(&event.message, event.offset);
+
+        if self.clock.is_playing != was_playing {
+            self.is_playing = self.clock.is_playing;
+
+            if !self.is_playing {
+                self.release_notes();
+            }
+
+            self.playback_changed();
+        }
+    }
+
+    fn playback_changed(&mut self) {
+        self.outgoing.send(AudioEngineFeedbackPacket::PlaybackStatus(self.is_playing)).unwrap();
+
+        if let Some(osc) = self.osc.as_mut() {
+            osc.send_playback(self.is_playing);
+        }
+    }
+
+    pub fn set_clock_sync(&mut self, sync: ClockSync) {
+        self.clock.set_sync(sync);
+    }
+
+    pub fn set_arpeggiator(&mut self, settings: ArpSettings) {
+        self.clock.arpeggiator.set_settings(settings);
+
+        // Switching off lets go of whatever the arpeggiator was playing
+        let notes = self.clock.arpeggiator.get_note_offs();
+        self.send_note_offs(notes);
+    }
+
+    pub fn add_generator(&mut self, settings: GeneratorSettings, file: Option<MidiFile>) {
+        if self.generators.len() >= MAX_GENERATORS {
+            return;
+        }
+
+        match settings.build(file.as_ref()) {
+            Ok(generator) => {
+                self.clock.add_generator(generator);
+                self.generators.push(settings);
+            },
+            Err(e) => {
+                self.outgoing.send(AudioEngineFeedbackPacket::Error(EngineError::Generator(format!("{:#}", e)))).unwrap();
+            }
+        }
+
+        self.outgoing.send(AudioEngineFeedbackPacket::Generators(self.generators.clone())).unwrap();
+    }
+
+    pub fn set_generator(&mut self, index: usize, settings: GeneratorSettings, file: Option<MidiFile>) {
+        if index >= self.generators.len() {
+            return;
+        }
+
+        match self.clock.set_generator(index, &settings, file.as_ref()) {
+            Ok(messages) => {
+                self.send_messages(messages);
+                self.generators[index] = settings;
+            },
+            Err(e) => {
+                self.outgoing.send(AudioEngineFeedbackPacket::Error(EngineError::Generator(format!("{:#}", e)))).unwrap();
+                self.outgoing.send(AudioEngineFeedbackPacket::Generators(self.generators.clone())).unwrap();
+            }
+        }
+    }
+
+    pub fn remove_generator(&mut self, index: usize) {
+        if index >= self.generators.len() {
+            return;
+        }
+
+        let messages = self.clock.remove_generator(index);
+        self.send_messages(messages);
+        self.generators.remove(index);
+        self.outgoing.send(AudioEngineFeedbackPacket::Generators(self.generators.clone())).unwrap();
+    }
+
+    // Into the take, when recording
+    fn record(&mut self, sample_position: usize, message: &MidiMessage) {
+        let position = if self.clock.is_playing { Some(self.clock.position) } else { None };
+        self.recorder.record(sample_position, position, message);
+    }
+
+    // The manager writes it, also while still recording
+    pub fn save_take(&mut self, name: Option<String>) {
+        if self.recorder.get_take().is_empty() {
+            self.outgoing.send(AudioEngineFeedbackPacket::Error(EngineError::Recording("Nothing was recorded".to_string()))).unwrap();
+            return;
+        }
+
+        self.outgoing.send(AudioEngineFeedbackPacket::SaveTake(self.recorder.get_take().clone(), name)).unwrap();
+    }
+
+    pub fn load_take_into_sequencer(&mut self, rate: Rate) {
+        let take = self.recorder.get_take();
+        if take.is_empty() {
+            self.outgoing.send(AudioEngineFeedbackPacket::Error(EngineError::Recording("Nothing was recorded".to_string()))).unwrap();
+            return;
+        }
+
+        self.clock.sequencer.set_pattern(take.to_pattern(rate));
+        let notes = self.clock.sequencer.get_note_offs();
+        self.send_note_offs(notes);
+        self.outgoing.send(AudioEngineFeedbackPacket::SequencerPattern(self.clock.sequencer.get_pattern().clone())).unwrap();
+    }
+
+    pub fn set_sequencer_enabled(&mut self, enabled: bool) {
+        self.clock.sequencer.set_enabled(enabled);
+
+        let notes = self.clock.sequencer.get_note_offs();
+        self.send_note_offs(notes);
+    }
+
     pub fn toggle_playback(&mut self) {
-        self.is_playing = !self.is_playing;
-        self.synth.toggle_playback();
+        if self.is_playing {
+            self.pause_playback();
+        } else {
+            self.start_playback();
+        }
+    }
+
+    pub fn start_playback(&mut self) {
+        if self.is_playing {
+            return;
+        }
+
+        let message = if self.clock.sample_position == 0 { MidiMessage::Start } else { MidiMessage::Continue };
+        self.send_midi(&message, 0);
+
+        self.is_playing = true;
+        self.clock.is_playing = true;
+        self.playback_changed();
+    }
+
+    pub fn pause_playback(&mut self) {
+        if !self.is_playing {
+            return;
+        }
+
+        self.is_playing = false;
+        self.clock.is_playing = false;
+        self.send_midi(&MidiMessage::Stop, 0);
+        self.release_notes();
+        self.playback_changed();
+    }
+
+    // Don't leave generated notes hanging, here or on the receiving end
+    fn release_notes(&mut self) {
+        let messages = self.clock.release_notes();
+        self.send_messages(messages);
+    }
+
+    fn send_note_offs(&mut self, notes: Vec<(u8, u8)>) {
+        self.send_messages(notes.into_iter().map(|(channel, pitch)| MidiMessage::NoteOff(channel, pitch, 0)).collect());
+    }
+
+    // Straight to the parts and the MIDI output, outside of the block's events
+    fn send_messages(&mut self, messages: Vec<MidiMessage>) {
+        for message in messages {
+            for part in self.parts.iter_mut() {
+                part.handle_message(&message);
+            }
+            self.send_midi(&message, 0);
+        }
+    }
+
+    // Timed to when the next rendered block is heard, `offset` samples into it
+    fn send_midi(&mut self, message: &MidiMessage, offset: usize) {
+        let delay = Duration::from_secs_f32((self.latency + offset) as f32 / self.sample_rate);
+        self.midi_out.send_at(message, Instant::now() + delay);
+    }
+
+    pub fn stop_playback(&mut self) {
+        self.pause_playback();
+        self.reset_playback();
+    }
+
+    pub fn reset_playback(&mut self) {
+        self.release_notes();
+        self.clock.reset();
+        self.send_midi(&MidiMessage::SongPosition(0), 0);
+    }
+
+    pub fn locate(&mut self, position: usize) {
+        self.release_notes();
+        self.clock.locate(position);
+
+        // Song position pointers count sixteenth notes
+        let beats = position * 4 / self.clock.ppq;
+        self.send_midi(&MidiMessage::SongPosition(beats.min(0x3FFF) as u16), 0);
+    }
+
+    // Moved by mapped controllers, the GUI and OSC clients follow the selected part
+    fn report_parameter_changes(&mut self) {
+        for (index, part) in self.parts.iter_mut().enumerate() {
+            let changes = part.synth.take_parameter_changes();
+            if index != self.active_part {
+                continue;
+            }
+
+            for (id, value) in changes {
+                if let Some(osc) = self.osc.as_mut() {
+                    osc.send_parameter(id, value);
+                }
+                self.outgoing.send(AudioEngineFeedbackPacket::ParameterChanged(id, value)).unwrap();
+            }
+        }
     }
 
     pub fn set_parameter(&mut self, id: ParameterID, value: f32) {
-        self.synth.set_parameter(id, value);
+        self.synth().set_parameter(id, value);
+
+        if let Some(osc) = self.osc.as_mut() {
+            osc.send_parameter(id, value);
+        }
+        self.outgoing.send(AudioEngineFeedbackPacket::ParameterChanged(id, value)).unwrap();
+    }
+
+    pub fn set_half_parameter(&mut self, half: Half, id: ParameterID, value: f32) {
+        self.synth().set_half_parameter(half, id, value);
+
+        if let Some(osc) = self.osc.as_mut() {
+            osc.send_half_parameter(half, id, value);
+        }
+        self.outgoing.send(AudioEngineFeedbackPacket::HalfParameterChanged(half, id, value)).unwrap();
+    }
+
+    pub fn set_preset(&mut self, preset: Preset, half: Option<Half>) {
+        for parameter in preset.parameters {
+            match (serde_json::from_value(serde_json::Value::String(parameter.key.clone())), half) {
+                (Ok(id), None) => self.set_parameter(id, parameter.value),
+                (Ok(id), Some(half)) => self.set_half_parameter(half, id, parameter.value),
+                (Err(_), _) => println!("Preset contains unknown parameter: {}", parameter.key)
+            }
+        }
+
+        // Links apply to every voice, the voice a link was saved with is ignored
+        let mut mod_matrix = ModMatrix::default();
+        for link in preset.mod_links {
+            let source: Result<ModSource, _> = serde_json::from_value(serde_json::Value::String(link.source.clone()));
+            let destination: Result<ParameterID, _> = serde_json::from_value(serde_json::Value::String(link.destination.clone()));
+
+            match (source, destination) {
+                (Ok(source), Ok(destination)) => mod_matrix.set(ModRoute { source, destination, amount: link.amount }),
+                _ => println!("Preset contains unknown modulation: {} -> {}", link.source, link.destination)
+            }
+        }
+        self.set_mod_matrix(mod_matrix);
+
+        let curve = preset.velocity_curve.unwrap_or_else(|| self.velocity_curve.clone());
+        self.synth().set_velocity_curve(curve);
+    }
+
+    pub fn set_midi_output(&mut self, name: &str) {
+        let packet = match self.midi_out.set_output(name) {
+            Ok(name) => AudioEngineFeedbackPacket::MidiOutputConnected(name),
+            Err(e) => AudioEngineFeedbackPacket::Error(EngineError::NoMidiOutput(e.to_string()))
+        };
+
+        self.outgoing.send(packet).unwrap();
+    }
+
+    pub fn set_midi_channels(&mut self, mask: u16) {
+        self.midi.set_channel_mask(mask);
     }
 
     pub fn get_debug_info(&self) -> DevInfo {
@@ -101,6 +812,11 @@ impl AudioEngine {
     }
 
     pub fn set_midi_device(&mut self, port: usize) {
-        self.midi.set_input(port);
+        let packet = match self.midi.set_input(port) {
+            Ok(name) => AudioEngineFeedbackPacket::MidiInputConnected(name),
+            Err(e) => AudioEngineFeedbackPacket::Error(EngineError::NoMidiInput(e.to_string()))
+        };
+
+        self.outgoing.send(packet).unwrap();
     }
 }
\ No newline at end of file
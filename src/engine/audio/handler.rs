@@ -1,50 +1,56 @@
-use std::sync::{atomic::AtomicU16, mpsc::{Receiver, Sender}, Arc};
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+use std::thread::Thread;
 
-use super::{AudioEngineControlPacket, AudioEngineFeedbackPacket};
+use super::ring::Consumer;
 
+// Runs inside the audio callback: it only copies prerendered frames out of the ring,
+// so it never locks, allocates or blocks.
 pub struct AudioHandler {
-    pub block_size: usize,
-    pub sample_rate: f32,
-    position: usize,
+    pub channels: usize,
 
-    pub packets_in_pipe: Arc<AtomicU16>,
-    pub outgoing: Sender<AudioEngineFeedbackPacket>,
-    pub incoming: Receiver<AudioEngineControlPacket>,
+    frames: Consumer<[f32; 2]>,
+    underruns: Arc<AtomicUsize>,
+    // Largest callback seen so far, in frames, the engine keeps this much in the ring
+    callback_frames: Arc<AtomicUsize>,
+    engine_thread: Thread
 }
 
 impl AudioHandler {
-    pub fn new(sr: f32, bs: usize, qs: Arc<AtomicU16>, outgoing: Sender<AudioEngineFeedbackPacket>, incoming: Receiver<AudioEngineControlPacket>) -> AudioHandler {
+    pub fn new(channels: usize, frames: Consumer<[f32; 2]>, underruns: Arc<AtomicUsize>, callback_frames: Arc<AtomicUsize>, engine_thread: Thread) -> AudioHandler {
         AudioHandler {
-            block_size: bs,
-            sample_rate: sr,
-            packets_in_pipe: qs,
-            position: 0,
-            outgoing,
-            incoming
+            channels: channels.max(1),
+            frames,
+            underruns,
+            callback_frames,
+            engine_thread
         }
     }
 
-    pub fn process(&mut self, data: &mut [f32], info: &cpal::OutputCallbackInfo) {
-        if data.len() != self.block_size {
-            self.block_size = data.len();
-            self.outgoing.send(AudioEngineFeedbackPacket::BlockSize(self.block_size)).unwrap();
-        }
+    pub fn process(&mut self, data: &mut [f32]) {
+        let mut starved = false;
+        self.callback_frames.fetch_max(data.len() / self.channels, Ordering::Relaxed);
+
+        for frame in data.chunks_mut(self.channels) {
+            let [left, right] = self.frames.pop().unwrap_or_else(|| {
+                starved = true;
+                [0.0, 0.0]
+            });
 
-        if let Ok(packet) = self.incoming.try_recv() {
-            match packet {
-                AudioEngineControlPacket::AudioPacket(buffer, start) => {
-                    // if self.position <= start {
-                        for i in 0..self.block_size {
-                            data[i] = buffer[i];
-                        }
-                        self.outgoing.send(AudioEngineFeedbackPacket::Block(buffer)).unwrap();
-                    // }
-                    self.position += self.block_size;
-
-                    self.packets_in_pipe.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
-                },
-                _ => {}
+            match frame.len() {
+                1 => frame[0] = (left + right) * 0.5,
+                _ => {
+                    frame[0] = left;
+                    frame[1] = right;
+                    frame[2..].fill(0.0);
+                }
             }
         }
+
+        if starved {
+            self.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Wake the engine thread so it can top the ring back up
+        self.engine_thread.unpark();
     }
-}
\ No newline at end of file
+}
@@ -1,117 +1,259 @@
 // EngineManager
 // Creates and manages IO threads
 
-use std::sync::atomic::AtomicU16;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread::Thread;
+use std::time::Duration;
+use cpal::{BufferSize, SupportedBufferSize};
 use cpal::BufferSize::Fixed;
-use cpal::{Devices, InputDevices, OutputDevices};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use midir::{Ignore, MidiInput};
+use crate::engine::clock::{ClockSync, TimeSignature, MAX_GENERATORS};
+use crate::generators::arpeggiator::ArpSettings;
+use crate::generators::sequencer::{Pattern, Step};
+use crate::generators::{GeneratorSettings, Rate};
+use crate::engine::mixer::{PartSettings, MAX_PARTS};
+use crate::engine::mpe::{MpeZone, DEFAULT_BEND_RANGE};
+use crate::engine::note_handler::{Half, KeyboardMode};
+use crate::engine::synthesis::{GlideMode, NotePriority, VoiceMode, DEFAULT_POLYPHONY};
+use crate::modulators::matrix::{ModMatrix, ModRoute, ModSource};
+use crate::engine::midi::{MidiOutputHandler, ALL_CHANNELS};
+use crate::engine::recorder::Take;
+use crate::system::controller::ControllerProfile;
 use crate::system::dev::DevInfo;
+use crate::system::midi_map::{MidiMap, MidiMapping};
 use crate::system::parameter::ParameterID;
+use crate::system::preset::Preset;
+use crate::system::tuning::Tuning;
+use crate::system::util::default_path;
+use crate::system::velocity::VelocityCurve;
+
+use super::ring::{ring_buffer, Producer};
+use super::{AudioEngineControlPacket, AudioEngineFeedbackPacket, AudioEngine, AudioHandler, EngineError};
+
+const NULL_SINK_SAMPLE_RATE: u32 = 48_000;
+// Frames the ring can hold, the largest callback we can feed has to fit next to a block
+const RING_CAPACITY: usize = 16384;
+// The engine renders at most this many frames at a time, smaller callbacks get smaller blocks
+const MAX_BLOCK_SIZE: usize = 512;
+// Callback size we ask for when the device lets us choose
+const PREFERRED_CALLBACK_SIZE: u32 = 256;
+const COMMAND_QUEUE_SIZE: usize = 1024;
+
+// Voice settings of a part as last sent to the engine
+#[derive(Debug, Clone)]
+pub struct PartState {
+    pub settings: PartSettings,
+    pub bend_range: f32,
+    pub polyphony: usize,
+    pub keyboard_mode: KeyboardMode,
+    pub split_point: u8,
+    pub voice_mode: VoiceMode,
+    pub note_priority: NotePriority,
+    pub glide_time: f32,
+    pub glide_mode: GlideMode
+}
 
-use super::{AudioEngineControlPacket, AudioEngineFeedbackPacket, AudioEngine, AudioHandler};
+impl PartState {
+    pub fn new(channel: Option<u8>) -> Self {
+        Self {
+            settings: PartSettings::new(channel),
+            bend_range: DEFAULT_BEND_RANGE,
+            polyphony: DEFAULT_POLYPHONY,
+            keyboard_mode: KeyboardMode::Whole,
+            split_point: 60,
+            voice_mode: VoiceMode::Poly,
+            note_priority: NotePriority::Last,
+            glide_time: 0.0,
+            glide_mode: GlideMode::Always
+        }
+    }
+}
 
 pub struct EngineManager {
     pub host: cpal::Host,
-    pub device: cpal::Device,
+    pub device: Option<cpal::Device>,
     pub config: cpal::StreamConfig,
-    pub stream: cpal::Stream,
+    pub stream: Option<cpal::Stream>,
     
-    pub audio_ins: InputDevices<Devices>,
-    pub audio_outs: OutputDevices<Devices>,
+    pub audio_ins: Vec<String>,
+    pub audio_outs: Vec<String>,
     pub midi_ins: Vec<(String, usize)>,
     pub active_midi_in: usize,
+    pub midi_channels: u16,
+    pub clock_sync: ClockSync,
+    pub arpeggiator: ArpSettings,
+    pub sequencer: Pattern,
+    pub sequencer_enabled: bool,
+    pub generators: Vec<GeneratorSettings>,
+    pub recording: bool,
+    // Path the last take was saved to
+    pub last_take: Option<String>,
+    // MPE zone of the selected part
+    pub mpe_zone: Option<MpeZone>,
+    // Name of the selected part's tuning
+    pub tuning_name: String,
+    pub velocity_curve: VelocityCurve,
+    pub parts: Vec<PartState>,
+    pub active_part: usize,
+    pub midi_outs: Vec<String>,
+    pub active_midi_out: Option<String>,
     pub playback_status: bool,
+    // Bar, beat and tick as reported by the engine
+    pub position: (usize, usize, usize),
+    pub loop_range: Option<(usize, usize)>,
+    pub time_signature: TimeSignature,
     pub latest_debug_info: DevInfo,
-
-    pub to_engine: Sender<AudioEngineControlPacket>,
+    pub midi_error: Option<String>,
+    pub audio_error: Option<String>,
+    pub underruns: Arc<AtomicUsize>,
+    // Parameter values set by the engine, e.g. from OSC or a preset, for the GUI to pick up.
+    // Changes without a half went to both halves.
+    pub parameter_changes: Vec<(Option<Half>, ParameterID, f32)>,
+    pub midi_map: MidiMap,
+    pub midi_learn: Option<ParameterID>,
+    pub mod_matrix: ModMatrix,
+    pub controller_profiles: Vec<ControllerProfile>,
+    pub active_controller_profile: Option<String>,
+
+    pub to_engine: Producer<AudioEngineControlPacket>,
     pub from_handler: Receiver<AudioEngineFeedbackPacket>,
-    pub from_engine: Receiver<AudioEngineFeedbackPacket>
+    pub from_engine: Receiver<AudioEngineFeedbackPacket>,
+    engine_thread: Thread
 }
 
 impl EngineManager {
     pub fn new() -> EngineManager {
         // Initialize cpal
         let (from_handler_tx, from_handler_rx) = channel();
-        let (to_engine_tx, to_engine_rx) = channel();
+        let (to_engine_tx, mut to_engine_rx) = ring_buffer(COMMAND_QUEUE_SIZE);
         let (from_engine_tx, from_engine_rx) = channel();
 
         let host = cpal::default_host();
-        
-        let audio_ins = host.input_devices().unwrap();
-        let audio_outs = host.output_devices().unwrap();
-        
-        let device = host.default_output_device().expect("Failed to find a default output device");
-
         println!("{}", host.id().name());
-        println!("device: {}", device.name().unwrap());
 
-        let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
-        let mut config = device.default_output_config().unwrap().config();
-
-        config.buffer_size = Fixed(512);
+        let mut audio_error = None;
+        let output = host.default_output_device()
+            .ok_or_else(|| "Failed to find a default output device".to_string())
+            .and_then(|device| {
+                let supported = device.default_output_config().map_err(|e| e.to_string())?;
+                let mut config = supported.config();
+                config.buffer_size = match supported.buffer_size() {
+                    SupportedBufferSize::Range { min, max } => Fixed(PREFERRED_CALLBACK_SIZE.clamp(*min, *max)),
+                    SupportedBufferSize::Unknown => BufferSize::Default
+                };
+                Ok((device, config))
+            });
+
+        let (device, config) = match output {
+            Ok((device, config)) => {
+                println!("device: {}", device.name().unwrap_or_default());
+                (Some(device), config)
+            },
+            Err(e) => {
+                eprintln!("{}, running without audio output", e);
+                audio_error = Some(e);
+                (None, cpal::StreamConfig {
+                    channels: 2,
+                    sample_rate: cpal::SampleRate(NULL_SINK_SAMPLE_RATE),
+                    buffer_size: Fixed(PREFERRED_CALLBACK_SIZE)
+                })
+            }
+        };
 
         let sr = config.sample_rate.0 as f32;
-        let buffer_size = match config.buffer_size {
+        // A guess until the first callback tells us, hosts don't always deliver what we asked for
+        let callback_size = match config.buffer_size {
             Fixed(size) => size as usize,
-            _ => 512,
+            _ => MAX_BLOCK_SIZE
+        };
+        let callback_frames = Arc::new(AtomicUsize::new(callback_size));
+        let buffer_size = callback_size.min(MAX_BLOCK_SIZE);
+        let engine_callback_frames = callback_frames.clone();
+
+        // The engine thread gets its end of the frame ring once we know whether a stream could be opened
+        let (frames_tx, frames_rx) = channel::<Producer<[f32; 2]>>();
+
+        // Read here, the engine thread stays off the disk
+        let midi_map = match MidiMap::load() {
+            Ok(midi_map) => midi_map,
+            Err(e) => {
+                println!("Using the default MIDI map: {:#}", e);
+                from_engine_tx.send(AudioEngineFeedbackPacket::Error(EngineError::MidiMap(format!("{:#}", e)))).unwrap();
+                MidiMap::default_mappings()
+            }
         };
 
-        let (cross_engine_tx, cross_engine_rx) = channel();
+        let process_thread = std::thread::spawn(move || {
+            let mut engine = AudioEngine::new(sr, buffer_size, midi_map, from_engine_tx);
+            let mut frames = frames_rx.recv().unwrap();
 
-        let packets_in_pipe = Arc::new(AtomicU16::new(0));
-        let _packets = packets_in_pipe.clone();
+            loop {
+                while let Some(packet) = to_engine_rx.pop() {
+                    engine.handle_packet(packet);
+                }
+                engine.run_osc();
 
-        let _process_thread = std::thread::spawn(move || {
-            let mut engine = AudioEngine::new(sr, buffer_size, from_engine_tx);
-            let mut position = 0;
+                // Blocks follow the callback size, and still fit next to the largest callback
+                let callback = engine_callback_frames.load(Ordering::Relaxed).clamp(1, frames.capacity() / 2);
+                engine.set_block_size(callback.min(MAX_BLOCK_SIZE));
 
-            loop {
-                if _packets.load(std::sync::atomic::Ordering::SeqCst) > 1 {
-                    std::thread::sleep(std::time::Duration::from_micros(100));
+                // Only keep what the next callback takes in the ring, anything more is latency.
+                // Sleep until the callback has taken it, or a command arrives.
+                if frames.capacity() - frames.free() >= callback {
+                    std::thread::park_timeout(Duration::from_secs_f32(engine.buffer_size as f32 / engine.sample_rate));
+                    continue;
                 }
 
-                while let Ok(packet) = to_engine_rx.try_recv() {
-                    match packet {
-                        AudioEngineControlPacket::SetParameter(id, value) => {
-                            engine.set_parameter(id, value);
-                        },
-                        AudioEngineControlPacket::SetMidiInput(port) => {
-                            engine.set_midi_device(port);
-                        },
-                        AudioEngineControlPacket::TogglePlayback => {
-                            engine.toggle_playback();
-                        },
-                        AudioEngineControlPacket::SetBlockSize(size) => {
-                            engine.set_block_size(size);
-                        },
-                        _ => {
-                            println!("Unhandled packet: {:?}", packet);
-                        }
-                    }
+                engine.latency = frames.capacity() - frames.free();
+                let (left, right) = engine.process();
+                for i in 0..left.get_size() {
+                    let _ = frames.push([left[i], right[i]]);
                 }
+            }
+        });
 
-                let output = engine.process();
-                position += buffer_size;
+        let engine_thread = process_thread.thread().clone();
+        let underruns = Arc::new(AtomicUsize::new(0));
 
-                cross_engine_tx.send(AudioEngineControlPacket::AudioPacket(output, position)).unwrap();
+        let stream = device.as_ref().and_then(|device| {
+            let (producer, consumer) = ring_buffer(RING_CAPACITY);
+            let mut handler = AudioHandler::new(config.channels as usize, consumer, underruns.clone(), callback_frames.clone(), engine_thread.clone());
 
-                _packets.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-            }
-        });
+            let err_tx = from_handler_tx.clone();
+            let err_fn = move |err: cpal::StreamError| {
+                eprintln!("an error occurred on the output audio stream: {}", err);
+                let _ = err_tx.send(AudioEngineFeedbackPacket::Error(EngineError::AudioStream(err.to_string())));
+            };
 
-        let cb = Arc::new(Mutex::new(AudioHandler::new(sr, buffer_size, packets_in_pipe, from_handler_tx, cross_engine_rx)));
+            let stream = device.build_output_stream(&config, move |output: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                handler.process(output);
+            }, err_fn, None).map_err(|e| e.to_string())
+                .and_then(|stream| stream.play().map(|_| stream).map_err(|e| e.to_string()));
 
-        let stream = device.build_output_stream(&config, {
-            let _cb = cb.clone();
-            move |output: &mut [f32], info: &cpal::OutputCallbackInfo| {
-                _cb.lock().unwrap().process(output, info);
+            match stream {
+                Ok(stream) => {
+                    frames_tx.send(producer).unwrap();
+                    Some(stream)
+                },
+                Err(e) => {
+                    eprintln!("Failed to open the output stream: {}, running without audio output", e);
+                    audio_error = Some(e);
+                    None
+                }
             }
-        }, err_fn, None).unwrap();
+        });
 
-        stream.play().unwrap();
+        if stream.is_none() {
+            let (producer, consumer) = ring_buffer(RING_CAPACITY);
+            frames_tx.send(producer).unwrap();
+
+            let handler = AudioHandler::new(config.channels as usize, consumer, underruns.clone(), callback_frames.clone(), engine_thread.clone());
+            Self::spawn_null_sink(handler, sr, callback_size);
+        }
 
         let mut em = EngineManager {
             host,
@@ -119,35 +261,119 @@ impl EngineManager {
             config,
             stream,
             
-            audio_ins,
-            audio_outs,
+            audio_ins: vec![],
+            audio_outs: vec![],
             midi_ins: vec![],
             active_midi_in: 0,
+            midi_channels: ALL_CHANNELS,
+            clock_sync: ClockSync::Internal,
+            arpeggiator: ArpSettings::default(),
+            sequencer: Pattern::default(),
+            sequencer_enabled: true,
+            generators: vec![],
+            recording: false,
+            last_take: None,
+            mpe_zone: None,
+            tuning_name: String::new(),
+            velocity_curve: VelocityCurve::default(),
+            parts: vec![PartState::new(None)],
+            active_part: 0,
+            midi_outs: vec![],
+            active_midi_out: None,
             playback_status: false,
+            position: (1, 1, 0),
+            loop_range: None,
+            time_signature: TimeSignature::default(),
             latest_debug_info: DevInfo::start(buffer_size, sr),
+            midi_error: None,
+            audio_error,
+            underruns,
+            parameter_changes: vec![],
+            midi_map: MidiMap::default(),
+            midi_learn: None,
+            mod_matrix: ModMatrix::default(),
+            controller_profiles: vec![],
+            active_controller_profile: None,
 
             to_engine: to_engine_tx,
             from_handler: from_handler_rx,
-            from_engine: from_engine_rx
+            from_engine: from_engine_rx,
+            engine_thread
         };
         
         em.refresh_device_list();
         
         em
     }
+
+    // Stands in for the cpal callback when no output is available, so the engine keeps running
+    fn spawn_null_sink(mut handler: AudioHandler, sample_rate: f32, block_size: usize) {
+        std::thread::spawn(move || {
+            let mut data = vec![0.0; block_size * handler.channels];
+            let interval = Duration::from_secs_f32(block_size as f32 / sample_rate);
+
+            loop {
+                handler.process(&mut data);
+                std::thread::sleep(interval);
+            }
+        });
+    }
+
+    fn send(&mut self, packet: AudioEngineControlPacket) {
+        if let Err(packet) = self.to_engine.push(packet) {
+            eprintln!("Engine command queue is full, dropping {:?}", packet);
+        }
+
+        self.engine_thread.unpark();
+    }
     
     pub fn refresh_device_list(&mut self) {
-        self.audio_ins = self.host.input_devices().unwrap();
-        self.audio_outs = self.host.output_devices().unwrap();
+        self.audio_ins = self.host.input_devices()
+            .map(|devices| devices.filter_map(|d| d.name().ok()).collect())
+            .unwrap_or_default();
+        self.audio_outs = self.host.output_devices()
+            .map(|devices| devices.filter_map(|d| d.name().ok()).collect())
+            .unwrap_or_default();
+
+        self.midi_outs = MidiOutputHandler::list_outputs();
+        self.midi_ins.clear();
+
+        if let Ok(mut midi_in) = MidiInput::new("Donut MIDI IN") {
+            midi_in.ignore(Ignore::None);
+
+            let in_ports = midi_in.ports();
+            in_ports.iter().enumerate().for_each(|(i, port)| {
+                if let Ok(name) = midi_in.port_name(port) {
+                    self.midi_ins.push((name, i));
+                }
+            });
+        }
 
-        let mut midi_in = MidiInput::new("Donut MIDI IN").unwrap();
-        midi_in.ignore(Ignore::None);
+        self.controller_profiles = ControllerProfile::load_all();
+        self.select_controller_profile();
+    }
 
-        let in_ports = midi_in.ports();
-        in_ports.iter().enumerate().for_each(|(i, port)| {
-            let name = midi_in.port_name(port).unwrap();
-            self.midi_ins.push((name, i));
-        });
+    // Picks the profile that belongs to the active MIDI input, if there is one
+    fn select_controller_profile(&mut self) {
+        let profile = self.midi_ins.iter()
+            .find(|(_, i)| *i == self.active_midi_in)
+            .and_then(|(name, _)| ControllerProfile::find(&self.controller_profiles, name))
+            .cloned();
+
+        self.set_controller_profile(profile);
+    }
+
+    pub fn get_controller_profiles(&self) -> &Vec<ControllerProfile> {
+        &self.controller_profiles
+    }
+
+    pub fn get_active_controller_profile(&self) -> Option<&String> {
+        self.active_controller_profile.as_ref()
+    }
+
+    pub fn set_controller_profile(&mut self, profile: Option<ControllerProfile>) {
+        self.active_controller_profile = profile.as_ref().map(|p| p.name.clone());
+        self.send(AudioEngineControlPacket::SetControllerProfile(profile));
     }
     
     pub fn get_midi_ports(&self) -> &Vec<(String, usize)> {
@@ -157,35 +383,446 @@ impl EngineManager {
         self.active_midi_in
     }
     
-    pub fn get_audio_inputs(&self) -> &InputDevices<Devices> {
+    pub fn get_audio_inputs(&self) -> &Vec<String> {
         &self.audio_ins
     }
     
-    pub fn get_audio_outputs(&self) -> &OutputDevices<Devices> {
+    pub fn get_audio_outputs(&self) -> &Vec<String> {
         &self.audio_outs
     }
+
+    pub fn get_midi_error(&self) -> Option<&String> {
+        self.midi_error.as_ref()
+    }
+
+    pub fn get_audio_error(&self) -> Option<&String> {
+        self.audio_error.as_ref()
+    }
     
     pub fn set_midi_device(&mut self, port: usize) {
         self.active_midi_in = port;
-        self.to_engine.send(AudioEngineControlPacket::SetMidiInput(port)).unwrap();
+        self.send(AudioEngineControlPacket::SetMidiInput(port));
+        self.select_controller_profile();
     }
     
+    pub fn get_midi_outputs(&self) -> &Vec<String> {
+        &self.midi_outs
+    }
+
+    pub fn get_selected_midi_output(&self) -> Option<&String> {
+        self.active_midi_out.as_ref()
+    }
+
+    pub fn set_midi_output(&mut self, name: String) {
+        self.active_midi_out = Some(name.clone());
+        self.send(AudioEngineControlPacket::SetMidiOutput(name));
+    }
+
+    pub fn get_clock_sync(&self) -> ClockSync {
+        self.clock_sync
+    }
+
+    pub fn set_clock_sync(&mut self, sync: ClockSync) {
+        self.clock_sync = sync;
+        self.send(AudioEngineControlPacket::SetClockSync(sync));
+    }
+
+    pub fn get_arpeggiator(&self) -> ArpSettings {
+        self.arpeggiator
+    }
+
+    pub fn set_arpeggiator(&mut self, settings: ArpSettings) {
+        self.arpeggiator = settings;
+        self.send(AudioEngineControlPacket::SetArpeggiator(settings));
+    }
+
+    pub fn get_sequencer(&self) -> &Pattern {
+        &self.sequencer
+    }
+
+    pub fn set_sequencer_step(&mut self, index: usize, step: Step) {
+        self.sequencer.set_step(index, step);
+        self.send(AudioEngineControlPacket::SetSequencerStep(index, step));
+    }
+
+    pub fn set_sequencer_length(&mut self, length: usize) {
+        self.sequencer.set_length(length);
+        self.send(AudioEngineControlPacket::SetSequencerLength(length));
+    }
+
+    pub fn set_sequencer_rate(&mut self, rate: Rate) {
+        self.sequencer.rate = rate;
+        self.send(AudioEngineControlPacket::SetSequencerRate(rate));
+    }
+
+    pub fn get_sequencer_enabled(&self) -> bool {
+        self.sequencer_enabled
+    }
+
+    pub fn set_sequencer_enabled(&mut self, enabled: bool) {
+        self.sequencer_enabled = enabled;
+        self.send(AudioEngineControlPacket::SetSequencerEnabled(enabled));
+    }
+
+    pub fn get_generators(&self) -> &Vec<GeneratorSettings> {
+        &self.generators
+    }
+
+    // The list is updated once the engine has added it
+    pub fn add_generator(&mut self, settings: GeneratorSettings) {
+        if self.generators.len() >= MAX_GENERATORS {
+            return;
+        }
+
+        match settings.load() {
+            Ok(file) => self.send(AudioEngineControlPacket::AddGenerator(settings, file)),
+            Err(e) => self.report(EngineError::Generator(format!("{:#}", e)))
+        }
+    }
+
+    pub fn set_generator(&mut self, index: usize, settings: GeneratorSettings) {
+        if index >= self.generators.len() {
+            return;
+        }
+
+        match settings.load() {
+            Ok(file) => {
+                self.generators[index] = settings.clone();
+                self.send(AudioEngineControlPacket::SetGenerator(index, settings, file));
+            },
+            Err(e) => self.report(EngineError::Generator(format!("{:#}", e)))
+        }
+    }
+
+    pub fn remove_generator(&mut self, index: usize) {
+        if index < self.generators.len() {
+            self.generators.remove(index);
+            self.send(AudioEngineControlPacket::RemoveGenerator(index));
+        }
+    }
+
+    pub fn get_recording(&self) -> bool {
+        self.recording
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+        if recording {
+            self.send(AudioEngineControlPacket::StartRecording);
+        } else {
+            self.send(AudioEngineControlPacket::StopRecording);
+        }
+    }
+
+    pub fn get_last_take(&self) -> Option<&String> {
+        self.last_take.as_ref()
+    }
+
+    // The engine hands the take back to be written, see write_take
+    pub fn save_take(&mut self, name: Option<String>) {
+        self.send(AudioEngineControlPacket::SaveTake(name));
+    }
+
+    // Under the Donut folder, named after the time when no name is given
+    fn write_take(&mut self, take: Take, name: Option<String>) {
+        let name = name.filter(|name| !name.is_empty()).unwrap_or_else(|| {
+            let seconds = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            format!("take-{}", seconds)
+        });
+        let path = default_path().join("takes").join(format!("{}.mid", name.trim_end_matches(".mid")));
+
+        match take.save(&path) {
+            Ok(()) => self.last_take = Some(path.display().to_string()),
+            Err(e) => self.report(EngineError::Recording(format!("{:#}", e)))
+        }
+    }
+
+    // The sequencer is updated once the engine has quantized the take
+    pub fn load_take_into_sequencer(&mut self, rate: Rate) {
+        self.send(AudioEngineControlPacket::LoadTakeIntoSequencer(rate));
+    }
+
+    pub fn get_mpe_zone(&self) -> Option<MpeZone> {
+        self.mpe_zone
+    }
+
+    pub fn set_mpe_zone(&mut self, zone: Option<MpeZone>) {
+        self.mpe_zone = zone;
+        self.send(AudioEngineControlPacket::SetMpeZone(zone));
+    }
+
+    pub fn get_parts(&self) -> &Vec<PartState> {
+        &self.parts
+    }
+
+    pub fn get_active_part(&self) -> usize {
+        self.active_part
+    }
+
+    fn part_mut(&mut self) -> &mut PartState {
+        &mut self.parts[self.active_part]
+    }
+
+    // The new part is selected straight away
+    pub fn add_part(&mut self, channel: Option<u8>) {
+        if self.parts.len() >= MAX_PARTS {
+            return;
+        }
+
+        self.parts.push(PartState::new(channel));
+        self.active_part = self.parts.len() - 1;
+        self.send(AudioEngineControlPacket::AddPart(channel));
+    }
+
+    pub fn remove_part(&mut self, index: usize) {
+        if self.parts.len() < 2 || index >= self.parts.len() {
+            return;
+        }
+
+        self.parts.remove(index);
+        if self.active_part >= index && self.active_part > 0 {
+            self.active_part -= 1;
+        }
+        self.send(AudioEngineControlPacket::RemovePart(index));
+    }
+
+    pub fn select_part(&mut self, index: usize) {
+        if index >= self.parts.len() {
+            return;
+        }
+
+        self.active_part = index;
+        self.send(AudioEngineControlPacket::SelectPart(index));
+    }
+
+    pub fn set_part_channel(&mut self, index: usize, channel: Option<u8>) {
+        if let Some(part) = self.parts.get_mut(index) {
+            part.settings.channel = channel;
+            self.send(AudioEngineControlPacket::SetPartChannel(index, channel));
+        }
+    }
+
+    pub fn set_part_level(&mut self, index: usize, level: f32) {
+        if let Some(part) = self.parts.get_mut(index) {
+            part.settings.level = level;
+            self.send(AudioEngineControlPacket::SetPartLevel(index, level));
+        }
+    }
+
+    pub fn set_part_pan(&mut self, index: usize, pan: f32) {
+        if let Some(part) = self.parts.get_mut(index) {
+            part.settings.pan = pan;
+            self.send(AudioEngineControlPacket::SetPartPan(index, pan));
+        }
+    }
+
+    pub fn get_bend_range(&self) -> f32 {
+        self.parts[self.active_part].bend_range
+    }
+
+    pub fn set_bend_range(&mut self, semitones: f32) {
+        self.part_mut().bend_range = semitones;
+        self.send(AudioEngineControlPacket::SetBendRange(semitones));
+    }
+
+    pub fn get_polyphony(&self) -> usize {
+        self.parts[self.active_part].polyphony
+    }
+
+    pub fn set_polyphony(&mut self, polyphony: usize) {
+        self.part_mut().polyphony = polyphony;
+        self.send(AudioEngineControlPacket::SetPolyphony(polyphony));
+    }
+
+    pub fn get_voice_mode(&self) -> (VoiceMode, NotePriority) {
+        let part = &self.parts[self.active_part];
+        (part.voice_mode, part.note_priority)
+    }
+
+    pub fn set_voice_mode(&mut self, mode: VoiceMode, priority: NotePriority) {
+        let part = self.part_mut();
+        part.voice_mode = mode;
+        part.note_priority = priority;
+        self.send(AudioEngineControlPacket::SetVoiceMode(mode, priority));
+    }
+
+    pub fn get_glide(&self) -> (f32, GlideMode) {
+        let part = &self.parts[self.active_part];
+        (part.glide_time, part.glide_mode)
+    }
+
+    pub fn set_glide(&mut self, time: f32, mode: GlideMode) {
+        let part = self.part_mut();
+        part.glide_time = time;
+        part.glide_mode = mode;
+        self.send(AudioEngineControlPacket::SetGlide(time, mode));
+    }
+
+    pub fn get_midi_channels(&self) -> u16 {
+        self.midi_channels
+    }
+
+    pub fn set_midi_channels(&mut self, mask: u16) {
+        self.midi_channels = mask;
+        self.send(AudioEngineControlPacket::SetMidiChannels(mask));
+    }
+
     pub fn set_parameter(&mut self, id: ParameterID, value: f32) {
-        self.to_engine.send(AudioEngineControlPacket::SetParameter(id, value)).unwrap();
+        self.send(AudioEngineControlPacket::SetParameter(id, value));
+    }
+
+    pub fn set_half_parameter(&mut self, half: Half, id: ParameterID, value: f32) {
+        self.send(AudioEngineControlPacket::SetHalfParameter(half, id, value));
+    }
+
+    pub fn get_keyboard_mode(&self) -> (KeyboardMode, u8) {
+        let part = &self.parts[self.active_part];
+        (part.keyboard_mode, part.split_point)
+    }
+
+    pub fn set_keyboard_mode(&mut self, mode: KeyboardMode, split_point: u8) {
+        let part = self.part_mut();
+        part.keyboard_mode = mode;
+        part.split_point = split_point;
+        self.send(AudioEngineControlPacket::SetKeyboardMode(mode, split_point));
+    }
+
+    pub fn get_velocity_curve(&self) -> &VelocityCurve {
+        &self.velocity_curve
+    }
+
+    pub fn set_velocity_curve(&mut self, curve: VelocityCurve) {
+        self.velocity_curve = curve.clone();
+        self.send(AudioEngineControlPacket::SetVelocityCurve(curve));
+    }
+
+    pub fn get_tuning_name(&self) -> &str {
+        &self.tuning_name
+    }
+
+    pub fn load_tuning(&mut self, scale: String, mapping: Option<String>) {
+        match Tuning::load(scale, mapping) {
+            Ok(tuning) => self.send(AudioEngineControlPacket::SetTuning(Box::new(tuning))),
+            Err(e) => self.report(EngineError::Tuning(format!("{:#}", e)))
+        }
+    }
+
+    // Loads into both halves when no half is given. The tuning it names is found next to it.
+    pub fn load_preset(&mut self, path: String, half: Option<Half>) {
+        let preset = match Preset::load(&path) {
+            Ok(preset) => preset,
+            Err(e) => {
+                self.report(EngineError::Preset(format!("{:#}", e)));
+                return;
+            }
+        };
+
+        let tuning = preset.tuning.clone();
+        self.send(AudioEngineControlPacket::SetPreset(Box::new(preset), half));
+
+        let directory = Path::new(&path).parent().unwrap_or(Path::new(""));
+        match tuning {
+            Some(tuning) => {
+                let scale = directory.join(&tuning.scale);
+                let mapping = tuning.mapping.map(|m| directory.join(m).to_string_lossy().to_string());
+                self.load_tuning(scale.to_string_lossy().to_string(), mapping);
+            },
+            None => self.reset_tuning()
+        }
+    }
+
+    pub fn reset_tuning(&mut self) {
+        self.send(AudioEngineControlPacket::ResetTuning);
     }
 
     pub fn toggle_playback(&mut self) {
         self.playback_status = !self.playback_status;
-        self.to_engine.send(AudioEngineControlPacket::TogglePlayback).unwrap();
+        self.send(AudioEngineControlPacket::TogglePlayback);
     }
 
     pub fn stop_playback(&mut self) {
         self.playback_status = false;
-        self.to_engine.send(AudioEngineControlPacket::StopPlayback).unwrap();
+        self.send(AudioEngineControlPacket::StopPlayback);
     }
 
     pub fn reset_playback(&mut self) {
-        self.to_engine.send(AudioEngineControlPacket::ResetPlayback).unwrap();
+        self.send(AudioEngineControlPacket::ResetPlayback);
+    }
+
+    pub fn get_position(&self) -> (usize, usize, usize) {
+        self.position
+    }
+
+    // Position in ticks, the engine reports back where it ended up
+    pub fn locate(&mut self, position: usize) {
+        self.send(AudioEngineControlPacket::Locate(position));
+    }
+
+    pub fn get_loop(&self) -> Option<(usize, usize)> {
+        self.loop_range
+    }
+
+    pub fn set_loop(&mut self, range: Option<(usize, usize)>) {
+        self.loop_range = range.filter(|(start, end)| start < end);
+        self.send(AudioEngineControlPacket::SetLoop(self.loop_range));
+    }
+
+    pub fn get_time_signature(&self) -> TimeSignature {
+        self.time_signature
+    }
+
+    pub fn set_time_signature(&mut self, signature: TimeSignature) {
+        self.time_signature = TimeSignature::new(signature.numerator, signature.denominator);
+        self.send(AudioEngineControlPacket::SetTimeSignature(self.time_signature));
+    }
+
+    pub fn get_midi_map(&self) -> &MidiMap {
+        &self.midi_map
+    }
+
+    pub fn get_midi_learn(&self) -> Option<ParameterID> {
+        self.midi_learn
+    }
+
+    pub fn learn_midi(&mut self, parameter: Option<ParameterID>) {
+        self.midi_learn = parameter;
+        self.send(AudioEngineControlPacket::LearnMidi(parameter));
+    }
+
+    pub fn set_midi_mapping(&mut self, mapping: MidiMapping) {
+        self.midi_map.set(mapping.clone());
+        self.save_midi_map();
+        self.send(AudioEngineControlPacket::SetMidiMapping(mapping));
+    }
+
+    pub fn remove_midi_mapping(&mut self, parameter: ParameterID) {
+        self.midi_map.remove(parameter);
+        self.save_midi_map();
+        self.send(AudioEngineControlPacket::RemoveMidiMapping(parameter));
+    }
+
+    fn save_midi_map(&mut self) {
+        if let Err(e) = self.midi_map.save() {
+            self.report(EngineError::MidiMap(format!("{:#}", e)));
+        }
+    }
+
+    pub fn get_mod_matrix(&self) -> &ModMatrix {
+        &self.mod_matrix
+    }
+
+    pub fn set_mod_route(&mut self, route: ModRoute) {
+        self.mod_matrix.set(route.clone());
+        self.send(AudioEngineControlPacket::SetModRoute(route));
+    }
+
+    pub fn remove_mod_route(&mut self, source: ModSource, destination: ParameterID) {
+        self.mod_matrix.remove(source, destination);
+        self.send(AudioEngineControlPacket::RemoveModRoute(source, destination));
+    }
+
+    pub fn take_parameter_changes(&mut self) -> Vec<(Option<Half>, ParameterID, f32)> {
+        std::mem::take(&mut self.parameter_changes)
     }
 
     pub fn get_playback_status(&self) -> bool {
@@ -196,14 +833,19 @@ impl EngineManager {
         self.latest_debug_info.clone()
     }
 
+    pub fn get_underruns(&self) -> usize {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    // Shown the same way as the engine's errors
+    fn report(&mut self, error: EngineError) {
+        self.audio_error = Some(format!("{:?}", error));
+    }
+
     pub fn run(&mut self) {
         while let Ok(packet) = self.from_handler.try_recv() {
-            match packet {
-                AudioEngineFeedbackPacket::BlockSize(size) => {
-                    self.config.buffer_size = Fixed(size as u32);
-                    self.to_engine.send(AudioEngineControlPacket::SetBlockSize(size)).unwrap();
-                },
-                _ => {}
+            if let AudioEngineFeedbackPacket::Error(error) = packet {
+                self.audio_error = Some(format!("{:?}", error));
             }
         }
 
@@ -212,7 +854,65 @@ impl EngineManager {
                 AudioEngineFeedbackPacket::DebugInfo(info) => {
                     self.latest_debug_info = info;
                 },
-                _ => {}
+                AudioEngineFeedbackPacket::ParameterChanged(id, value) => {
+                    self.parameter_changes.push((None, id, value));
+                },
+                AudioEngineFeedbackPacket::HalfParameterChanged(half, id, value) => {
+                    self.parameter_changes.push((Some(half), id, value));
+                },
+                AudioEngineFeedbackPacket::MidiMap(midi_map) => {
+                    self.midi_map = midi_map;
+                },
+                AudioEngineFeedbackPacket::MidiLearned(mapping) => {
+                    if self.midi_learn == Some(mapping.parameter) {
+                        self.midi_learn = None;
+                    }
+                    self.midi_map.set(mapping);
+                    self.save_midi_map();
+                },
+                AudioEngineFeedbackPacket::ModMatrix(mod_matrix) => {
+                    self.mod_matrix = mod_matrix;
+                },
+                AudioEngineFeedbackPacket::MpeZone(zone) => {
+                    self.mpe_zone = zone;
+                },
+                AudioEngineFeedbackPacket::Tuning(name) => {
+                    self.tuning_name = name;
+                },
+                AudioEngineFeedbackPacket::Generators(generators) => {
+                    self.generators = generators;
+                },
+                AudioEngineFeedbackPacket::Recording(recording) => {
+                    self.recording = recording;
+                },
+                AudioEngineFeedbackPacket::SaveTake(take, name) => {
+                    self.write_take(take, name);
+                },
+                AudioEngineFeedbackPacket::LoadPreset(path, half) => {
+                    self.load_preset(path, half);
+                },
+                AudioEngineFeedbackPacket::SequencerPattern(pattern) => {
+                    self.sequencer = pattern;
+                },
+                AudioEngineFeedbackPacket::PlaybackStatus(playing) => {
+                    self.playback_status = playing;
+                },
+                AudioEngineFeedbackPacket::Position(bar, beat, tick) => {
+                    self.position = (bar, beat, tick);
+                },
+                AudioEngineFeedbackPacket::MidiInputConnected(_) | AudioEngineFeedbackPacket::MidiOutputConnected(_) => {
+                    self.midi_error = None;
+                },
+                AudioEngineFeedbackPacket::Error(EngineError::NoMidiInput(e)) => {
+                    self.midi_error = Some(e);
+                },
+                AudioEngineFeedbackPacket::Error(EngineError::NoMidiOutput(e)) => {
+                    self.active_midi_out = None;
+                    self.midi_error = Some(e);
+                },
+                AudioEngineFeedbackPacket::Error(error) => {
+                    self.report(error);
+                }
             }
         }
     }
@@ -1,35 +1,153 @@
 pub mod manager;
 pub mod engine;
 pub mod handler;
+pub mod ring;
 
 pub use manager::EngineManager;
 pub use engine::AudioEngine;
 pub use handler::AudioHandler;
 
-use crate::{dsp::buffer::Buffer, system::{dev::DevInfo, parameter::ParameterID}};
+use crate::engine::clock::{ClockSync, TimeSignature};
+use crate::generators::arpeggiator::ArpSettings;
+use crate::generators::sequencer::{Pattern, Step};
+use crate::generators::{GeneratorSettings, Rate};
+use crate::engine::midi::MidiMessage;
+use crate::engine::midi_file::MidiFile;
+use crate::engine::recorder::Take;
+use crate::engine::mpe::MpeZone;
+use crate::engine::note_handler::{Half, KeyboardMode};
+use crate::engine::synthesis::{GlideMode, NotePriority, VoiceMode};
+use crate::modulators::matrix::{ModMatrix, ModRoute, ModSource};
+use crate::system::preset::Preset;
+use crate::system::tuning::Tuning;
+use crate::system::velocity::VelocityCurve;
+use crate::system::{controller::ControllerProfile, dev::DevInfo, midi_map::{MidiMap, MidiMapping}, parameter::ParameterID};
 
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum AudioEngineControlPacket {
     SetParameter(ParameterID, f32),
+    // Changes the parameter in the patch of one keyboard half only
+    SetHalfParameter(Half, ParameterID, f32),
+    Midi(MidiMessage),
+    // Asks the manager to read a preset, e.g. from OSC
+    LoadPreset(String, Option<Half>),
+    // Goes into both halves when no half is given, the tuning follows separately
+    SetPreset(Box<Preset>, Option<Half>),
+    // Keyboard mode and the lowest note of the upper half
+    SetKeyboardMode(KeyboardMode, u8),
+    // For the selected part
+    SetTuning(Box<Tuning>),
+    // Back to 12-TET
+    ResetTuning,
+    // For every part, presets can bring their own
+    SetVelocityCurve(VelocityCurve),
+
+    // Adds a part on a MIDI channel, None listens to every channel
+    AddPart(Option<u8>),
+    RemovePart(usize),
+    // Parameter and voice changes go to the selected part
+    SelectPart(usize),
+    SetPartChannel(usize, Option<u8>),
+    SetPartLevel(usize, f32),
+    // -1 is hard left, 1 is hard right
+    SetPartPan(usize, f32),
 
-    AudioPacket(Buffer, usize),
+    // Assigns the next incoming CC to the parameter, None cancels
+    LearnMidi(Option<ParameterID>),
+    SetMidiMapping(MidiMapping),
+    RemoveMidiMapping(ParameterID),
+    SetControllerProfile(Option<ControllerProfile>),
+    SetModRoute(ModRoute),
+    RemoveModRoute(ModSource, ParameterID),
+    // Semitones, for every channel outside the MPE zone
+    SetBendRange(f32),
+    SetPolyphony(usize),
+    SetVoiceMode(VoiceMode, NotePriority),
+    // Glide time in seconds
+    SetGlide(f32, GlideMode),
 
-    SetBlockSize(usize),
     SetMidiInput(usize),
+    // Bitmask of the MIDI channels to listen to, channel 1 is the lowest bit
+    SetMidiChannels(u16),
+    SetClockSync(ClockSync),
+    SetArpeggiator(ArpSettings),
+    SetSequencerStep(usize, Step),
+    // Steps added to the end are rests
+    SetSequencerLength(usize),
+    SetSequencerRate(Rate),
+    SetSequencerEnabled(bool),
+    // Generators next to the sequencer, each with its own length and rate.
+    // MIDI file generators come with the file already read.
+    AddGenerator(GeneratorSettings, Option<MidiFile>),
+    SetGenerator(usize, GeneratorSettings, Option<MidiFile>),
+    RemoveGenerator(usize),
+    // Recording replaces the last take
+    StartRecording,
+    StopRecording,
+    // Hands the last take to the manager to save, named after the time when no name is given
+    SaveTake(Option<String>),
+    // Quantizes the last take to steps of the given rate
+    LoadTakeIntoSequencer(Rate),
+    SetMpeZone(Option<MpeZone>),
     SetMidiOutput(String),
     SetAudioInput(String),
     SetAudioOutput(String),
 
     TogglePlayback,
+    StartPlayback,
+    // Stops where it is
+    PausePlayback,
+    // Stops and goes back to the start
     StopPlayback,
-    ResetPlayback
+    ResetPlayback,
+    // Position in ticks
+    Locate(usize),
+    // Loop start and end in ticks, None plays on
+    SetLoop(Option<(usize, usize)>),
+    SetTimeSignature(TimeSignature)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineError {
+    NoMidiInput(String),
+    NoMidiOutput(String),
+    Preset(String),
+    MidiMap(String),
+    Tuning(String),
+    Generator(String),
+    Recording(String),
+    AudioStream(String)
 }
 
 #[derive(Debug)]
 pub enum AudioEngineFeedbackPacket {
-    Block(Buffer),
     DebugInfo(DevInfo),
+    ParameterChanged(ParameterID, f32),
+    // Changed in the patch of one keyboard half only
+    HalfParameterChanged(Half, ParameterID, f32),
+    MidiMap(MidiMap),
+    MidiLearned(MidiMapping),
+    ModMatrix(ModMatrix),
+    // Also sent when a controller configures the zone with an MPE Configuration Message
+    MpeZone(Option<MpeZone>),
+    // Name of the selected part's tuning, also sent when it was changed over MTS
+    Tuning(String),
+    // Generators running next to the sequencer, sent whenever one is added or removed
+    Generators(Vec<GeneratorSettings>),
+    Recording(bool),
+    // A take for the manager to write to disk, under the given name
+    SaveTake(Take, Option<String>),
+    // A preset asked for over OSC, for the manager to read
+    LoadPreset(String, Option<Half>),
+    // The sequencer's pattern was replaced by the engine, e.g. by a take
+    SequencerPattern(Pattern),
 
-    BlockSize(usize)
+    MidiInputConnected(String),
+    MidiOutputConnected(String),
+    // Playback was started or stopped by the engine itself, e.g. by an external clock
+    PlaybackStatus(bool),
+    // Bar, beat and tick, sent whenever the position changes
+    Position(usize, usize, usize),
+    Error(EngineError)
 }
\ No newline at end of file
@@ -0,0 +1,151 @@
+// RingBuffer
+// Preallocated single-producer single-consumer queue. Neither side ever locks or allocates,
+// which makes it safe to use from the audio callback.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct RingBuffer<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    // Both indices only ever grow (wrapping), the slot is found by taking them modulo the capacity
+    head: AtomicUsize,
+    tail: AtomicUsize
+}
+
+// Each slot is only ever touched by one side at a time, handed over through head/tail
+unsafe impl<T: Send> Sync for RingBuffer<T> {}
+
+impl<T> RingBuffer<T> {
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn len(&self) -> usize {
+        self.tail.load(Ordering::Acquire).wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+}
+
+impl<T> Drop for RingBuffer<T> {
+    fn drop(&mut self) {
+        let head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+
+        let mut i = head;
+        while i != tail {
+            unsafe { self.slots[i % self.slots.len()].get_mut().assume_init_drop(); }
+            i = i.wrapping_add(1);
+        }
+    }
+}
+
+pub struct Producer<T> {
+    ring: Arc<RingBuffer<T>>
+}
+
+pub struct Consumer<T> {
+    ring: Arc<RingBuffer<T>>
+}
+
+pub fn ring_buffer<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    assert!(capacity > 0, "Ring buffer capacity must be at least 1");
+
+    let slots = (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect();
+    let ring = Arc::new(RingBuffer {
+        slots,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0)
+    });
+
+    (Producer { ring: ring.clone() }, Consumer { ring })
+}
+
+impl<T> Producer<T> {
+    // Hands the value back when the queue is full
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        let head = self.ring.head.load(Ordering::Acquire);
+
+        if tail.wrapping_sub(head) == self.ring.capacity() {
+            return Err(value);
+        }
+
+        unsafe { (*self.ring.slots[tail % self.ring.capacity()].get()).write(value); }
+        self.ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+
+        Ok(())
+    }
+
+    pub fn free(&self) -> usize {
+        self.ring.capacity() - self.ring.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity()
+    }
+}
+
+impl<T> Consumer<T> {
+    pub fn pop(&mut self) -> Option<T> {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let tail = self.ring.tail.load(Ordering::Acquire);
+
+        if head == tail {
+            return None;
+        }
+
+        let value = unsafe { (*self.ring.slots[head % self.ring.capacity()].get()).assume_init_read() };
+        self.ring.head.store(head.wrapping_add(1), Ordering::Release);
+
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+
+        for i in 0..4 {
+            producer.push(i).unwrap();
+        }
+        assert_eq!(producer.push(4), Err(4));
+        assert_eq!(producer.free(), 0);
+
+        // Wrap around a few times
+        for i in 0..20 {
+            assert_eq!(consumer.pop(), Some(i));
+            producer.push(i + 4).unwrap();
+        }
+
+        assert_eq!(consumer.len(), 4);
+
+        let thread = std::thread::spawn(move || {
+            let mut received = vec![];
+            while received.len() < 1000 {
+                if let Some(value) = consumer.pop() {
+                    received.push(value);
+                }
+            }
+            received
+        });
+
+        let mut i = 24;
+        while i < 1020 {
+            if producer.push(i).is_ok() {
+                i += 1;
+            }
+        }
+
+        let received = thread.join().unwrap();
+        assert!(received.iter().zip(received.iter().skip(1)).all(|(a, b)| b - a == 1));
+    }
+}
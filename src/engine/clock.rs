@@ -1,6 +1,73 @@
-use crate::generators::{sequencer::Sequencer, Generator, Note};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::engine::midi::{MidiEvent, MidiMessage};
+use crate::engine::midi_file::MidiFile;
+use crate::generators::{arpeggiator::Arpeggiator, sequencer::Sequencer, Generator, GeneratorSettings};
 
 pub const PPQ: usize = 48;
+// MIDI beat clock runs at 24 pulses per quarter note
+pub const MIDI_CLOCK_PPQ: usize = 24;
+// How much of each new pulse interval goes into the tempo estimate
+const TEMPO_SMOOTHING: f32 = 0.1;
+// Pulses further apart than this mean the master clock was stopped, the estimate starts over
+const MAX_PULSE_INTERVAL: f32 = 0.25;
+pub const MAX_GENERATORS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ClockSync {
+    Internal,
+    External
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TimeSignature {
+    pub numerator: u8,
+    // Note value of a beat, a power of two
+    pub denominator: u8
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        Self { numerator: 4, denominator: 4 }
+    }
+}
+
+impl TimeSignature {
+    // Up to 32 beats of a 32nd note
+    pub fn new(numerator: u8, denominator: u8) -> Self {
+        Self {
+            numerator: numerator.clamp(1, 32),
+            denominator: denominator.clamp(1, 32).next_power_of_two().min(32)
+        }
+    }
+
+    pub fn beat_ticks(&self) -> usize {
+        PPQ * 4 / self.denominator as usize
+    }
+
+    pub fn bar_ticks(&self) -> usize {
+        self.beat_ticks() * self.numerator as usize
+    }
+
+    // Bars and beats count from 1, like on a counter
+    pub fn bar_beat_tick(&self, position: usize) -> (usize, usize, usize) {
+        let bar = position / self.bar_ticks();
+        let beat = position % self.bar_ticks() / self.beat_ticks();
+        (bar + 1, beat + 1, position % self.beat_ticks())
+    }
+}
+
+// Where the clock is when following an external MIDI clock
+struct ExternalClock {
+    // Pulse the next Start/Continue resumes from, set by song position pointers
+    resume_pulse: usize,
+    // Pulse of the last received clock, None until the first pulse after a (re)start
+    pulse: Option<usize>,
+    // Sample of the last received pulse, on the clock's own sample counter
+    last_pulse_sample: Option<usize>,
+    // Smoothed pulse interval in samples
+    interval: f32
+}
 
 pub struct Clock {
     pub bpm: f32,
@@ -9,13 +76,30 @@ pub struct Clock {
 
     pub sample_rate: f32,
     pub sample_position: usize,
+    // Position with the fraction of a tick, added up per block so tempo changes don't make it jump
+    tick_position: f64,
     pub is_playing: bool,
     pub block_size: usize,
-    pub loop_point: usize,
+    // Start and end of the loop, in ticks
+    pub loop_range: Option<(usize, usize)>,
+    pub time_signature: TimeSignature,
 
-    pub note_ons: Vec<Note>,
+    // What the generators played during the last tick, placed where their ticks fell in the block
+    pub events: Vec<MidiEvent>,
+    // Where in the last block each MIDI clock pulse fell, in samples
+    pub clock_pulses: Vec<usize>,
+    // Where in the last block the loop went back to its start
+    pub looped: Option<usize>,
 
-    pub generators: Vec<Box<dyn Generator + Send + Sync>>
+    pub sync: ClockSync,
+    external: ExternalClock,
+    // Samples seen by the clock, running or not
+    samples_elapsed: usize,
+
+    pub generators: Vec<Box<dyn Generator + Send + Sync>>,
+    // These two are always there, the arpeggiator plays what is held while it's enabled
+    pub sequencer: Sequencer,
+    pub arpeggiator: Arpeggiator
 }
 
 impl Clock {
@@ -25,56 +109,111 @@ impl Clock {
             ppq: PPQ,
             position: 0,
             sample_position: 0,
+            tick_position: 0.0,
             is_playing: false,
             block_size,
             sample_rate,
-            loop_point: 0,
-            note_ons: vec![],
-            generators: vec![
-                Box::new(Sequencer::new())
-            ]
+            loop_range: None,
+            time_signature: TimeSignature::default(),
+            events: vec![],
+            clock_pulses: vec![],
+            looped: None,
+            sync: ClockSync::Internal,
+            external: ExternalClock {
+                resume_pulse: 0,
+                pulse: None,
+                last_pulse_sample: None,
+                interval: sample_rate * 60.0 / bpm / MIDI_CLOCK_PPQ as f32
+            },
+            samples_elapsed: 0,
+            generators: vec![],
+            sequencer: Sequencer::new(),
+            arpeggiator: Arpeggiator::new()
         }
     }
 
     pub fn tick(&mut self) -> Option<usize> {
+        self.events.clear();
+        self.clock_pulses.clear();
+        self.looped = None;
+        self.samples_elapsed += self.block_size;
+
         if self.is_playing {
+            let old_pos = self.position;
             self.sample_position += self.block_size;
 
-            let old_pos = self.position;
-            self.position = (self.sample_position as f32 / self.sample_rate * self.bpm / 60.0 * self.ppq as f32) as usize;
-            
-            if self.loop_point > 0 && self.position >= self.loop_point {
-                self.position -= self.loop_point;
-            }
-            
-            let has_passed_sixteenth = (old_pos as f32 / (self.ppq as f32 / 4.0)).floor() != (self.position as f32 / (self.ppq as f32 / 4.0)).floor();
-            let has_passed_eighth = (old_pos as f32 / (self.ppq as f32 / 2.0)).floor() != (self.position as f32 / (self.ppq as f32 / 2.0)).floor();
-            let has_passed_quarter = (old_pos as f32 / self.ppq as f32).floor() != (self.position as f32 / self.ppq as f32).floor();
-            
-            self.note_ons.clear();
-
-            for generator in &mut self.generators {
-                if has_passed_quarter {
-                    if let Some(note) = generator.quarter() {
-                        self.note_ons.push(note);
+            // Each tick reached during the block, and how far into the block it was reached
+            let mut reached = vec![];
+
+            match self.sync {
+                ClockSync::Internal => {
+                    let ticks = self.block_size as f64 / self.sample_rate as f64 * self.bpm as f64 / 60.0 * self.ppq as f64;
+                    let start = self.tick_position;
+                    let next = self.wrap(start + ticks);
+                    let first = start as usize + 1;
+
+                    // Pulses follow the position, so they line up with the loop and with locates
+                    match self.loop_range {
+                        Some((loop_start, loop_end)) if start + ticks >= loop_end as f64 => {
+                            let before = (loop_end as f64 - start).max(0.0);
+                            self.add_clock_pulses(start, start + before, 0.0, ticks);
+                            self.add_clock_pulses(loop_start as f64, next, before, ticks);
+                            self.looped = Some(self.block_offset(before, ticks));
+
+                            for tick in first..loop_end {
+                                reached.push((tick, self.block_offset(tick as f64 - start, ticks)));
+                            }
+                            for tick in loop_start..=next as usize {
+                                reached.push((tick, self.block_offset(tick as f64 - loop_start as f64 + before, ticks)));
+                            }
+                        },
+                        _ => {
+                            self.add_clock_pulses(start, start + ticks, 0.0, ticks);
+
+                            for tick in first..=(start + ticks) as usize {
+                                reached.push((tick, self.block_offset(tick as f64 - start, ticks)));
+                            }
+                        }
                     }
-                }
 
-                if has_passed_eighth {
-                    if let Some(note) = generator.eighth() {
-                        self.note_ons.push(note);
+                    self.tick_position = next;
+                    self.position = next as usize;
+                },
+                ClockSync::External => {
+                    // The master's pulses already landed at their own offsets, the ticks between them aren't timed
+                    self.position = self.wrap(self.external_position() as f64) as usize;
+                    if self.position > old_pos {
+                        reached.extend((old_pos + 1..=self.position).map(|tick| (tick, 0)));
                     }
                 }
+            }
+
+            // Generators still get to play between ticks, e.g. the arpeggiator's first step
+            if reached.is_empty() {
+                reached.push((self.position, 0));
+            }
 
-                if has_passed_sixteenth {
-                    if let Some(note) = generator.sixteenth() {
-                        self.note_ons.push(note);
+            let mut previous = old_pos;
+            let mut tempo = None;
+            for (position, offset) in reached {
+                // Looped, or moved back by the master clock
+                if position < previous {
+                    let start = match self.loop_range {
+                        Some((start, _)) if position >= start => start,
+                        _ => position
+                    };
+                    for generator in self.all_generators() {
+                        generator.locate(start);
                     }
                 }
 
-                if let Some(note) = generator.pulse(self.position) {
-                    self.note_ons.push(note);
-                }
+                tempo = self.pulse_generators(previous, position, offset).or(tempo);
+                previous = position;
+            }
+
+            // Following a MIDI file's tempo map, an external master decides for itself
+            if let (Some(bpm), ClockSync::Internal) = (tempo, self.sync) {
+                self.bpm = bpm;
             }
 
             Some(self.position)
@@ -83,19 +222,232 @@ impl Clock {
         }
     }
 
-    pub fn get_notes(&self) -> Vec<Note> {
-        self.note_ons.clone()
-    }
+    // Plays what the generators have for this position, `offset` samples into the block
+    fn pulse_generators(&mut self, old_pos: usize, position: usize, offset: usize) -> Option<f32> {
+        let has_passed_sixteenth = (old_pos as f32 / (self.ppq as f32 / 4.0)).floor() != (position as f32 / (self.ppq as f32 / 4.0)).floor();
+        let has_passed_eighth = (old_pos as f32 / (self.ppq as f32 / 2.0)).floor() != (position as f32 / (self.ppq as f32 / 2.0)).floor();
+        let has_passed_quarter = (old_pos as f32 / self.ppq as f32).floor() != (position as f32 / self.ppq as f32).floor();
 
-    pub fn get_note_offs(&self) -> Vec<u8> {
+        let mut note_ons = vec![];
         let mut note_offs = vec![];
+        let mut messages = vec![];
+        let mut tempo = None;
 
-        for generator in self.generators.iter() {
-            let notes = generator.get_note_offs();
-            note_offs.extend(notes);
+        for generator in self.all_generators() {
+            if has_passed_quarter {
+                if let Some(note) = generator.quarter() {
+                    note_ons.push(note);
+                }
+            }
+
+            if has_passed_eighth {
+                if let Some(note) = generator.eighth() {
+                    note_ons.push(note);
+                }
+            }
+
+            if has_passed_sixteenth {
+                if let Some(note) = generator.sixteenth() {
+                    note_ons.push(note);
+                }
+            }
+
+            if let Some(note) = generator.pulse(position) {
+                note_ons.push(note);
+            }
+
+            note_offs.extend(generator.get_note_offs());
+            messages.extend(generator.get_messages());
+            tempo = generator.get_tempo().or(tempo);
+        }
+
+        // Releases first, so a note played again right away isn't cut off
+        let note_offs = note_offs.into_iter().map(|(channel, pitch)| MidiMessage::NoteOff(channel, pitch, 0));
+        let note_ons = note_ons.into_iter().map(|note| MidiMessage::NoteOn(note.channel, note.pitch, (note.velocity * 127.0) as u8));
+        for message in note_offs.chain(messages).chain(note_ons) {
+            self.events.push(MidiEvent { offset, message });
+        }
+
+        tempo
+    }
+
+    // Picks up from where the clock is
+    pub fn add_generator(&mut self, mut generator: Box<dyn Generator + Send + Sync>) {
+        if self.generators.len() < MAX_GENERATORS {
+            generator.locate(self.position);
+            self.generators.push(generator);
+        }
+    }
+
+    // Both return what it takes to silence the old generator
+    pub fn set_generator(&mut self, index: usize, settings: &GeneratorSettings, file: Option<&MidiFile>) -> Result<Vec<MidiMessage>> {
+        let position = self.position;
+        let Some(generator) = self.generators.get_mut(index) else {
+            return Ok(vec![]);
+        };
+
+        // Changed in place where possible, so it keeps its phase
+        if generator.configure(settings) {
+            return Ok(vec![]);
         }
 
-        note_offs
+        let mut replacement = settings.build(file)?;
+        replacement.locate(position);
+        let messages = Self::silence(generator.as_mut());
+        *generator = replacement;
+        Ok(messages)
+    }
+
+    pub fn remove_generator(&mut self, index: usize) -> Vec<MidiMessage> {
+        if index >= self.generators.len() {
+            return vec![];
+        }
+
+        let mut old = self.generators.remove(index);
+        Self::silence(old.as_mut())
+    }
+
+    fn silence(generator: &mut dyn Generator) -> Vec<MidiMessage> {
+        generator.stop();
+        generator.get_note_offs().into_iter()
+            .map(|(channel, pitch)| MidiMessage::NoteOff(channel, pitch, 0))
+            .chain(generator.get_messages())
+            .collect()
+    }
+
+    fn all_generators(&mut self) -> impl Iterator<Item = &mut dyn Generator> + '_ {
+        self.generators.iter_mut()
+            .map(|generator| generator.as_mut() as &mut dyn Generator)
+            .chain([&mut self.sequencer as &mut dyn Generator, &mut self.arpeggiator as &mut dyn Generator])
+    }
+
+    pub fn get_events(&self) -> Vec<MidiEvent> {
+        self.events.clone()
+    }
+
+    // Collects the notes the generators are still holding, so they can be stopped along with the clock
+    pub fn release_notes(&mut self) -> Vec<MidiMessage> {
+        self.all_generators()
+            .flat_map(Self::silence)
+            .collect()
+    }
+
+    // Lets the generators see what is being played. Returns true when one of them took the message,
+    // new notes are only taken while the clock runs so nothing goes silent while stopped.
+    pub fn handle_message(&mut self, message: &MidiMessage) -> bool {
+        if !self.is_playing && matches!(message, MidiMessage::NoteOn(_, _, velocity) if *velocity > 0) {
+            return false;
+        }
+
+        // Every generator gets to see it, also when an earlier one took it
+        let mut taken = false;
+        for generator in self.all_generators() {
+            taken |= generator.handle_message(message);
+        }
+        taken
+    }
+
+    // Counted from the sample position, so it keeps running across loop points
+    // Pulses on the grid between two tick positions, `skipped` ticks into a block of `ticks`
+    fn add_clock_pulses(&mut self, from: f64, to: f64, skipped: f64, ticks: f64) {
+        if ticks <= 0.0 {
+            return;
+        }
+
+        let per_pulse = self.ppq as f64 / MIDI_CLOCK_PPQ as f64;
+        let first = (from / per_pulse).ceil() as usize;
+        let last = (to / per_pulse).ceil() as usize;
+        for pulse in first..last {
+            let offset = self.block_offset(pulse as f64 * per_pulse - from + skipped, ticks);
+            self.clock_pulses.push(offset);
+        }
+    }
+
+    fn block_offset(&self, ticks_in: f64, ticks: f64) -> usize {
+        if ticks <= 0.0 {
+            return 0;
+        }
+        ((ticks_in / ticks * self.block_size as f64) as usize).min(self.block_size.saturating_sub(1))
+    }
+
+    pub fn set_sync(&mut self, sync: ClockSync) {
+        self.sync = sync;
+        self.external.pulse = None;
+        self.external.last_pulse_sample = None;
+    }
+
+    // Feeds a transport message that arrived at the given offset into the upcoming block.
+    // Only has an effect while following an external clock.
+    pub fn receive(&mut self, message: &MidiMessage, offset: usize) {
+        if self.sync != ClockSync::External {
+            return;
+        }
+
+        match *message {
+            MidiMessage::Clock => self.receive_pulse(self.samples_elapsed + offset),
+            MidiMessage::Start => {
+                self.sample_position = 0;
+                self.locate(0);
+                self.is_playing = true;
+            },
+            MidiMessage::Continue => {
+                self.is_playing = true;
+            },
+            MidiMessage::Stop => {
+                self.is_playing = false;
+                // A later Continue picks up after the last pulse we saw
+                if let Some(pulse) = self.external.pulse.take() {
+                    self.external.resume_pulse = pulse + 1;
+                }
+            },
+            MidiMessage::SongPosition(beats) => {
+                // One MIDI beat is a sixteenth note, six clock pulses
+                let pulse = beats as usize * 6;
+                self.locate(self.pulse_to_position(pulse));
+                self.external.resume_pulse = pulse;
+            },
+            _ => {}
+        }
+    }
+
+    fn receive_pulse(&mut self, sample: usize) {
+        // Tempo is tracked even while stopped, so we're in time as soon as the master starts
+        if let Some(last) = self.external.last_pulse_sample {
+            let interval = sample.saturating_sub(last) as f32;
+
+            if interval > 0.0 && interval < MAX_PULSE_INTERVAL * self.sample_rate {
+                self.external.interval += (interval - self.external.interval) * TEMPO_SMOOTHING;
+                self.bpm = self.sample_rate * 60.0 / (self.external.interval * MIDI_CLOCK_PPQ as f32);
+            }
+        }
+        self.external.last_pulse_sample = Some(sample);
+
+        if self.is_playing {
+            self.external.pulse = Some(match self.external.pulse {
+                Some(pulse) => pulse + 1,
+                None => self.external.resume_pulse
+            });
+        }
+    }
+
+    fn pulse_to_position(&self, pulse: usize) -> usize {
+        pulse * self.ppq / MIDI_CLOCK_PPQ
+    }
+
+    // Follows the received pulses, interpolating in between when our ppq is finer than the MIDI clock
+    fn external_position(&self) -> usize {
+        let Some(pulse) = self.external.pulse else {
+            return self.pulse_to_position(self.external.resume_pulse);
+        };
+
+        let ticks_per_pulse = (self.ppq / MIDI_CLOCK_PPQ).max(1);
+        let since_pulse = self.external.last_pulse_sample
+            .map(|last| self.samples_elapsed.saturating_sub(last))
+            .unwrap_or(0);
+        let fraction = (since_pulse as f32 / self.external.interval * ticks_per_pulse as f32) as usize;
+
+        // Never run ahead into the next pulse, it hasn't arrived yet
+        self.pulse_to_position(pulse) + fraction.min(ticks_per_pulse - 1)
     }
 
     pub fn toggle_play(&mut self) {
@@ -103,8 +455,41 @@ impl Clock {
     }
 
     pub fn reset(&mut self) {
-        self.position = 0;
         self.sample_position = 0;
+        self.locate(0);
+    }
+
+    // Generators that hold notes should be released before moving
+    pub fn locate(&mut self, position: usize) {
+        self.position = position;
+        self.tick_position = position as f64;
+
+        // The master's next pulse picks up from here
+        self.external.resume_pulse = position * MIDI_CLOCK_PPQ / self.ppq;
+        self.external.pulse = None;
+
+        for generator in self.all_generators() {
+            generator.locate(position);
+        }
+    }
+
+    // A loop that ends before it starts is no loop
+    pub fn set_loop(&mut self, range: Option<(usize, usize)>) {
+        self.loop_range = range.filter(|(start, end)| start < end);
+    }
+
+    pub fn get_bar_beat_tick(&self) -> (usize, usize, usize) {
+        self.time_signature.bar_beat_tick(self.position)
+    }
+
+    // Back to the loop start once the end is reached
+    fn wrap(&self, position: f64) -> f64 {
+        match self.loop_range {
+            Some((start, end)) if position >= end as f64 => {
+                start as f64 + (position - end as f64) % (end - start) as f64
+            },
+            _ => position
+        }
     }
 
     pub fn set_bpm(&mut self, bpm: f32) {
@@ -118,4 +503,137 @@ impl Clock {
     pub fn set_block_size(&mut self, block_size: usize) {
         self.block_size = block_size;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::midi_file::{MidiFile, MidiFileEvent, MidiFileTrack, TrackEvent};
+    use crate::generators::euclidean::EuclideanSettings;
+    use crate::generators::player::MidiFilePlayer;
+    use crate::generators::{GeneratorSettings, Rate};
+
+    #[test]
+    fn test_external_clock() {
+        let sample_rate = 48_000.0;
+        let block_size = 480;
+        let mut clock = Clock::new(120.0, sample_rate, block_size);
+        clock.set_sync(ClockSync::External);
+        clock.generators.clear();
+
+        clock.receive(&MidiMessage::SongPosition(4), 0);
+        assert_eq!(clock.position, PPQ);
+
+        clock.receive(&MidiMessage::Start, 0);
+        assert!(clock.is_playing);
+
+        // A master at 140 bpm, four seconds worth of pulses
+        let interval = sample_rate as f64 * 60.0 / 140.0 / MIDI_CLOCK_PPQ as f64;
+        let mut next_pulse = 0.0;
+        let mut pulses = 0;
+
+        for block in 0..400 {
+            let block_start = block * block_size;
+            while (next_pulse as usize) < block_start + block_size {
+                clock.receive(&MidiMessage::Clock, next_pulse as usize - block_start);
+                next_pulse += interval;
+                pulses += 1;
+            }
+
+            clock.tick();
+        }
+
+        assert!((clock.bpm - 140.0).abs() < 0.5, "Estimated {} bpm", clock.bpm);
+
+        let expected = (pulses - 1) * PPQ / MIDI_CLOCK_PPQ;
+        assert!(clock.position >= expected && clock.position < expected + PPQ / MIDI_CLOCK_PPQ);
+
+        clock.receive(&MidiMessage::Stop, 0);
+        assert!(!clock.is_playing);
+        assert_eq!(clock.tick(), None);
+
+        // Skipping ahead doesn't play what was skipped
+        let event = |tick: u64, pitch: u8| TrackEvent { tick, event: MidiFileEvent::Message(MidiMessage::NoteOn(0, pitch, 100)) };
+        let file = MidiFile { format: 0, ppq: 96, tracks: vec![MidiFileTrack { events: vec![event(0, 60), event(96, 62), event(384, 64)] }] };
+        let mut clock = Clock::new(120.0, sample_rate, block_size);
+        clock.set_sync(ClockSync::External);
+        clock.sequencer.set_enabled(false);
+        clock.add_generator(Box::new(MidiFilePlayer::new(&file)));
+
+        clock.receive(&MidiMessage::SongPosition(16), 0);
+        clock.receive(&MidiMessage::Continue, 0);
+        clock.receive(&MidiMessage::Clock, 0);
+        assert_eq!(clock.tick(), Some(PPQ * 4));
+        assert_eq!(clock.get_events(), vec![MidiEvent { offset: 0, message: MidiMessage::NoteOn(0, 64, 100) }]);
+    }
+
+    #[test]
+    fn test_polymeter() {
+        let mut clock = Clock::new(120.0, 48_000.0, 64);
+        clock.sequencer.set_enabled(false);
+        clock.is_playing = true;
+
+        // Three in eight sixteenths against two in five eighths
+        let kick = EuclideanSettings { pitches: vec![36], ..Default::default() };
+        let snare = EuclideanSettings { steps: 5, pulses: 2, rate: Rate::Eighth, pitches: vec![38], ..Default::default() };
+        clock.add_generator(GeneratorSettings::Euclidean(kick).build(None).unwrap());
+        clock.add_generator(GeneratorSettings::Euclidean(snare).build(None).unwrap());
+
+        // Twenty quarter notes, both patterns line up again every ten
+        let mut counts = [0, 0];
+        let mut offsets = vec![];
+        while clock.position < PPQ * 20 - 1 {
+            clock.tick();
+            for event in clock.get_events() {
+                if let MidiMessage::NoteOn(_, pitch, _) = event.message {
+                    counts[(pitch == 38) as usize] += 1;
+                    offsets.push(event.offset);
+                }
+            }
+        }
+        assert_eq!(counts, [30, 16]);
+        // Notes start where their tick fell, not at the start of the block
+        assert!(offsets.iter().all(|offset| *offset < 64));
+        assert!(offsets.iter().any(|offset| *offset > 0));
+
+        assert!(clock.remove_generator(5).is_empty());
+        clock.remove_generator(0);
+        assert_eq!(clock.generators.len(), 1);
+    }
+
+    #[test]
+    fn test_transport() {
+        let mut clock = Clock::new(120.0, 48_000.0, 480);
+        clock.sequencer.set_enabled(false);
+        clock.time_signature = TimeSignature::new(3, 4);
+        clock.set_loop(Some((PPQ * 4, PPQ * 6)));
+
+        clock.locate(PPQ * 5);
+        assert_eq!(clock.get_bar_beat_tick(), (2, 3, 0));
+
+        // Two quarter notes of loop at a little under a tick per block
+        clock.is_playing = true;
+        let mut wrapped = 0;
+        let mut pulses = 0;
+        for _ in 0..200 {
+            let old_position = clock.position;
+            clock.tick();
+            if clock.position < old_position {
+                assert!(clock.looped.is_some());
+                wrapped += 1;
+            }
+            assert!(clock.clock_pulses.iter().all(|offset| *offset < 480));
+            pulses += clock.clock_pulses.len();
+            assert!((PPQ * 4..PPQ * 6).contains(&clock.position));
+        }
+        assert_eq!(wrapped, 2);
+        // Four quarter notes went by, loop or not
+        assert_eq!(pulses, 4 * MIDI_CLOCK_PPQ);
+
+        assert_eq!(TimeSignature::new(6, 8).bar_beat_tick(PPQ * 3 + 6), (2, 1, 6));
+        assert_eq!(TimeSignature::new(0, 5), TimeSignature { numerator: 1, denominator: 8 });
+
+        clock.set_loop(Some((PPQ * 2, PPQ)));
+        assert_eq!(clock.loop_range, None);
+    }
+}
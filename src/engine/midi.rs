@@ -1,17 +1,33 @@
 use std::error::Error;
-use std::sync::mpsc::Receiver;
-use std::time::Duration;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
 use std::borrow::Borrow;
-use midir::{Ignore, MidiInput, MidiInputConnection, MidiInputPort};
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiInputPort, MidiOutput, MidiOutputConnection};
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, PartialEq)]
+// Channels are zero-based (0-15), pitch bend is centered around 0 (-8192..8191)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum MidiMessage {
-    NoteOn(u8, u8),
-    NoteOff(u8, u8),
-    MidiCC(u8, u8),
-    PitchBend(u8, u8),
+    NoteOn(u8, u8, u8),
+    NoteOff(u8, u8, u8),
+    PolyPressure(u8, u8, u8),
+    MidiCC(u8, u8, u8),
     ModWheel(u8, u8),
+    ProgramChange(u8, u8),
+    ChannelPressure(u8, u8),
+    PitchBend(u8, i16),
+
+    SongPosition(u16),
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+
+    // Payload between the 0xF0 and 0xF7 bytes
+    SysEx(Vec<u8>),
     Unknown
 }
 
@@ -21,24 +37,259 @@ pub struct MidiInputCallbackInfo {
     pub message: MidiMessage
 }
 
+pub const ALL_CHANNELS: u16 = 0xFFFF;
+
+// A message positioned in samples, relative to the start of the block it belongs to
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiEvent {
+    pub offset: usize,
+    pub message: MidiMessage
+}
+
+// Places incoming messages on the engine's sample timeline using their midir timestamps.
+// Messages are delayed by a fixed latency so that their relative timing survives,
+// instead of every message landing at the start of the next block.
+pub struct MidiEventScheduler {
+    sample_rate: f32,
+    latency: usize,
+    // midir timestamp (µs) and the engine sample position it corresponds to
+    anchor: Option<(u64, usize)>,
+    pending: Vec<(usize, MidiMessage)>
+}
+
+impl MidiEventScheduler {
+    pub fn new(sample_rate: f32, latency: usize) -> Self {
+        Self {
+            sample_rate,
+            latency,
+            anchor: None,
+            pending: vec![]
+        }
+    }
+
+    pub fn schedule(&mut self, info: MidiInputCallbackInfo, block_start: usize) {
+        let (anchor_stamp, anchor_sample) = *self.anchor.get_or_insert((info.timestamp, block_start));
+
+        let elapsed = info.timestamp.saturating_sub(anchor_stamp) as f64 * self.sample_rate as f64 / 1_000_000.0;
+        let mut target = anchor_sample + elapsed as usize + self.latency;
+
+        if target + self.latency < block_start || target > block_start + 4 * self.latency {
+            // The MIDI clock and the audio clock have drifted apart, start over from this message
+            self.anchor = Some((info.timestamp, block_start.saturating_sub(self.latency)));
+            target = block_start;
+        }
+
+        self.pending.push((target.max(block_start), info.message));
+    }
+
+    // For messages without a timestamp, e.g. from OSC or the GUI
+    pub fn push(&mut self, position: usize, message: MidiMessage) {
+        self.pending.push((position, message));
+    }
+
+    // Returns the messages that fall inside the given block, in order
+    pub fn take_block(&mut self, block_start: usize, block_size: usize) -> Vec<MidiEvent> {
+        let block_end = block_start + block_size;
+
+        let mut events: Vec<MidiEvent> = self.pending.iter()
+            .filter(|(position, _)| *position < block_end)
+            .map(|(position, message)| MidiEvent {
+                offset: position.saturating_sub(block_start),
+                message: message.clone()
+            })
+            .collect();
+        self.pending.retain(|(position, _)| *position >= block_end);
+
+        events.sort_by_key(|e| e.offset);
+        events
+    }
+
+    pub fn set_latency(&mut self, latency: usize) {
+        self.latency = latency;
+        self.anchor = None;
+    }
+}
+
 impl MidiMessage {
+    // Parses a single complete message, anything after the first message is ignored
     pub fn from(data: &[u8]) -> Self {
-        match data[0] {
-            144 => MidiMessage::NoteOn(data[1], data[2]),
-            128 => MidiMessage::NoteOff(data[1], data[2]),
-            176 => MidiMessage::MidiCC(data[1], data[2]),
-            224 => MidiMessage::PitchBend(data[1], data[2]),
-            228 => MidiMessage::ModWheel(data[1], data[2]),
-            _ => MidiMessage::Unknown
+        MidiParser::new().parse(data).into_iter().next().unwrap_or(MidiMessage::Unknown)
+    }
+
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            MidiMessage::NoteOn(channel, _, _) |
+            MidiMessage::NoteOff(channel, _, _) |
+            MidiMessage::PolyPressure(channel, _, _) |
+            MidiMessage::MidiCC(channel, _, _) |
+            MidiMessage::ModWheel(channel, _) |
+            MidiMessage::ProgramChange(channel, _) |
+            MidiMessage::ChannelPressure(channel, _) |
+            MidiMessage::PitchBend(channel, _) => Some(channel),
+            _ => None
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            MidiMessage::NoteOn(channel, note, velocity) => vec![0x90 | channel, *note, *velocity],
+            MidiMessage::NoteOff(channel, note, velocity) => vec![0x80 | channel, *note, *velocity],
+            MidiMessage::PolyPressure(channel, note, value) => vec![0xA0 | channel, *note, *value],
+            MidiMessage::MidiCC(channel, cc, value) => vec![0xB0 | channel, *cc, *value],
+            MidiMessage::ModWheel(channel, value) => vec![0xB0 | channel, 1, *value],
+            MidiMessage::ProgramChange(channel, program) => vec![0xC0 | channel, *program],
+            MidiMessage::ChannelPressure(channel, value) => vec![0xD0 | channel, *value],
+            MidiMessage::PitchBend(channel, value) => {
+                let value = (*value + 8192).clamp(0, 0x3FFF) as u16;
+                vec![0xE0 | channel, (value & 0x7F) as u8, (value >> 7) as u8]
+            },
+            MidiMessage::SongPosition(position) => vec![0xF2, (position & 0x7F) as u8, ((position >> 7) & 0x7F) as u8],
+            MidiMessage::Clock => vec![0xF8],
+            MidiMessage::Start => vec![0xFA],
+            MidiMessage::Continue => vec![0xFB],
+            MidiMessage::Stop => vec![0xFC],
+            MidiMessage::ActiveSensing => vec![0xFE],
+            MidiMessage::Reset => vec![0xFF],
+            MidiMessage::SysEx(data) => {
+                let mut bytes = Vec::with_capacity(data.len() + 2);
+                bytes.push(0xF0);
+                bytes.extend_from_slice(data);
+                bytes.push(0xF7);
+                bytes
+            },
+            MidiMessage::Unknown => vec![]
+        }
+    }
+
+    fn decode(status: u8, data: &[u8]) -> Self {
+        let channel = status & 0x0F;
+
+        match status & 0xF0 {
+            0x80 => MidiMessage::NoteOff(channel, data[0], data[1]),
+            // Running status senders use velocity 0 instead of a real note off
+            0x90 if data[1] == 0 => MidiMessage::NoteOff(channel, data[0], 0),
+            0x90 => MidiMessage::NoteOn(channel, data[0], data[1]),
+            0xA0 => MidiMessage::PolyPressure(channel, data[0], data[1]),
+            0xB0 if data[0] == 1 => MidiMessage::ModWheel(channel, data[1]),
+            0xB0 => MidiMessage::MidiCC(channel, data[0], data[1]),
+            0xC0 => MidiMessage::ProgramChange(channel, data[0]),
+            0xD0 => MidiMessage::ChannelPressure(channel, data[0]),
+            0xE0 => MidiMessage::PitchBend(channel, (((data[1] as i16) << 7) | data[0] as i16) - 8192),
+            _ => match status {
+                0xF2 => MidiMessage::SongPosition(((data[1] as u16) << 7) | data[0] as u16),
+                _ => MidiMessage::Unknown
+            }
+        }
+    }
+}
+
+// Number of data bytes following a status byte
+fn data_length(status: u8) -> usize {
+    match status & 0xF0 {
+        0xC0 | 0xD0 => 1,
+        0xF0 => match status {
+            0xF1 | 0xF3 => 1,
+            0xF2 => 2,
+            _ => 0
+        },
+        _ => 2
+    }
+}
+
+// Turns a MIDI byte stream into messages. State is kept between calls,
+// so running status and SysEx dumps may be split over several packets.
+#[derive(Default)]
+pub struct MidiParser {
+    status: u8,
+    data: Vec<u8>,
+    sysex: Option<Vec<u8>>
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(&mut self, bytes: &[u8]) -> Vec<MidiMessage> {
+        let mut messages = vec![];
+
+        for &byte in bytes {
+            match byte {
+                // Realtime messages may appear anywhere, even in the middle of another message
+                0xF8..=0xFF => {
+                    let message = match byte {
+                        0xF8 => MidiMessage::Clock,
+                        0xFA => MidiMessage::Start,
+                        0xFB => MidiMessage::Continue,
+                        0xFC => MidiMessage::Stop,
+                        0xFE => MidiMessage::ActiveSensing,
+                        0xFF => MidiMessage::Reset,
+                        _ => continue
+                    };
+                    messages.push(message);
+                },
+                0xF0 => {
+                    self.status = 0;
+                    self.sysex = Some(vec![]);
+                },
+                0xF7 => {
+                    if let Some(sysex) = self.sysex.take() {
+                        messages.push(MidiMessage::SysEx(sysex));
+                    }
+                },
+                0x80..=0xF6 => {
+                    // Any other status byte also ends a SysEx dump
+                    if let Some(sysex) = self.sysex.take() {
+                        messages.push(MidiMessage::SysEx(sysex));
+                    }
+
+                    self.status = byte;
+                    self.data.clear();
+
+                    if data_length(byte) == 0 {
+                        // Tune request, nothing we act on
+                        self.status = 0;
+                    }
+                },
+                _ => {
+                    if let Some(sysex) = self.sysex.as_mut() {
+                        sysex.push(byte);
+                        continue;
+                    }
+
+                    // Stray data byte without a status to run on
+                    if self.status == 0 {
+                        continue;
+                    }
+
+                    self.data.push(byte);
+                    if self.data.len() == data_length(self.status) {
+                        match MidiMessage::decode(self.status, &self.data) {
+                            MidiMessage::Unknown => {},
+                            message => messages.push(message)
+                        }
+                        self.data.clear();
+
+                        // System common messages cancel running status
+                        if self.status >= 0xF0 {
+                            self.status = 0;
+                        }
+                    }
+                }
+            }
         }
+
+        messages
     }
 }
 
 pub struct MidiInputHandler {
-    pub midi_in_port: MidiInputPort,
+    pub midi_in_port: Option<MidiInputPort>,
+    // One bit per channel, channel 1 is the lowest bit
+    channel_mask: u16,
     callbacks: Vec<Box<dyn FnMut(MidiInputCallbackInfo) + Send>>,
     from_midi: Receiver<MidiInputCallbackInfo>,
-    midi_connection: MidiInputConnection<()>
+    midi_connection: Option<MidiInputConnection<()>>
 }
 
 impl MidiInputHandler {
@@ -46,16 +297,28 @@ impl MidiInputHandler {
         self.callbacks.push(Box::new(callback));
     }
 
+    // A handler without a device attached, used until a MIDI input becomes available
+    pub fn null() -> Self {
+        let (_, rx) = std::sync::mpsc::channel();
+
+        Self {
+            midi_in_port: None,
+            channel_mask: ALL_CHANNELS,
+            callbacks: Vec::new(),
+            from_midi: rx,
+            midi_connection: None
+        }
+    }
+
     pub fn init() -> Result<Self, Box<dyn Error>> {
         let mut midi_in = MidiInput::new("Donut MIDI IN")?;
         midi_in.ignore(Ignore::None);
 
         let in_ports = midi_in.ports();
-        let in_port = match in_ports.len() {
+        match in_ports.len() {
             0 => return Err("No MIDI inputs available".into()),
             1 => {
-                println!("Only one port available. Donut uses this one automatically: {}",midi_in.port_name(&in_ports[0]).unwrap());
-                &in_ports[0]
+                println!("Only one port available. Donut uses this one automatically: {}", midi_in.port_name(&in_ports[0])?);
             },
             _ => {
                 println!("Multiple devices found:");
@@ -63,75 +326,161 @@ impl MidiInputHandler {
                     let pn = midi_in.port_name(port);
                     println!("\t{:?}", pn);
                 }
-
-                // TODO: Add input selection
-                &in_ports[0]
             }
         };
 
+        // TODO: Add input selection
+        Self::connect(midi_in, &in_ports[0])
+    }
+
+    fn connect(midi_in: MidiInput, in_port: &MidiInputPort) -> Result<Self, Box<dyn Error>> {
         let (tx, rx) = std::sync::mpsc::channel();
+        let mut parser = MidiParser::new();
 
         let _in = midi_in.connect(
             in_port,
             "donut-midi-in",
-            move |stamp, message, _| {
-                if message.len() == 3 {
-                    tx.send(MidiInputCallbackInfo {
+            move |stamp, bytes, _| {
+                for message in parser.parse(bytes) {
+                    // The receiving end is gone when the engine shuts down
+                    let _ = tx.send(MidiInputCallbackInfo {
                         timestamp: stamp,
-                        message: MidiMessage::from(message)
-                    }).unwrap();
+                        message
+                    });
                 }
             },
             ())?;
 
         Ok(Self {
-            midi_in_port: in_port.clone(),
+            midi_in_port: Some(in_port.clone()),
+            channel_mask: ALL_CHANNELS,
             callbacks: Vec::new(),
             from_midi: rx,
-            midi_connection: _in
+            midi_connection: Some(_in)
         })
     }
-    
-    pub fn set_input(&mut self, port: usize) {        
-        let mut midi_in = MidiInput::new("Donut MIDI IN").unwrap();
+
+    // Connects to the given port and returns its name. The current connection is kept on failure.
+    pub fn set_input(&mut self, port: usize) -> Result<String, Box<dyn Error>> {
+        let mut midi_in = MidiInput::new("Donut MIDI IN")?;
         midi_in.ignore(Ignore::None);
 
         let in_ports = midi_in.ports();
-        let in_port = &in_ports[port];
+        let in_port = in_ports.get(port).ok_or("MIDI input is no longer available")?;
+        let name = midi_in.port_name(in_port)?;
 
-        let (tx, rx) = std::sync::mpsc::channel();
+        let channel_mask = self.channel_mask;
+        *self = Self::connect(midi_in, in_port)?;
+        self.channel_mask = channel_mask;
 
-        let _in = midi_in.connect(
-            in_port,
-            "donut-midi-in",
-            move |stamp, message, _| {
-                if message.len() == 3 {
-                    tx.send(MidiInputCallbackInfo {
-                        timestamp: stamp,
-                        message: MidiMessage::from(message)
-                    }).unwrap();
-                }
-            },
-            ()).unwrap();
+        Ok(name)
+    }
 
-        *self = Self {
-            midi_in_port: in_port.clone(),
-            callbacks: Vec::new(),
-            from_midi: rx,
-            midi_connection: _in
-        };
+    pub fn set_channel_mask(&mut self, mask: u16) {
+        self.channel_mask = mask;
     }
 
-    pub fn run(&mut self) -> Vec<MidiMessage> {
+    pub fn run(&mut self) -> Vec<MidiInputCallbackInfo> {
         let mut messages = vec![];
         while let Ok(msg) = self.from_midi.try_recv() {
-            messages.push(msg.message);
+            // System messages aren't tied to a channel and always pass
+            if let Some(channel) = msg.message.channel() {
+                if self.channel_mask & (1 << channel) == 0 {
+                    continue;
+                }
+            }
+
+            messages.push(msg);
         }
 
         messages
     }
 }
 
+// Messages go out from their own thread, each one when it is due. The engine renders ahead
+// of what is playing, sending straight away would put the output ahead of the audio.
+pub struct MidiOutputHandler {
+    sender: Option<Sender<(Instant, Vec<u8>)>>
+}
+
+impl MidiOutputHandler {
+    // A handler without a device attached, messages are dropped until an output is set
+    pub fn null() -> Self {
+        Self {
+            sender: None
+        }
+    }
+
+    pub fn list_outputs() -> Vec<String> {
+        let Ok(midi_out) = MidiOutput::new("Donut MIDI OUT") else {
+            return vec![];
+        };
+
+        midi_out.ports().iter()
+            .filter_map(|port| midi_out.port_name(port).ok())
+            .collect()
+    }
+
+    // Connects to the output with the given name. The current connection is kept on failure.
+    pub fn set_output(&mut self, name: &str) -> Result<String, Box<dyn Error>> {
+        let midi_out = MidiOutput::new("Donut MIDI OUT")?;
+
+        let out_ports = midi_out.ports();
+        let out_port = out_ports.iter()
+            .find(|port| midi_out.port_name(port).map(|n| n == name).unwrap_or(false))
+            .ok_or("MIDI output is no longer available")?;
+
+        let connection = midi_out.connect(out_port, "donut-midi-out")?;
+
+        // Replacing the sender ends the thread of the previous connection
+        let (sender, receiver) = channel();
+        std::thread::spawn(move || Self::run(connection, receiver));
+        self.sender = Some(sender);
+
+        Ok(name.to_string())
+    }
+
+    pub fn send_at(&mut self, message: &MidiMessage, due: Instant) {
+        let Some(sender) = self.sender.as_ref() else {
+            return;
+        };
+
+        let bytes = message.to_bytes();
+        if !bytes.is_empty() {
+            let _ = sender.send((due, bytes));
+        }
+    }
+
+    fn run(mut connection: MidiOutputConnection, receiver: Receiver<(Instant, Vec<u8>)>) {
+        // Ordered by when they are due, messages due at the same time keep their order
+        let mut pending: Vec<(Instant, Vec<u8>)> = vec![];
+
+        loop {
+            let received = match pending.first() {
+                Some((due, _)) => receiver.recv_timeout(due.saturating_duration_since(Instant::now())),
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            };
+
+            match received {
+                Ok((due, bytes)) => {
+                    let index = pending.partition_point(|(d, _)| *d <= due);
+                    pending.insert(index, (due, bytes));
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => return
+            }
+
+            let now = Instant::now();
+            let due = pending.partition_point(|(d, _)| *d <= now);
+            for (_, bytes) in pending.drain(..due) {
+                if let Err(e) = connection.send(&bytes) {
+                    println!("Failed to send MIDI message {:?}: {}", bytes, e);
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -140,4 +489,81 @@ mod tests {
     fn test_midi_input() {
         // MidiInputHandler::run().unwrap();
     }
+
+    #[test]
+    fn test_event_scheduler() {
+        // One sample per millisecond keeps the arithmetic readable
+        let mut scheduler = MidiEventScheduler::new(1000.0, 10);
+
+        scheduler.schedule(MidiInputCallbackInfo { timestamp: 0, message: MidiMessage::NoteOn(0, 60, 100) }, 0);
+        scheduler.schedule(MidiInputCallbackInfo { timestamp: 5000, message: MidiMessage::NoteOff(0, 60, 0) }, 0);
+
+        assert!(scheduler.take_block(0, 10).is_empty());
+        assert_eq!(scheduler.take_block(10, 10), vec![
+            MidiEvent { offset: 0, message: MidiMessage::NoteOn(0, 60, 100) },
+            MidiEvent { offset: 5, message: MidiMessage::NoteOff(0, 60, 0) },
+        ]);
+    }
+
+    #[test]
+    fn test_midi_parser() {
+        let mut parser = MidiParser::new();
+
+        // Channel nibble, running status and velocity 0 note offs
+        assert_eq!(parser.parse(&[0x93, 60, 100, 62, 90, 60, 0]), vec![
+            MidiMessage::NoteOn(3, 60, 100),
+            MidiMessage::NoteOn(3, 62, 90),
+            MidiMessage::NoteOff(3, 60, 0),
+        ]);
+
+        // 14-bit pitch bend, split across two packets
+        assert_eq!(parser.parse(&[0xE0, 0x00]), vec![]);
+        assert_eq!(parser.parse(&[0x40]), vec![MidiMessage::PitchBend(0, 0)]);
+        assert_eq!(MidiMessage::from(&[0xE1, 0x7F, 0x7F]), MidiMessage::PitchBend(1, 8191));
+        assert_eq!(MidiMessage::from(&[0xE1, 0x00, 0x00]), MidiMessage::PitchBend(1, -8192));
+
+        assert_eq!(parser.parse(&[0xB0, 1, 64, 74, 10]), vec![
+            MidiMessage::ModWheel(0, 64),
+            MidiMessage::MidiCC(0, 74, 10),
+        ]);
+        assert_eq!(parser.parse(&[0xC5, 7, 0xD5, 80, 0xA5, 60, 20]), vec![
+            MidiMessage::ProgramChange(5, 7),
+            MidiMessage::ChannelPressure(5, 80),
+            MidiMessage::PolyPressure(5, 60, 20),
+        ]);
+
+        // Realtime bytes interleaved with a note on don't break it up
+        assert_eq!(parser.parse(&[0x90, 60, 0xF8, 100, 0xFA]), vec![
+            MidiMessage::Clock,
+            MidiMessage::NoteOn(0, 60, 100),
+            MidiMessage::Start,
+        ]);
+
+        assert_eq!(parser.parse(&[0xF2, 0x10, 0x01]), vec![MidiMessage::SongPosition(0x90)]);
+        // Song position cancels running status
+        assert_eq!(parser.parse(&[0x10, 0x10]), vec![]);
+
+        assert_eq!(parser.parse(&[0xF0, 0x7E, 0x7F]), vec![]);
+        assert_eq!(parser.parse(&[0x06, 0x01, 0xF7]), vec![MidiMessage::SysEx(vec![0x7E, 0x7F, 0x06, 0x01])]);
+    }
+
+    #[test]
+    fn test_midi_to_bytes() {
+        let messages = vec![
+            MidiMessage::NoteOn(9, 36, 127),
+            MidiMessage::NoteOff(9, 36, 0),
+            MidiMessage::MidiCC(2, 74, 64),
+            MidiMessage::ProgramChange(0, 12),
+            MidiMessage::PitchBend(0, -8192),
+            MidiMessage::PitchBend(0, 8191),
+            MidiMessage::SongPosition(300),
+            MidiMessage::Clock,
+            MidiMessage::Start,
+            MidiMessage::SysEx(vec![0x7E, 0x01]),
+        ];
+
+        for message in messages {
+            assert_eq!(MidiMessage::from(&message.to_bytes()), message);
+        }
+    }
 }
\ No newline at end of file
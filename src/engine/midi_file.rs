@@ -0,0 +1,385 @@
+// MidiFile
+// Reads Standard MIDI Files (type 0 and 1) into absolute-tick event lists, and writes them back
+
+use std::path::Path;
+use anyhow::{anyhow, bail, Context, Result};
+use crate::engine::midi::MidiMessage;
+
+// Microseconds per quarter note, 120 bpm
+pub const DEFAULT_TEMPO: u32 = 500_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MidiFileEvent {
+    Message(MidiMessage),
+    Tempo(u32),
+    TimeSignature(u8, u8),
+    EndOfTrack
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackEvent {
+    pub tick: u64,
+    pub event: MidiFileEvent
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MidiFileTrack {
+    pub events: Vec<TrackEvent>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiFile {
+    pub format: u16,
+    pub ppq: u16,
+    pub tracks: Vec<MidiFileTrack>
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        let byte = *self.data.get(self.position).context("Unexpected end of MIDI data")?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        let end = self.position + length;
+        if end > self.data.len() {
+            bail!("Unexpected end of MIDI data");
+        }
+
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn vlq(&mut self) -> Result<u32> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(anyhow!("Variable length quantity is too long"))
+    }
+}
+
+impl MidiFile {
+    pub fn load<T: AsRef<Path>>(path: T) -> Result<Self> {
+        let data = std::fs::read(path).context("Failed to read MIDI file")?;
+        Self::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(data);
+
+        if reader.bytes(4)? != b"MThd" {
+            bail!("Not a Standard MIDI File");
+        }
+
+        let header_length = reader.u32()? as usize;
+        let format = reader.u16()?;
+        let n_tracks = reader.u16()?;
+        let division = reader.u16()?;
+        reader.bytes(header_length.saturating_sub(6))?;
+
+        if format > 1 {
+            bail!("Unsupported MIDI file format: {}", format);
+        }
+        if division & 0x8000 != 0 {
+            bail!("SMPTE time division is not supported");
+        }
+
+        let mut tracks = vec![];
+        while tracks.len() < n_tracks as usize && !reader.is_empty() {
+            let chunk_type = reader.bytes(4)?;
+            let length = reader.u32()? as usize;
+            let chunk = reader.bytes(length)?;
+
+            // Unknown chunks are allowed by the spec and should be skipped
+            if chunk_type == b"MTrk" {
+                tracks.push(Self::parse_track(chunk)?);
+            }
+        }
+
+        Ok(Self {
+            format,
+            ppq: division,
+            tracks
+        })
+    }
+
+    fn parse_track(data: &[u8]) -> Result<MidiFileTrack> {
+        let mut reader = Reader::new(data);
+        let mut track = MidiFileTrack::default();
+        let mut tick = 0u64;
+        let mut running_status = 0u8;
+
+        while !reader.is_empty() {
+            tick += reader.vlq()? as u64;
+
+            let mut status = reader.u8()?;
+            let mut first_data = None;
+            if status & 0x80 == 0 {
+                if running_status == 0 {
+                    bail!("Data byte without running status");
+                }
+                first_data = Some(status);
+                status = running_status;
+            }
+
+            let event = match status {
+                0xFF => {
+                    let meta_type = reader.u8()?;
+                    let length = reader.vlq()? as usize;
+                    let meta = reader.bytes(length)?;
+
+                    match meta_type {
+                        0x51 if length == 3 => Some(MidiFileEvent::Tempo(u32::from_be_bytes([0, meta[0], meta[1], meta[2]]))),
+                        // The denominator is stored as a power of two, too large a one is skipped
+                        0x58 if length >= 2 => 1u8.checked_shl(meta[1] as u32).map(|denominator| MidiFileEvent::TimeSignature(meta[0], denominator)),
+                        0x2F => Some(MidiFileEvent::EndOfTrack),
+                        _ => None
+                    }
+                },
+                // The length covers the data and the closing F7
+                0xF0 => {
+                    let length = reader.vlq()? as usize;
+                    let data = reader.bytes(length)?;
+                    let data = data.strip_suffix(&[0xF7]).unwrap_or(data);
+                    Some(MidiFileEvent::Message(MidiMessage::SysEx(data.to_vec())))
+                },
+                // Escaped bytes and continued SysEx packets, nothing we can play
+                0xF7 => {
+                    let length = reader.vlq()? as usize;
+                    reader.bytes(length)?;
+                    None
+                },
+                _ => {
+                    running_status = status;
+
+                    let d1 = match first_data {
+                        Some(d) => d,
+                        None => reader.u8()?
+                    };
+                    let message = match status & 0xF0 {
+                        0xC0 | 0xD0 => MidiMessage::from(&[status, d1]),
+                        _ => MidiMessage::from(&[status, d1, reader.u8()?])
+                    };
+
+                    match message {
+                        MidiMessage::Unknown => None,
+                        message => Some(MidiFileEvent::Message(message))
+                    }
+                }
+            };
+
+            if let Some(event) = event {
+                let is_end = event == MidiFileEvent::EndOfTrack;
+                track.events.push(TrackEvent { tick, event });
+
+                if is_end {
+                    break;
+                }
+            }
+        }
+
+        Ok(track)
+    }
+
+    pub fn save<T: AsRef<Path>>(&self, path: T) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context("Failed to create the folder for the MIDI file")?;
+        }
+
+        std::fs::write(path, self.to_bytes()).with_context(|| format!("Failed to write MIDI file {}", path.display()))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = b"MThd".to_vec();
+        data.extend_from_slice(&6u32.to_be_bytes());
+        data.extend_from_slice(&self.format.to_be_bytes());
+        data.extend_from_slice(&(self.tracks.len() as u16).to_be_bytes());
+        data.extend_from_slice(&self.ppq.to_be_bytes());
+
+        for track in self.tracks.iter() {
+            let chunk = Self::track_bytes(track);
+            data.extend_from_slice(b"MTrk");
+            data.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            data.extend_from_slice(&chunk);
+        }
+
+        data
+    }
+
+    // Events are expected in order, an end of track is added when it's missing
+    fn track_bytes(track: &MidiFileTrack) -> Vec<u8> {
+        let mut data = vec![];
+        let mut last_tick = 0u64;
+
+        let end = track.events.last().map_or(0, |e| e.tick);
+        let end_of_track = TrackEvent { tick: end, event: MidiFileEvent::EndOfTrack };
+        let missing_end = track.events.last().map(|e| &e.event) != Some(&MidiFileEvent::EndOfTrack);
+
+        for event in track.events.iter().chain(missing_end.then_some(&end_of_track)) {
+            let bytes = match &event.event {
+                MidiFileEvent::Message(MidiMessage::SysEx(sysex)) => {
+                    let mut bytes = vec![0xF0];
+                    write_vlq(&mut bytes, sysex.len() as u32 + 1);
+                    bytes.extend_from_slice(sysex);
+                    bytes.push(0xF7);
+                    bytes
+                },
+                MidiFileEvent::Message(message) => {
+                    // System common and realtime messages have no place in a file
+                    let bytes = message.to_bytes();
+                    if bytes.first().is_some_and(|status| *status < 0xF0) { bytes } else { vec![] }
+                },
+                MidiFileEvent::Tempo(tempo) => {
+                    let t = tempo.to_be_bytes();
+                    vec![0xFF, 0x51, 0x03, t[1], t[2], t[3]]
+                },
+                MidiFileEvent::TimeSignature(numerator, denominator) => {
+                    vec![0xFF, 0x58, 0x04, *numerator, denominator.max(&1).trailing_zeros() as u8, 24, 8]
+                },
+                MidiFileEvent::EndOfTrack => vec![0xFF, 0x2F, 0x00]
+            };
+
+            if bytes.is_empty() {
+                continue;
+            }
+
+            write_vlq(&mut data, event.tick.saturating_sub(last_tick) as u32);
+            last_tick = last_tick.max(event.tick);
+            data.extend_from_slice(&bytes);
+        }
+
+        data
+    }
+
+    pub fn merged(&self) -> Vec<TrackEvent> {
+        let mut events: Vec<TrackEvent> = self.tracks.iter()
+            .flat_map(|t| t.events.iter().cloned())
+            .collect();
+
+        // Stable sort keeps the per-track order of simultaneous events
+        events.sort_by_key(|e| e.tick);
+        events
+    }
+
+    pub fn timed_messages(&self) -> Vec<(f64, MidiMessage)> {
+        let mut messages = vec![];
+        let mut tempo = DEFAULT_TEMPO;
+        let mut last_tick = 0u64;
+        let mut seconds = 0.0;
+
+        for event in self.merged() {
+            seconds += (event.tick - last_tick) as f64 * tempo as f64 / 1_000_000.0 / self.ppq as f64;
+            last_tick = event.tick;
+
+            match event.event {
+                MidiFileEvent::Tempo(t) => tempo = t,
+                MidiFileEvent::Message(message) => messages.push((seconds, message)),
+                _ => {}
+            }
+        }
+
+        messages
+    }
+}
+
+fn write_vlq(data: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+
+    data.extend(bytes.iter().rev());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_midi_file_roundtrip() {
+        let event = |tick: u64, event: MidiFileEvent| TrackEvent { tick, event };
+        let file = MidiFile {
+            format: 0,
+            ppq: 480,
+            tracks: vec![MidiFileTrack { events: vec![
+                event(0, MidiFileEvent::Tempo(400_000)),
+                event(0, MidiFileEvent::TimeSignature(7, 8)),
+                event(0, MidiFileEvent::Message(MidiMessage::NoteOn(3, 60, 90))),
+                event(200, MidiFileEvent::Message(MidiMessage::PitchBend(3, -2000))),
+                // An MTS single note retuning
+                event(300, MidiFileEvent::Message(MidiMessage::SysEx(vec![0x7F, 0x7F, 0x08, 0x02, 0x00, 0x01, 0x45, 0x40, 0x00, 0x00]))),
+                event(20_000, MidiFileEvent::Message(MidiMessage::NoteOff(3, 60, 0)))
+            ] }]
+        };
+
+        let parsed = MidiFile::parse(&file.to_bytes()).unwrap();
+        assert_eq!(parsed.ppq, 480);
+
+        let mut expected = file.tracks[0].events.clone();
+        expected.push(event(20_000, MidiFileEvent::EndOfTrack));
+        assert_eq!(parsed.tracks[0].events, expected);
+        assert_eq!(parsed.timed_messages()[3].0, 20_000.0 * 0.4 / 480.0);
+    }
+
+    #[test]
+    fn test_midi_file_parser() {
+        let mut data = b"MThd".to_vec();
+        data.extend_from_slice(&[0, 0, 0, 6, 0, 0, 0, 1, 0, 96]);
+
+        let track = [
+            0x00, 0xFF, 0x58, 0x04, 6, 3, 24, 8,
+            // A denominator of 2^8 doesn't fit
+            0x00, 0xFF, 0x58, 0x04, 3, 8, 24, 8,
+            0x00, 0x90, 60, 100,
+            // Running status, a note on without velocity is a note off
+            0x81, 0x40, 60, 0,
+            0x00, 0xFF, 0x2F, 0x00
+        ];
+        data.extend_from_slice(b"MTrk");
+        data.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        data.extend_from_slice(&track);
+
+        let file = MidiFile::parse(&data).unwrap();
+        let events: Vec<(u64, MidiFileEvent)> = file.tracks[0].events.iter().map(|e| (e.tick, e.event.clone())).collect();
+        assert_eq!(events, vec![
+            (0, MidiFileEvent::TimeSignature(6, 8)),
+            (0, MidiFileEvent::Message(MidiMessage::NoteOn(0, 60, 100))),
+            (192, MidiFileEvent::Message(MidiMessage::NoteOff(0, 60, 0))),
+            (192, MidiFileEvent::EndOfTrack)
+        ]);
+    }
+}
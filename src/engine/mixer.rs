@@ -0,0 +1,165 @@
+// Mixer
+// Parts are synths bound to a MIDI channel, the mixer sums them into a stereo pair
+
+use std::f32::consts::FRAC_PI_4;
+use serde::{Deserialize, Serialize};
+use crate::dsp::buffer::Buffer;
+use crate::engine::midi::{MidiEvent, MidiMessage};
+use crate::engine::synthesis::Synth;
+
+pub const MAX_PARTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PartSettings {
+    // MIDI channel the part listens to, None listens to every channel
+    pub channel: Option<u8>,
+    pub level: f32,
+    // -1 is hard left, 1 is hard right
+    pub pan: f32
+}
+
+impl PartSettings {
+    pub fn new(channel: Option<u8>) -> Self {
+        Self {
+            channel,
+            level: 1.0,
+            pan: 0.0
+        }
+    }
+
+    // Equal power pan law
+    pub fn gains(&self) -> (f32, f32) {
+        let angle = (self.pan.clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+        (self.level * angle.cos(), self.level * angle.sin())
+    }
+}
+
+pub struct Part {
+    pub synth: Synth,
+    settings: PartSettings,
+    events: Vec<MidiEvent>
+}
+
+impl Part {
+    pub fn new(sample_rate: f32, block_size: usize, channel: Option<u8>) -> Self {
+        Self {
+            synth: Synth::new(sample_rate, block_size),
+            settings: PartSettings::new(channel),
+            events: vec![]
+        }
+    }
+
+    // Messages without a channel reach every part. A part in MPE mode also takes the rest of its zone.
+    pub fn accepts(&self, message: &MidiMessage) -> bool {
+        let (Some(channel), Some(own)) = (message.channel(), self.settings.channel) else {
+            return true;
+        };
+
+        match self.synth.get_mpe_zone() {
+            Some(zone) if zone.is_master(own) || zone.is_member(own) => zone.is_master(channel) || zone.is_member(channel),
+            _ => channel == own
+        }
+    }
+
+    pub fn handle_message(&mut self, message: &MidiMessage) {
+        if self.accepts(message) {
+            self.synth.handle_message(message);
+        }
+    }
+
+    pub fn process_events(&mut self, events: &[MidiEvent]) -> &Buffer {
+        let mut accepted = std::mem::take(&mut self.events);
+        accepted.clear();
+        accepted.extend(events.iter().filter(|e| self.accepts(&e.message)).cloned());
+
+        self.events = accepted;
+        self.synth.process_events(&self.events)
+    }
+
+    pub fn get_settings(&self) -> PartSettings {
+        self.settings
+    }
+
+    pub fn set_channel(&mut self, channel: Option<u8>) {
+        if channel != self.settings.channel {
+            self.synth.all_notes_off();
+        }
+        self.settings.channel = channel.map(|c| c.min(15));
+    }
+
+    pub fn set_level(&mut self, level: f32) {
+        self.settings.level = level.clamp(0.0, 1.0);
+    }
+
+    pub fn set_pan(&mut self, pan: f32) {
+        self.settings.pan = pan.clamp(-1.0, 1.0);
+    }
+}
+
+pub struct Mixer {
+    left: Buffer,
+    right: Buffer
+}
+
+impl Mixer {
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            left: Buffer::new(block_size, "Mixer L".to_string()),
+            right: Buffer::new(block_size, "Mixer R".to_string())
+        }
+    }
+
+    // Renders every part for one block and sums them
+    pub fn process(&mut self, parts: &mut [Part], events: &[MidiEvent]) -> (&Buffer, &Buffer) {
+        self.left.wipe();
+        self.right.wipe();
+
+        for part in parts.iter_mut() {
+            let (gain_left, gain_right) = part.get_settings().gains();
+            let output = part.process_events(events);
+
+            for i in 0..self.left.get_size() {
+                self.left[i] += output[i] * gain_left;
+                self.right[i] += output[i] * gain_right;
+            }
+        }
+
+        (&self.left, &self.right)
+    }
+
+    pub fn get_output(&self) -> (&Buffer, &Buffer) {
+        (&self.left, &self.right)
+    }
+
+    pub fn set_block_size(&mut self, block_size: usize) {
+        *self = Self::new(block_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parts() {
+        let mut parts = vec![Part::new(48000.0, 64, Some(1)), Part::new(48000.0, 64, Some(2))];
+        parts[1].set_pan(1.0);
+        let mut mixer = Mixer::new(64);
+
+        // Bass on channel 2, nothing for the pad on channel 3
+        let events = [MidiEvent { offset: 0, message: MidiMessage::NoteOn(1, 40, 100) }];
+        mixer.process(&mut parts, &events);
+        assert!(parts[0].synth.voices.iter().any(|v| v.is_busy()));
+        assert!(!parts[1].synth.voices.iter().any(|v| v.is_busy()));
+
+        // Notes on channel 3 only reach the pad
+        let events = [MidiEvent { offset: 0, message: MidiMessage::NoteOn(2, 64, 100) }];
+        let (left, right) = mixer.process(&mut parts, &events);
+        assert_eq!(left.get_size(), 64);
+        assert!(right.as_slice().iter().any(|s| *s != 0.0));
+        assert!(parts[1].synth.voices.iter().any(|v| v.is_busy()));
+
+        let (l, r) = PartSettings { channel: None, level: 1.0, pan: 1.0 }.gains();
+        assert!(l.abs() < 1e-6 && (r - 1.0).abs() < 1e-6);
+    }
+}
@@ -1,8 +1,13 @@
 pub mod audio;
 pub mod midi;
+pub mod midi_file;
 pub mod synthesis;
 mod voice;
-mod note_handler;
+pub mod note_handler;
 pub mod engine;
 pub mod clock;
-
+pub mod offline;
+pub mod osc;
+pub mod mpe;
+pub mod mixer;
+pub mod recorder;
\ No newline at end of file
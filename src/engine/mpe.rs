@@ -0,0 +1,168 @@
+// MPE
+// Per-channel controller state, registered parameter numbers and MPE zones
+
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_BEND_RANGE: f32 = 2.0;
+// Default pitch bend range of the member channels in an MPE zone
+pub const MPE_MEMBER_BEND_RANGE: f32 = 48.0;
+
+const RPN_BEND_RANGE: (u8, u8) = (0, 0);
+const RPN_MPE_CONFIGURATION: (u8, u8) = (0, 6);
+
+// An MPE zone: a master channel for zone-wide messages and the member channels notes are spread over.
+// The lower zone is mastered by channel 1, the upper zone by channel 16.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MpeZone {
+    pub master: u8,
+    pub members: u8
+}
+
+impl MpeZone {
+    pub fn lower(members: u8) -> Self {
+        Self { master: 0, members: members.min(15) }
+    }
+
+    pub fn upper(members: u8) -> Self {
+        Self { master: 15, members: members.min(15) }
+    }
+
+    pub fn is_member(&self, channel: u8) -> bool {
+        match self.master {
+            0 => channel >= 1 && channel <= self.members,
+            _ => channel < self.master && channel >= self.master - self.members
+        }
+    }
+
+    pub fn is_master(&self, channel: u8) -> bool {
+        channel == self.master
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RpnChange {
+    BendRange(f32),
+    // Number of member channels, 0 turns the zone off
+    MpeConfiguration(u8)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelState {
+    // -1..1
+    pub bend: f32,
+    // Semitones at full bend
+    pub bend_range: f32,
+    pub pressure: f32,
+    pub timbre: f32,
+    pub mod_wheel: f32,
+    // Pedals pressed on a member channel, they only hold the notes on that channel
+    pub sustain: bool,
+    pub sostenuto: bool,
+    pub soft_pedal: bool,
+
+    rpn: Option<(u8, u8)>
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        Self {
+            bend: 0.0,
+            bend_range: DEFAULT_BEND_RANGE,
+            pressure: 0.0,
+            timbre: 0.0,
+            mod_wheel: 0.0,
+            sustain: false,
+            sostenuto: false,
+            soft_pedal: false,
+            rpn: None
+        }
+    }
+}
+
+impl ChannelState {
+    pub fn set_bend(&mut self, value: i16) {
+        self.bend = (value as f32 / 8192.0).clamp(-1.0, 1.0);
+    }
+
+    pub fn bend_semitones(&self) -> f32 {
+        self.bend * self.bend_range
+    }
+
+    // Tracks RPN selection and data entry. Returns the change once a parameter is written,
+    // None for any other controller.
+    pub fn handle_rpn(&mut self, cc: u8, value: u8) -> Option<RpnChange> {
+        match cc {
+            101 => {
+                self.rpn = Some((value, self.rpn.map(|r| r.1).unwrap_or(127)));
+                None
+            },
+            100 => {
+                self.rpn = Some((self.rpn.map(|r| r.0).unwrap_or(127), value));
+                None
+            },
+            // Data entry MSB
+            6 => match self.rpn? {
+                RPN_BEND_RANGE => {
+                    self.bend_range = value as f32;
+                    Some(RpnChange::BendRange(self.bend_range))
+                },
+                RPN_MPE_CONFIGURATION => Some(RpnChange::MpeConfiguration(value)),
+                _ => None
+            },
+            // Data entry LSB, cents for the bend range
+            38 => match self.rpn? {
+                RPN_BEND_RANGE => {
+                    self.bend_range = self.bend_range.floor() + value.min(99) as f32 / 100.0;
+                    Some(RpnChange::BendRange(self.bend_range))
+                },
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    // Is this CC part of an RPN sequence
+    pub fn is_rpn(cc: u8) -> bool {
+        matches!(cc, 101 | 100 | 6 | 38)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mpe_configuration() {
+        let mut master = ChannelState::default();
+
+        // MPE Configuration Message for a lower zone with 7 member channels
+        assert_eq!(master.handle_rpn(101, 0), None);
+        assert_eq!(master.handle_rpn(100, 6), None);
+        assert_eq!(master.handle_rpn(6, 7), Some(RpnChange::MpeConfiguration(7)));
+
+        let zone = MpeZone::lower(7);
+        assert!(zone.is_master(0));
+        assert!(!zone.is_member(0));
+        assert!(zone.is_member(1) && zone.is_member(7));
+        assert!(!zone.is_member(8));
+
+        let zone = MpeZone::upper(3);
+        assert!(zone.is_member(12) && zone.is_member(14));
+        assert!(!zone.is_member(11) && !zone.is_member(15));
+
+        // Bend range of 12 semitones and 50 cents
+        let mut member = ChannelState::default();
+        member.handle_rpn(101, 0);
+        member.handle_rpn(100, 0);
+        assert_eq!(member.handle_rpn(6, 12), Some(RpnChange::BendRange(12.0)));
+        assert_eq!(member.handle_rpn(38, 50), Some(RpnChange::BendRange(12.5)));
+
+        member.set_bend(-8192);
+        assert_eq!(member.bend_semitones(), -12.5);
+
+        // Null RPN, data entry does nothing
+        member.handle_rpn(101, 127);
+        member.handle_rpn(100, 127);
+        assert_eq!(member.handle_rpn(6, 2), None);
+    }
+}
@@ -1,12 +1,167 @@
+// NoteHandler
+// Splits the keyboard into a lower and an upper half, each with its own voice pool and patch,
+// or layers the two patches on top of each other
+
+use serde::{Deserialize, Serialize};
+use crate::system::parameter::ParameterID;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum KeyboardMode {
+    // Every voice plays the lower half's patch
+    Whole,
+    // Notes below the split point play the lower half, the rest the upper half
+    Split,
+    // Every note plays both halves
+    Layer
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Half {
+    Lower,
+    Upper
+}
+
+impl KeyboardMode {
+    pub fn all() -> &'static [KeyboardMode] {
+        &[KeyboardMode::Whole, KeyboardMode::Split, KeyboardMode::Layer]
+    }
+}
+
 pub struct KeyboardHalf {
     voices: Vec<usize>,
     rr_index: usize,
-    name: String
+    // Normalized value of every parameter in this half's patch
+    parameters: Vec<(ParameterID, f32)>
+}
+
+impl KeyboardHalf {
+    fn new(parameters: Vec<(ParameterID, f32)>) -> Self {
+        Self {
+            voices: vec![],
+            rr_index: 0,
+            parameters
+        }
+    }
+
+    pub fn get_voices(&self) -> &[usize] {
+        &self.voices
+    }
+
+    // The pool in round-robin order, starting at the voice after the last one handed out
+    pub fn candidates(&self) -> impl Iterator<Item = usize> + '_ {
+        let len = self.voices.len();
+        (0..len).map(move |i| self.voices[(self.rr_index + i) % len])
+    }
+
+    pub fn used(&mut self, voice: usize) {
+        if let Some(position) = self.voices.iter().position(|v| *v == voice) {
+            self.rr_index = (position + 1) % self.voices.len();
+        }
+    }
+
+    pub fn get_parameters(&self) -> &[(ParameterID, f32)] {
+        &self.parameters
+    }
+
+    pub fn set_parameter(&mut self, id: ParameterID, value: f32) {
+        match self.parameters.iter_mut().find(|p| p.0 == id) {
+            Some(parameter) => parameter.1 = value,
+            None => self.parameters.push((id, value))
+        }
+    }
 }
 
 pub struct NoteHandler {
-    voices: Vec<usize>,
-    used_voices: Vec<usize>,
+    mode: KeyboardMode,
+    // Lowest note of the upper half
+    split_point: u8,
     half_upper: KeyboardHalf,
     half_lower: KeyboardHalf
-}
\ No newline at end of file
+}
+
+impl NoteHandler {
+    // Both halves start out with the same patch
+    pub fn new(polyphony: usize, parameters: Vec<(ParameterID, f32)>) -> Self {
+        let mut handler = Self {
+            mode: KeyboardMode::Whole,
+            split_point: 60,
+            half_upper: KeyboardHalf::new(parameters.clone()),
+            half_lower: KeyboardHalf::new(parameters)
+        };
+        handler.assign_voices(polyphony);
+
+        handler
+    }
+
+    pub fn set_mode(&mut self, mode: KeyboardMode, split_point: u8, polyphony: usize) {
+        self.mode = mode;
+        self.split_point = split_point.min(127);
+        self.assign_voices(polyphony);
+    }
+
+    // Whole gives every voice to the lower half, otherwise the voices are shared out evenly
+    pub fn assign_voices(&mut self, polyphony: usize) {
+        let lower = match self.mode {
+            KeyboardMode::Whole => polyphony,
+            _ => (polyphony / 2).max(1)
+        };
+
+        self.half_lower.voices = (0..lower).collect();
+        self.half_upper.voices = (lower..polyphony).collect();
+        self.half_lower.rr_index = 0;
+        self.half_upper.rr_index = 0;
+    }
+
+    // Halves that play this note
+    pub fn halves_for(&self, midi_note: u8) -> &'static [Half] {
+        match self.mode {
+            KeyboardMode::Whole => &[Half::Lower],
+            KeyboardMode::Split if midi_note < self.split_point => &[Half::Lower],
+            KeyboardMode::Split => &[Half::Upper],
+            KeyboardMode::Layer => &[Half::Lower, Half::Upper]
+        }
+    }
+
+    pub fn half(&self, half: Half) -> &KeyboardHalf {
+        match half {
+            Half::Lower => &self.half_lower,
+            Half::Upper => &self.half_upper
+        }
+    }
+
+    pub fn half_mut(&mut self, half: Half) -> &mut KeyboardHalf {
+        match half {
+            Half::Lower => &mut self.half_lower,
+            Half::Upper => &mut self.half_upper
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_note_handler() {
+        let mut handler = NoteHandler::new(12, vec![(ParameterID::WT1Shape, 0.0)]);
+        assert_eq!(handler.half(Half::Lower).get_voices().len(), 12);
+        assert!(handler.half(Half::Upper).get_voices().is_empty());
+        assert_eq!(handler.halves_for(90), &[Half::Lower]);
+
+        handler.set_mode(KeyboardMode::Split, 55, 12);
+        assert_eq!(handler.half(Half::Lower).get_voices(), &[0, 1, 2, 3, 4, 5]);
+        assert_eq!(handler.half(Half::Upper).get_voices(), &[6, 7, 8, 9, 10, 11]);
+        assert_eq!(handler.halves_for(54), &[Half::Lower]);
+        assert_eq!(handler.halves_for(55), &[Half::Upper]);
+
+        let upper = handler.half_mut(Half::Upper);
+        upper.used(7);
+        assert_eq!(upper.candidates().take(3).collect::<Vec<_>>(), vec![8, 9, 10]);
+        upper.set_parameter(ParameterID::WT1Shape, 1.0);
+        assert_eq!(handler.half(Half::Upper).get_parameters(), &[(ParameterID::WT1Shape, 1.0)]);
+        assert_eq!(handler.half(Half::Lower).get_parameters(), &[(ParameterID::WT1Shape, 0.0)]);
+
+        handler.set_mode(KeyboardMode::Layer, 55, 12);
+        assert_eq!(handler.halves_for(30), &[Half::Lower, Half::Upper]);
+    }
+}
@@ -0,0 +1,176 @@
+// OfflineRenderer
+// Drives the synth without any audio or MIDI devices and renders to a WAV file
+
+use std::path::Path;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::engine::midi::{MidiEvent, MidiMessage};
+use crate::engine::midi_file::MidiFile;
+use crate::engine::synthesis::Synth;
+use crate::system::parameter::ParameterID;
+
+pub const DEFAULT_SAMPLE_RATE: f32 = 48_000.0;
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+pub const DEFAULT_TAIL: f32 = 2.0;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RenderEvent {
+    // Time in seconds from the start of the render
+    pub time: f32,
+    pub message: MidiMessage
+}
+
+pub struct OfflineRenderer {
+    synth: Synth,
+    sample_rate: f32,
+    block_size: usize
+}
+
+impl OfflineRenderer {
+    pub fn new(sample_rate: f32, block_size: usize) -> Self {
+        Self {
+            synth: Synth::new(sample_rate, block_size),
+            sample_rate,
+            block_size
+        }
+    }
+
+    // Applies a parameter state in the same shape the GUI keeps it in:
+    // { "parameters": { "KSCutoff": 0.5, ... } }, with normalized values
+    pub fn apply_state(&mut self, state: &serde_json::Value) -> Result<()> {
+        let Some(parameters) = state.get("parameters").and_then(|p| p.as_object()) else {
+            return Ok(());
+        };
+
+        for (key, value) in parameters {
+            let id: ParameterID = serde_json::from_value(serde_json::Value::String(key.clone()))
+                .with_context(|| format!("Unknown parameter: {}", key))?;
+            let value = value.as_f64().with_context(|| format!("Parameter {} is not a number", key))?;
+
+            self.synth.set_parameter(id, value as f32);
+        }
+
+        Ok(())
+    }
+
+    pub fn render(&mut self, events: &[RenderEvent], tail: f32) -> Vec<f32> {
+        let mut events = events.to_vec();
+        events.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+        let end = events.last().map(|e| e.time).unwrap_or(0.0).max(0.0) + tail;
+        let length = (end * self.sample_rate).ceil() as usize;
+
+        let mut output = Vec::with_capacity(length + self.block_size);
+        let mut next_event = 0;
+
+        let mut block = vec![];
+
+        while output.len() < length {
+            let block_start = output.len();
+            let block_end = block_start + self.block_size;
+
+            block.clear();
+            while next_event < events.len() && ((events[next_event].time * self.sample_rate) as usize) < block_end {
+                let position = (events[next_event].time.max(0.0) * self.sample_rate) as usize;
+                block.push(MidiEvent {
+                    offset: position.saturating_sub(block_start),
+                    message: events[next_event].message.clone()
+                });
+                next_event += 1;
+            }
+
+            output.extend_from_slice(self.synth.process_events(&block).as_slice());
+        }
+
+        output.truncate(length);
+        output
+    }
+
+    pub fn render_to_file<T: AsRef<Path>>(&mut self, events: &[RenderEvent], tail: f32, path: T) -> Result<()> {
+        let output = self.render(events, tail);
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: self.sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+
+        let mut writer = hound::WavWriter::create(path, spec).context("Failed to create WAV file")?;
+        for sample in output {
+            writer.write_sample(sample)?;
+        }
+        writer.finalize().context("Failed to finalize WAV file")?;
+
+        Ok(())
+    }
+}
+
+// Loads a render input, either a JSON list of RenderEvents or a Standard MIDI File
+pub fn load_events<T: AsRef<Path>>(path: T) -> Result<Vec<RenderEvent>> {
+    let path = path.as_ref();
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+
+    match extension.as_str() {
+        "mid" | "midi" => {
+            let file = MidiFile::load(path)?;
+            Ok(file.timed_messages().into_iter()
+                .map(|(time, message)| RenderEvent { time: time as f32, message })
+                .collect())
+        },
+        "json" => {
+            let file = std::fs::File::open(path).context("Failed to open event list")?;
+            serde_json::from_reader(file).context("Failed to parse event list")
+        },
+        _ => bail!("Unsupported event file: {}", path.display())
+    }
+}
+
+// donut render <events.json|file.mid> <output.wav> [state.json]
+pub fn run_cli(args: &[String]) -> Result<()> {
+    if args.len() < 2 {
+        bail!("Usage: donut render <events.json|file.mid> <output.wav> [state.json]");
+    }
+
+    let events = load_events(&args[0])?;
+    let mut renderer = OfflineRenderer::new(DEFAULT_SAMPLE_RATE, DEFAULT_BLOCK_SIZE);
+
+    if let Some(state_path) = args.get(2) {
+        let file = std::fs::File::open(state_path).context("Failed to open parameter state")?;
+        let state: serde_json::Value = serde_json::from_reader(file).context("Failed to parse parameter state")?;
+        renderer.apply_state(&state)?;
+    }
+
+    let start = std::time::Instant::now();
+    renderer.render_to_file(&events, DEFAULT_TAIL, &args[1])?;
+    println!("Rendered {} events to {} in {:?}", events.len(), args[1], start.elapsed());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offline_render() {
+        let events = vec![
+            RenderEvent { time: 0.0, message: MidiMessage::NoteOn(0, 60, 100) },
+            RenderEvent { time: 0.25, message: MidiMessage::NoteOff(0, 60, 0) },
+        ];
+
+        let path = std::env::temp_dir().join("donut_test_offline_render.wav");
+        let mut renderer = OfflineRenderer::new(DEFAULT_SAMPLE_RATE, DEFAULT_BLOCK_SIZE);
+        renderer.render_to_file(&events, 0.25, &path).unwrap();
+
+        let reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().sample_rate, DEFAULT_SAMPLE_RATE as u32);
+        assert_eq!(reader.len() as usize, (0.5 * DEFAULT_SAMPLE_RATE) as usize);
+
+        let samples: Vec<f32> = reader.into_samples::<f32>().map(|s| s.unwrap()).collect();
+        assert!(samples.iter().any(|s| s.abs() > 0.0));
+        assert!(samples.iter().all(|s| s.is_finite()));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
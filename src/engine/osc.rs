@@ -0,0 +1,312 @@
+// OscServer
+// Receives OSC over UDP and turns it into engine control packets. Clients that register
+// get parameter, transport and meter feedback sent back to them.
+
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use rosc::{OscMessage, OscPacket, OscType};
+use crate::engine::audio::AudioEngineControlPacket;
+use crate::engine::midi::MidiMessage;
+use crate::engine::note_handler::Half;
+use crate::system::parameter::ParameterID;
+
+pub const OSC_PORT: u16 = 9000;
+// Set to e.g. 127.0.0.1:9000 to only listen on this machine
+pub const OSC_ADDRESS_VAR: &str = "DONUT_OSC_ADDRESS";
+
+// Every interface on the OSC port, unless the environment says otherwise
+pub fn osc_address() -> String {
+    std::env::var(OSC_ADDRESS_VAR).unwrap_or_else(|_| format!("0.0.0.0:{}", OSC_PORT))
+}
+
+pub struct OscServer {
+    socket: UdpSocket,
+    clients: Vec<SocketAddr>,
+    buffer: Vec<u8>
+}
+
+fn as_f32(arg: &OscType) -> Option<f32> {
+    match *arg {
+        OscType::Float(v) => Some(v),
+        OscType::Double(v) => Some(v as f32),
+        OscType::Int(v) => Some(v as f32),
+        OscType::Long(v) => Some(v as f32),
+        OscType::Bool(v) => Some(if v { 1.0 } else { 0.0 }),
+        _ => None
+    }
+}
+
+fn as_u8(arg: &OscType) -> Option<u8> {
+    as_f32(arg).map(|v| v.clamp(0.0, 127.0) as u8)
+}
+
+fn as_half(name: &str) -> Option<Half> {
+    match name {
+        "lower" => Some(Half::Lower),
+        "upper" => Some(Half::Upper),
+        _ => None
+    }
+}
+
+fn as_parameter(name: &str) -> Option<ParameterID> {
+    serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+}
+
+impl OscServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            clients: vec![],
+            buffer: vec![0; rosc::decoder::MTU]
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // Reads everything that arrived since the last call, never blocks
+    pub fn poll(&mut self) -> Vec<AudioEngineControlPacket> {
+        let mut packets = vec![];
+
+        loop {
+            let (size, from) = match self.socket.recv_from(&mut self.buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("OSC receive failed: {}", e);
+                    break;
+                }
+            };
+
+            match rosc::decoder::decode_udp(&self.buffer[..size]) {
+                Ok((_, packet)) => self.handle_packet(packet, from, &mut packets),
+                Err(e) => println!("Invalid OSC packet from {}: {:?}", from, e)
+            }
+        }
+
+        packets
+    }
+
+    fn handle_packet(&mut self, packet: OscPacket, from: SocketAddr, packets: &mut Vec<AudioEngineControlPacket>) {
+        match packet {
+            OscPacket::Message(message) => {
+                if let Some(packet) = self.handle_message(&message, from) {
+                    packets.push(packet);
+                }
+            },
+            // Bundles are applied right away, time tags are ignored
+            OscPacket::Bundle(bundle) => {
+                for packet in bundle.content {
+                    self.handle_packet(packet, from, packets);
+                }
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: &OscMessage, from: SocketAddr) -> Option<AudioEngineControlPacket> {
+        let path: Vec<&str> = message.addr.trim_start_matches('/').split('/').collect();
+        let args = &message.args;
+
+        match path.as_slice() {
+            ["donut", "register"] => {
+                let client = Self::client(from, args);
+                if !self.clients.contains(&client) {
+                    self.clients.push(client);
+                }
+                None
+            },
+            ["donut", "unregister"] => {
+                let client = Self::client(from, args);
+                self.clients.retain(|c| *c != client);
+                None
+            },
+            ["donut", "param", name] => {
+                let id = as_parameter(name)?;
+                let value = args.first().and_then(as_f32)?;
+
+                Some(AudioEngineControlPacket::SetParameter(id, value.clamp(0.0, 1.0)))
+            },
+            // Patch of one keyboard half, /donut/lower/param/<ID> or /donut/upper/param/<ID>
+            ["donut", half, "param", name] if as_half(half).is_some() => {
+                let id = as_parameter(name)?;
+                let value = args.first().and_then(as_f32)?;
+
+                Some(AudioEngineControlPacket::SetHalfParameter(as_half(half)?, id, value.clamp(0.0, 1.0)))
+            },
+            ["donut", "note"] => {
+                let note = args.first().and_then(as_u8)?;
+                let velocity = args.get(1).and_then(as_u8).unwrap_or(100);
+                let channel = args.get(2).and_then(as_u8).unwrap_or(0).min(15);
+
+                let message = match velocity {
+                    0 => MidiMessage::NoteOff(channel, note, 0),
+                    _ => MidiMessage::NoteOn(channel, note, velocity)
+                };
+                Some(AudioEngineControlPacket::Midi(message))
+            },
+            ["donut", "cc"] => {
+                let cc = args.first().and_then(as_u8)?;
+                let value = args.get(1).and_then(as_u8)?;
+                let channel = args.get(2).and_then(as_u8).unwrap_or(0).min(15);
+
+                Some(AudioEngineControlPacket::Midi(MidiMessage::MidiCC(channel, cc, value)))
+            },
+            ["donut", "transport", "play"] => {
+                // Without an argument this acts like the play button, buttons on a touch surface send 1 and 0
+                match args.first().and_then(as_f32) {
+                    None => Some(AudioEngineControlPacket::TogglePlayback),
+                    Some(v) if v > 0.0 => Some(AudioEngineControlPacket::StartPlayback),
+                    Some(_) => Some(AudioEngineControlPacket::PausePlayback)
+                }
+            },
+            ["donut", "transport", "pause"] => Some(AudioEngineControlPacket::PausePlayback),
+            ["donut", "transport", "stop"] => Some(AudioEngineControlPacket::StopPlayback),
+            ["donut", "transport", "reset"] => Some(AudioEngineControlPacket::ResetPlayback),
+            ["donut", "preset", "load"] => {
+                let Some(OscType::String(path)) = args.first() else {
+                    return None;
+                };
+                let half = match args.get(1) {
+                    Some(OscType::String(half)) => as_half(half),
+                    _ => None
+                };
+
+                Some(AudioEngineControlPacket::LoadPreset(path.clone(), half))
+            },
+            _ => {
+                println!("Unhandled OSC message: {} {:?}", message.addr, message.args);
+                None
+            }
+        }
+    }
+
+    // Clients may ask for feedback on a different port than they send from
+    fn client(from: SocketAddr, args: &[OscType]) -> SocketAddr {
+        let mut client = from;
+        if let Some(port) = args.first().and_then(as_f32) {
+            client.set_port(port as u16);
+        }
+        client
+    }
+
+    pub fn has_clients(&self) -> bool {
+        !self.clients.is_empty()
+    }
+
+    pub fn send(&mut self, addr: &str, args: Vec<OscType>) {
+        if self.clients.is_empty() {
+            return;
+        }
+
+        let packet = OscPacket::Message(OscMessage { addr: addr.to_string(), args });
+        let Ok(bytes) = rosc::encoder::encode(&packet) else {
+            return;
+        };
+
+        for client in self.clients.iter() {
+            if let Err(e) = self.socket.send_to(&bytes, client) {
+                println!("Failed to send OSC to {}: {}", client, e);
+            }
+        }
+    }
+
+    pub fn send_parameter(&mut self, id: ParameterID, value: f32) {
+        self.send(&format!("/donut/param/{:?}", id), vec![OscType::Float(value)]);
+    }
+
+    pub fn send_half_parameter(&mut self, half: Half, id: ParameterID, value: f32) {
+        let half = match half {
+            Half::Lower => "lower",
+            Half::Upper => "upper"
+        };
+        self.send(&format!("/donut/{}/param/{:?}", half, id), vec![OscType::Float(value)]);
+    }
+
+    pub fn send_playback(&mut self, is_playing: bool) {
+        self.send("/donut/transport/play", vec![OscType::Int(is_playing as i32)]);
+    }
+
+    pub fn send_meter(&mut self, peak: f32) {
+        self.send("/donut/meter/output", vec![OscType::Float(peak)]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn send(client: &UdpSocket, server: SocketAddr, addr: &str, args: Vec<OscType>) {
+        let packet = OscPacket::Message(OscMessage { addr: addr.to_string(), args });
+        client.send_to(&rosc::encoder::encode(&packet).unwrap(), server).unwrap();
+    }
+
+    #[test]
+    fn test_osc_loopback() {
+        let mut server = OscServer::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        send(&client, server_addr, "/donut/register", vec![]);
+        send(&client, server_addr, "/donut/param/KSCutoff", vec![OscType::Float(0.5)]);
+        send(&client, server_addr, "/donut/note", vec![OscType::Int(60), OscType::Int(100)]);
+        send(&client, server_addr, "/donut/note", vec![OscType::Int(60), OscType::Int(0)]);
+        send(&client, server_addr, "/donut/transport/play", vec![OscType::Int(1)]);
+        send(&client, server_addr, "/donut/transport/stop", vec![]);
+        send(&client, server_addr, "/donut/preset/load", vec![OscType::String("bass.json".to_string())]);
+        send(&client, server_addr, "/donut/preset/load", vec![OscType::String("pad.json".to_string()), OscType::String("upper".to_string())]);
+        send(&client, server_addr, "/donut/lower/param/WT1Shape", vec![OscType::Float(1.0)]);
+        send(&client, server_addr, "/donut/param/NotAParameter", vec![OscType::Float(0.5)]);
+
+        let mut packets = vec![];
+        for _ in 0..100 {
+            packets.extend(server.poll());
+            if packets.len() >= 8 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        assert_eq!(packets, vec![
+            AudioEngineControlPacket::SetParameter(ParameterID::KSCutoff, 0.5),
+            AudioEngineControlPacket::Midi(MidiMessage::NoteOn(0, 60, 100)),
+            AudioEngineControlPacket::Midi(MidiMessage::NoteOff(0, 60, 0)),
+            AudioEngineControlPacket::StartPlayback,
+            AudioEngineControlPacket::StopPlayback,
+            AudioEngineControlPacket::LoadPreset("bass.json".to_string(), None),
+            AudioEngineControlPacket::LoadPreset("pad.json".to_string(), Some(Half::Upper)),
+            AudioEngineControlPacket::SetHalfParameter(Half::Lower, ParameterID::WT1Shape, 1.0),
+        ]);
+
+        // Feedback goes back to the registered client
+        assert!(server.has_clients());
+        server.send_parameter(ParameterID::KSCutoff, 0.5);
+
+        let mut buffer = [0; rosc::decoder::MTU];
+        let size = client.recv(&mut buffer).unwrap();
+        let (_, packet) = rosc::decoder::decode_udp(&buffer[..size]).unwrap();
+
+        assert_eq!(packet, OscPacket::Message(OscMessage {
+            addr: "/donut/param/KSCutoff".to_string(),
+            args: vec![OscType::Float(0.5)]
+        }));
+
+        // Only the address and port that registered are dropped
+        send(&client, server_addr, "/donut/register", vec![OscType::Int(9)]);
+        send(&client, server_addr, "/donut/unregister", vec![]);
+        for _ in 0..100 {
+            server.poll();
+            if server.clients.iter().all(|c| c.port() == 9) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(server.clients.iter().map(|c| c.port()).collect::<Vec<_>>(), vec![9]);
+    }
+}
@@ -0,0 +1,228 @@
+// Recorder
+// Captures incoming MIDI into takes, which can be saved as MIDI files or turned into sequencer patterns
+
+use std::path::Path;
+use anyhow::Result;
+use crate::engine::clock::PPQ;
+use crate::engine::midi::MidiMessage;
+use crate::engine::midi_file::{MidiFile, MidiFileEvent, MidiFileTrack, TrackEvent};
+use crate::generators::sequencer::{Pattern, Step, MAX_STEPS};
+use crate::generators::Rate;
+
+// Ticks per quarter note in saved takes
+const FILE_PPQ: u16 = 480;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedEvent {
+    // Samples since the take was started
+    pub time: usize,
+    // Clock position when it was running
+    pub position: Option<usize>,
+    pub message: MidiMessage
+}
+
+#[derive(Clone, Debug)]
+pub struct Take {
+    pub events: Vec<RecordedEvent>,
+    pub sample_rate: f32,
+    // Tempo at the start of the take, the file is written at this tempo
+    pub bpm: f32
+}
+
+impl Take {
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    // Ticks per sample at the given resolution
+    fn tick_rate(&self, ppq: usize) -> f64 {
+        self.bpm as f64 / 60.0 * ppq as f64 / self.sample_rate as f64
+    }
+
+    pub fn to_midi_file(&self) -> MidiFile {
+        let rate = self.tick_rate(FILE_PPQ as usize);
+        let mut events = vec![TrackEvent { tick: 0, event: MidiFileEvent::Tempo((60_000_000.0 / self.bpm) as u32) }];
+        events.extend(self.events.iter().map(|e| TrackEvent {
+            tick: (e.time as f64 * rate).round() as u64,
+            event: MidiFileEvent::Message(e.message.clone())
+        }));
+
+        MidiFile {
+            format: 0,
+            ppq: FILE_PPQ,
+            tracks: vec![MidiFileTrack { events }]
+        }
+    }
+
+    pub fn save<T: AsRef<Path>>(&self, path: T) -> Result<()> {
+        self.to_midi_file().save(path)
+    }
+
+    // Notes as start, end, pitch and velocity, in clock ticks
+    fn notes(&self) -> Vec<(usize, usize, u8, u8)> {
+        let rate = self.tick_rate(PPQ);
+        let tick = |time: usize| (time as f64 * rate).round() as usize;
+        let end = self.events.last().map_or(0, |e| tick(e.time));
+
+        let mut notes = vec![];
+        for (i, event) in self.events.iter().enumerate() {
+            let MidiMessage::NoteOn(channel, pitch, velocity) = event.message else {
+                continue;
+            };
+            if velocity == 0 {
+                continue;
+            }
+
+            // Notes still held at the end of the take last until the last event
+            let off = self.events[i + 1..].iter().find(|e| match e.message {
+                MidiMessage::NoteOn(c, p, _) | MidiMessage::NoteOff(c, p, _) => (c, p) == (channel, pitch),
+                _ => false
+            });
+            notes.push((tick(event.time), off.map_or(end, |e| tick(e.time)), pitch, velocity));
+        }
+
+        notes
+    }
+
+    // Quantizes the take to steps of the given rate, one note per step and the first one wins.
+    // Notes longer than a step continue on tied steps.
+    pub fn to_pattern(&self, rate: Rate) -> Pattern {
+        let ticks = rate.ticks();
+        let notes = self.notes();
+        let mut steps: Vec<Option<Step>> = vec![None; MAX_STEPS];
+
+        // Steps start on the clock's grid when it was running, otherwise on the first note
+        let first = self.events.iter().find(|e| matches!(e.message, MidiMessage::NoteOn(_, _, v) if v > 0));
+        let offset = first.and_then(|e| e.position).map_or(0, |position| position % ticks);
+        let origin = notes.first().map_or(0, |note| note.0.saturating_sub(offset));
+
+        for (start, end, pitch, velocity) in notes {
+            let index = ((start - origin) as f32 / ticks as f32).round() as usize;
+            if index >= MAX_STEPS || steps[index].is_some() {
+                continue;
+            }
+
+            let length = (end - start).max(1) as f32 / ticks as f32;
+            let note = Step { velocity: velocity as f32 / 127.0, gate: length.min(1.0), ..Step::new(pitch) };
+            steps[index] = Some(note);
+
+            // The last tied step holds what is left of the note
+            let mut left = length - 1.0;
+            let mut i = index + 1;
+            while left > 0.0 && i < MAX_STEPS && steps[i].is_none() {
+                steps[i] = Some(Step { tie: true, gate: left.min(1.0), ..note });
+                left -= 1.0;
+                i += 1;
+            }
+        }
+
+        let length = steps.iter().rposition(|s| s.is_some()).map_or(1, |i| i + 1);
+        let mut pattern = Pattern { steps: vec![], rate };
+        pattern.set_length(length);
+        for (i, step) in steps.into_iter().take(length).enumerate() {
+            if let Some(step) = step {
+                pattern.set_step(i, step);
+            }
+        }
+
+        pattern
+    }
+}
+
+pub struct Recorder {
+    pub recording: bool,
+    take: Take,
+    // Engine sample position the take started at
+    start: usize
+}
+
+impl Recorder {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            recording: false,
+            take: Take { events: vec![], sample_rate, bpm: 120.0 },
+            start: 0
+        }
+    }
+
+    // Replaces the last take
+    pub fn start(&mut self, sample_position: usize, sample_rate: f32, bpm: f32) {
+        self.take = Take { events: vec![], sample_rate, bpm };
+        self.start = sample_position;
+        self.recording = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.recording = false;
+    }
+
+    pub fn get_take(&self) -> &Take {
+        &self.take
+    }
+
+    pub fn record(&mut self, sample_position: usize, position: Option<usize>, message: &MidiMessage) {
+        // Timing messages would only fill up the take
+        if !self.recording || matches!(message, MidiMessage::Clock | MidiMessage::ActiveSensing) {
+            return;
+        }
+
+        self.take.events.push(RecordedEvent {
+            time: sample_position.saturating_sub(self.start),
+            position,
+            message: message.clone()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recorder() {
+        // 120 bpm, a quarter note is half a second
+        let sample_rate = 48000.0;
+        let quarter = 24000;
+        let sixteenth = Rate::Sixteenth.ticks();
+
+        let mut recorder = Recorder::new(sample_rate);
+        recorder.record(0, None, &MidiMessage::NoteOn(0, 40, 100));
+        recorder.start(1000, sample_rate, 120.0);
+
+        // The clock was a tick into a step when the take started, and the first note is a bit late
+        let played = [
+            (quarter / 4 + 200, MidiMessage::NoteOn(0, 60, 127)),
+            (quarter / 4 + 300, MidiMessage::Clock),
+            (quarter / 2, MidiMessage::NoteOff(0, 60, 0)),
+            (quarter + 100, MidiMessage::NoteOn(0, 62, 64)),
+            (quarter * 2 - quarter / 8, MidiMessage::NoteOff(0, 62, 0))
+        ];
+        for (time, message) in played.iter() {
+            recorder.record(1000 + time, Some(sixteenth + 1 + time * PPQ / quarter), message);
+        }
+        recorder.stop();
+        recorder.record(1000 + quarter * 2, None, &MidiMessage::NoteOn(0, 64, 100));
+
+        let take = recorder.get_take();
+        assert_eq!(take.events.len(), 4);
+
+        let file = take.to_midi_file();
+        let ticks: Vec<u64> = file.tracks[0].events.iter().map(|e| e.tick).collect();
+        assert_eq!(ticks, vec![0, 124, 240, 482, 900]);
+        assert_eq!(file.tracks[0].events[0].event, MidiFileEvent::Tempo(500_000));
+
+        // A sixteenth note, two rests, then a note held for three and a half steps
+        let pattern = take.to_pattern(Rate::Sixteenth);
+        let steps: Vec<(u8, bool, bool, f32)> = pattern.steps.iter().map(|s| (s.pitch, s.rest, s.tie, s.gate)).collect();
+        assert_eq!(steps, vec![
+            (60, false, false, 1.0),
+            (60, true, false, 1.0),
+            (60, true, false, 1.0),
+            (62, false, false, 1.0),
+            (62, false, true, 1.0),
+            (62, false, true, 1.0),
+            (62, false, true, 0.5)
+        ]);
+        assert_eq!(pattern.steps[3].velocity, 64.0 / 127.0);
+    }
+}
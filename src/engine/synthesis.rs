@@ -1,22 +1,101 @@
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
 use crate::dsp::add_and_divide::AddAndDivide;
 use crate::dsp::buffer::Buffer;
+use crate::engine::midi::{MidiEvent, MidiMessage};
+use crate::engine::note_handler::{Half, KeyboardMode, NoteHandler};
+use crate::engine::mpe::{ChannelState, MpeZone, RpnChange, DEFAULT_BEND_RANGE, MPE_MEMBER_BEND_RANGE};
 use crate::engine::voice::{Voice, VoiceData};
+use crate::modulators::matrix::{ModMatrix, ModSource, ModValues};
+use crate::system::midi_map::MidiMap;
 use crate::system::parameter::ParameterID;
-use crate::engine::clock::Clock;
+use crate::system::tuning::Tuning;
+use crate::system::velocity::VelocityCurve;
 
-const VOICES: usize = 12;
+// Voices are created up front, the polyphony setting decides how many of them are used
+pub const MAX_VOICES: usize = 32;
+pub const DEFAULT_POLYPHONY: usize = 12;
+
+const CC_SUSTAIN: u8 = 64;
+const CC_SOSTENUTO: u8 = 66;
+const CC_SOFT_PEDAL: u8 = 67;
+// Pedals count as down from halfway, so continuous pedals switch at half-pedal
+pub const PEDAL_THRESHOLD: u8 = 64;
+// Velocity of notes played with the soft pedal down, relative to how they were played
+const SOFT_PEDAL_VELOCITY: f32 = 0.7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum VoiceMode {
+    Poly,
+    // One voice, every new note retriggers the envelope
+    Mono,
+    // One voice, overlapping notes change pitch without retriggering
+    Legato
+}
+
+// Which held note a mono voice plays
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum NotePriority {
+    Last,
+    Low,
+    High
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GlideMode {
+    Always,
+    // Only between overlapping notes
+    Legato
+}
+
+impl VoiceMode {
+    pub fn all() -> &'static [VoiceMode] {
+        &[VoiceMode::Poly, VoiceMode::Mono, VoiceMode::Legato]
+    }
+}
+
+impl NotePriority {
+    pub fn all() -> &'static [NotePriority] {
+        &[NotePriority::Last, NotePriority::Low, NotePriority::High]
+    }
+}
 
 pub struct Synth {
     pub voices: Vec<Voice>,
     pub sample_rate: f32,
     pub block_size: usize,
 
-    voices_in_use: usize,
-    next_voice: usize,
-    sustained_notes: Vec<u8>,
+    polyphony: usize,
+    voice_mode: VoiceMode,
+    note_priority: NotePriority,
+    // Seconds, 0 turns glide off
+    glide_time: f32,
+    glide_mode: GlideMode,
+    // Notes held down in mono modes as (channel, note, velocity), oldest first
+    held_notes: Vec<(u8, u8, u8)>,
+    // Pitch of the last note played, where the next one glides from
+    last_pitch: Option<f32>,
+
+    notes: NoteHandler,
+    // Mono notes released while the sustain pedal is down, in poly mode the voices keep track themselves
+    sustained_notes: Vec<(u8, u8)>,
     sustain: bool,
+    sostenuto: bool,
+    soft_pedal: bool,
     mix: AddAndDivide,
-    clock: Clock
+    output: Buffer,
+    midi_map: MidiMap,
+    // Parameters moved by mapped controllers since they were last taken, latest value only
+    mapped_changes: Vec<(ParameterID, f32)>,
+
+    channels: [ChannelState; 16],
+    mpe: Option<MpeZone>,
+    // Bend range of every channel outside the MPE zone, in semitones
+    bend_range: f32,
+    mod_matrix: ModMatrix,
+    // Shared with every voice
+    tuning: Arc<Tuning>,
+    velocity_curve: VelocityCurve
 }
 
 impl Synth {
@@ -27,121 +106,610 @@ impl Synth {
         };
 
         let mut voices = vec![];
-        for v in 0..VOICES {
+        for v in 0..MAX_VOICES {
             voices.push(Voice::new(v, data.clone()));
         }
 
+        let parameters = voices[0].get_parameters().iter().map(|p| (p.id, p.get_normalized())).collect();
+
         Synth {
             voices,
             sample_rate,
             block_size,
-            voices_in_use: 0,
-            next_voice: 0,
+            polyphony: DEFAULT_POLYPHONY,
+            voice_mode: VoiceMode::Poly,
+            note_priority: NotePriority::Last,
+            glide_time: 0.0,
+            glide_mode: GlideMode::Always,
+            held_notes: vec![],
+            last_pitch: None,
+
+            notes: NoteHandler::new(DEFAULT_POLYPHONY, parameters),
             sustained_notes: vec![],
             sustain: false,
+            sostenuto: false,
+            soft_pedal: false,
             mix: AddAndDivide::new(),
-            clock: Clock::new(120.0, sample_rate, block_size)
+            output: Buffer::new(block_size, "Synth".to_string()),
+            midi_map: MidiMap::default_mappings(),
+            mapped_changes: vec![],
+
+            channels: [ChannelState::default(); 16],
+            mpe: None,
+            bend_range: DEFAULT_BEND_RANGE,
+            mod_matrix: ModMatrix::default_routes(),
+            tuning: Arc::new(Tuning::equal()),
+            velocity_curve: VelocityCurve::default()
         }
     }
 
-    pub fn process(&mut self) -> Buffer {
-        let _position = self.clock.tick();
+    pub fn process(&mut self) -> &Buffer {
+        self.process_events(&[])
+    }
+
+    // Renders one block, applying each event at its sample offset within the block.
+    // Events are expected in order of their offset.
+    pub fn process_events(&mut self, events: &[MidiEvent]) -> &Buffer {
+        let mut start = 0;
+        for event in events {
+            let offset = event.offset.min(self.block_size);
+            if offset > start {
+                self.render(start, offset);
+                start = offset;
+            }
 
-        let note_ons = self.clock.get_notes();
-        for note in note_ons {
-            self.note_on(note.pitch, (note.velocity * 127.0) as u8);
+            self.handle_message(&event.message);
         }
 
-        let note_offs = self.clock.get_note_offs();
-        for note in note_offs {
-            self.note_off(note);
+        if start < self.block_size {
+            self.render(start, self.block_size);
         }
-        
-        let mut outputs = vec![];
-        for voice in &mut self.voices {
-            outputs.push(voice.process());
+
+        let polyphony = self.polyphony;
+        let active = move |(i, voice): &(usize, &Voice)| *i < polyphony || voice.is_sounding();
+
+        for (i, voice) in self.voices.iter_mut().enumerate() {
+            if i < polyphony || voice.is_sounding() {
+                voice.finish();
+            }
         }
 
-        self.mix.process(&outputs, self.block_size, self.voices_in_use as f32)
+        let voices_in_use = self.voices.iter().filter(|v| v.is_busy()).count();
+        let outputs = self.voices.iter().enumerate().filter(active).map(|(_, v)| v.get_output());
+        self.mix.process(outputs, &mut self.output, voices_in_use as f32);
+
+        &self.output
     }
 
-    pub fn note_on(&mut self, midi_note: u8, velocity: u8) {
-        let mut found = false;
-        let old_idx = self.next_voice;
-        
-        while old_idx != (self.next_voice+1 % VOICES) {
-            if self.voices[self.next_voice].get_midi_note() == midi_note {
-                self.voices[self.next_voice].note_off();
+    // Voices past the polyphony limit are only rendered while they fade out
+    fn render(&mut self, start: usize, end: usize) {
+        for (i, voice) in self.voices.iter_mut().enumerate() {
+            if i < self.polyphony || voice.is_sounding() {
+                voice.render(start, end);
             }
-            if !self.voices[self.next_voice].is_busy() {
-                self.voices[self.next_voice].note_on(midi_note, velocity);
-                self.voices_in_use += 1;
-                found = true;
-                break;
+        }
+    }
+
+    pub fn get_output(&self) -> &Buffer {
+        &self.output
+    }
+
+    pub fn note_on(&mut self, channel: u8, midi_note: u8, velocity: u8) {
+        let velocity = match self.soft_pedal(channel) {
+            true => ((velocity as f32 * SOFT_PEDAL_VELOCITY) as u8).max(1),
+            false => velocity
+        };
+
+        if self.voice_mode != VoiceMode::Poly {
+            self.sustained_notes.retain(|n| n.0 != channel || n.1 != midi_note);
+            self.held_notes.retain(|n| n.0 != channel || n.1 != midi_note);
+            self.held_notes.push((channel, midi_note, velocity));
+            self.play_mono();
+            return;
+        }
+
+        let overlapping = self.voices[..self.polyphony].iter().any(|v| v.is_busy());
+        let last_pitch = self.last_pitch;
+        for half in self.notes.halves_for(midi_note) {
+            if let Some(index) = self.allocate_voice(*half, channel, midi_note) {
+                self.start_voice(index, channel, midi_note, velocity);
+                self.glide(index, last_pitch, overlapping);
             }
-            
-            self.next_voice = (self.next_voice + 1) % VOICES;
         }
+    }
 
-        if !found {
-            let mut oldest = 0;
-            let mut oldest_time = self.voices[0].last_used();
-            for (i, voice) in self.voices.iter().enumerate() {
-                if voice.last_used() < oldest_time {
-                    oldest = i;
-                    oldest_time = voice.last_used();
-                }
+    fn start_voice(&mut self, index: usize, channel: u8, midi_note: u8, velocity: u8) {
+        let pitch_offset = self.pitch_offset(channel);
+        let mod_values = self.mod_values(channel);
+
+        let velocity = self.velocity_curve.apply(velocity);
+
+        let voice = &mut self.voices[index];
+        // MPE controllers send the initial bend, pressure and timbre before the note
+        voice.note_on(channel, midi_note, velocity, pitch_offset);
+        voice.set_modulation(mod_values, &self.mod_matrix);
+    }
+
+    fn glide(&mut self, index: usize, from: Option<f32>, overlapping: bool) {
+        let glides = match self.glide_mode {
+            GlideMode::Always => true,
+            GlideMode::Legato => overlapping
+        };
+
+        if let Some(from) = from.filter(|_| glides && self.glide_time > 0.0) {
+            self.voices[index].glide_from(from, self.glide_time);
+        }
+        self.last_pitch = Some(self.voices[index].get_midi_note() as f32);
+    }
+
+    // Picks a voice from the half's pool. Prefers idle voices, then released ones, then the quietest
+    // and finally the oldest. Ties go round-robin.
+    fn allocate_voice(&mut self, half: Half, channel: u8, midi_note: u8) -> Option<usize> {
+        let pool = self.notes.half(half);
+
+        // The same note played again releases its old voice, even if a pedal is holding it
+        for index in pool.get_voices() {
+            let voice = &mut self.voices[*index];
+            if voice.is_busy() && voice.get_midi_note() == midi_note && voice.get_channel() == channel {
+                voice.note_off();
             }
+        }
+
+        let voices = &self.voices;
+        let index = pool.candidates().min_by(|&a, &b| {
+            let (a, b) = (&voices[a], &voices[b]);
+            a.is_busy().cmp(&b.is_busy())
+                .then(a.is_sounding().cmp(&b.is_sounding()))
+                .then(a.get_level().total_cmp(&b.get_level()))
+                .then(a.last_used().cmp(&b.last_used()))
+        })?;
+
+        self.notes.half_mut(half).used(index);
+        Some(index)
+    }
+
+    // Plays the held note with the highest priority on the first voice
+    fn play_mono(&mut self) {
+        let note = match self.note_priority {
+            NotePriority::Last => self.held_notes.last(),
+            NotePriority::Low => self.held_notes.iter().min_by_key(|n| n.1),
+            NotePriority::High => self.held_notes.iter().max_by_key(|n| n.1)
+        }.cloned();
+
+        let Some((channel, midi_note, velocity)) = note else {
+            self.voices[0].note_off();
+            return;
+        };
+
+        let voice = &self.voices[0];
+        if !voice.is_busy() {
+            self.start_voice(0, channel, midi_note, velocity);
+            self.glide(0, self.last_pitch, false);
+            return;
+        }
+
+        if voice.get_midi_note() == midi_note && voice.get_channel() == channel {
+            return;
+        }
 
-            self.voices[oldest].note_on(midi_note, velocity);
+        let from = Some(voice.get_pitch());
+        if self.voice_mode == VoiceMode::Legato {
+            let pitch_offset = self.pitch_offset(channel);
+            self.voices[0].legato(channel, midi_note, pitch_offset);
+        } else {
+            self.start_voice(0, channel, midi_note, velocity);
         }
+        self.glide(0, from, true);
     }
 
-    pub fn note_off(&mut self, midi_note: u8) {
-        if self.sustain {
-            self.sustained_notes.push(midi_note);
+    pub fn note_off(&mut self, channel: u8, midi_note: u8) {
+        if self.voice_mode != VoiceMode::Poly {
+            if !self.sustain(channel) {
+                self.held_notes.retain(|n| n.0 != channel || n.1 != midi_note);
+                self.play_mono();
+            } else if !self.sustained_notes.contains(&(channel, midi_note)) {
+                self.sustained_notes.push((channel, midi_note));
+            }
             return;
         }
         
-        for voice in &mut self.voices {
-            if voice.is_busy() && voice.get_midi_note() == midi_note {
-                voice.note_off();
-                self.voices_in_use -= 1;
-                break;
+        let sustain = self.sustain(channel);
+        for voice in &mut self.voices[..self.polyphony] {
+            // Layered notes play on a voice in each half
+            if voice.is_key_down() && voice.get_midi_note() == midi_note && voice.get_channel() == channel {
+                voice.key_up(sustain);
             }
         }
     }
+
+    // Voices past the new limit are faded out
+    pub fn set_polyphony(&mut self, polyphony: usize) {
+        self.polyphony = polyphony.clamp(1, MAX_VOICES);
+
+        for voice in &mut self.voices[self.polyphony..] {
+            voice.kill();
+        }
+
+        self.notes.assign_voices(self.polyphony);
+        self.apply_patches();
+    }
+
+    pub fn set_keyboard_mode(&mut self, mode: KeyboardMode, split_point: u8) {
+        self.notes.set_mode(mode, split_point, self.polyphony);
+        self.apply_patches();
+    }
+
+    // Gives every voice the patch of the half it belongs to
+    fn apply_patches(&mut self) {
+        for half in [Half::Lower, Half::Upper] {
+            let pool = self.notes.half(half);
+            for index in pool.get_voices() {
+                Self::apply_parameters(&mut self.voices[*index], pool.get_parameters());
+            }
+        }
+    }
+
+    fn apply_parameters(voice: &mut Voice, values: &[(ParameterID, f32)]) {
+        for p in voice.get_parameters_mut() {
+            if let Some((_, value)) = values.iter().find(|(id, _)| *id == p.id) {
+                p.set_value(*value);
+            }
+        }
+    }
+
+    pub fn set_voice_mode(&mut self, mode: VoiceMode, priority: NotePriority) {
+        if mode != self.voice_mode {
+            self.all_notes_off();
+        }
+
+        self.voice_mode = mode;
+        self.note_priority = priority;
+    }
+
+    pub fn set_glide(&mut self, time: f32, mode: GlideMode) {
+        self.glide_time = time.max(0.0);
+        self.glide_mode = mode;
+    }
+
+    pub fn all_notes_off(&mut self) {
+        self.held_notes.clear();
+        self.sustained_notes.clear();
+
+        for voice in &mut self.voices {
+            voice.note_off();
+        }
+    }
     
-    pub fn handle_cc(&mut self, cc: u8, value: u8) {
-        if cc == 64 {
-            self.sustain = value == 127;
-    
-            if !self.sustain {
-                let len = self.sustained_notes.len();
-                for i in 0..len {
-                    self.note_off(self.sustained_notes[i]);
+    pub fn handle_cc(&mut self, channel: u8, cc: u8, value: u8) {
+        match cc {
+            CC_SUSTAIN => self.set_sustain(value >= PEDAL_THRESHOLD),
+            CC_SOSTENUTO => self.set_sostenuto(value >= PEDAL_THRESHOLD),
+            CC_SOFT_PEDAL => self.set_soft_pedal(value >= PEDAL_THRESHOLD),
+            _ => {}
+        }
+
+        for (id, value) in self.midi_map.resolve(channel, cc, value) {
+            self.set_parameter(id, value);
+            self.mapped_changes.retain(|(changed, _)| *changed != id);
+            self.mapped_changes.push((id, value));
+        }
+    }
+
+    pub fn take_parameter_changes(&mut self) -> Vec<(ParameterID, f32)> {
+        std::mem::take(&mut self.mapped_changes)
+    }
+
+    fn set_sustain(&mut self, sustain: bool) {
+        if sustain == self.sustain {
+            return;
+        }
+        self.sustain = sustain;
+
+        if !sustain {
+            self.release_sustained(None);
+        }
+    }
+
+    // Lets go of the notes the sustain pedal held, unless a pedal on their own channel still holds them
+    fn release_sustained(&mut self, channel: Option<u8>) {
+        for i in 0..self.voices.len() {
+            let voice_channel = self.voices[i].get_channel();
+            if channel.is_none_or(|channel| channel == voice_channel) {
+                let sustain = self.sustain(voice_channel);
+                self.voices[i].pedal_up(sustain);
+            }
+        }
+
+        let (released, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.sustained_notes).into_iter()
+            .partition(|n| channel.is_none_or(|channel| channel == n.0) && !self.sustain(n.0));
+        self.sustained_notes = kept;
+        if !released.is_empty() {
+            self.held_notes.retain(|n| !released.contains(&(n.0, n.1)));
+            self.play_mono();
+        }
+    }
+
+    fn set_sostenuto(&mut self, sostenuto: bool) {
+        if sostenuto == self.sostenuto {
+            return;
+        }
+        self.sostenuto = sostenuto;
+
+        for i in 0..self.voices.len() {
+            let channel = self.voices[i].get_channel();
+            if !self.channels[channel as usize].sostenuto {
+                let sustain = self.sustain(channel);
+                self.voices[i].set_sostenuto(sostenuto, sustain);
+            }
+        }
+    }
+
+    // Softer and darker, through the SoftPedal modulation source
+    fn set_soft_pedal(&mut self, soft_pedal: bool) {
+        if soft_pedal == self.soft_pedal {
+            return;
+        }
+        self.soft_pedal = soft_pedal;
+        self.update_modulation(None);
+    }
+
+    // The same pedals on an MPE member channel, they only reach the notes on that channel
+    fn handle_channel_pedal(&mut self, channel: u8, cc: u8, down: bool) {
+        let state = &mut self.channels[channel as usize];
+        match cc {
+            CC_SUSTAIN if down != state.sustain => {
+                state.sustain = down;
+                if !down {
+                    self.release_sustained(Some(channel));
+                }
+            },
+            CC_SOSTENUTO if down != state.sostenuto => {
+                state.sostenuto = down;
+                if !self.sostenuto {
+                    let sustain = self.sustain(channel);
+                    for voice in self.voices.iter_mut().filter(|voice| voice.get_channel() == channel) {
+                        voice.set_sostenuto(down, sustain);
+                    }
+                }
+            },
+            CC_SOFT_PEDAL if down != state.soft_pedal => {
+                state.soft_pedal = down;
+                self.update_modulation(Some(channel));
+            },
+            _ => {}
+        }
+    }
+
+    fn sustain(&self, channel: u8) -> bool {
+        self.sustain || self.channels[channel as usize].sustain
+    }
+
+    fn soft_pedal(&self, channel: u8) -> bool {
+        self.soft_pedal || self.channels[channel as usize].soft_pedal
+    }
+
+    pub fn set_midi_map(&mut self, midi_map: MidiMap) {
+        self.midi_map = midi_map;
+    }
+
+    // Member channels get the MPE bend range, the master channel and every channel outside the zone keep the default
+    pub fn set_mpe_zone(&mut self, zone: Option<MpeZone>) {
+        self.mpe = zone;
+
+        for (channel, state) in self.channels.iter_mut().enumerate() {
+            state.bend_range = match zone {
+                Some(zone) if zone.is_member(channel as u8) => MPE_MEMBER_BEND_RANGE,
+                _ => self.bend_range
+            };
+        }
+
+        // Pedals held on channels that left the zone would never be lifted
+        for channel in 0..16 {
+            if !self.is_mpe_member(channel) {
+                for cc in [CC_SUSTAIN, CC_SOSTENUTO, CC_SOFT_PEDAL] {
+                    self.handle_channel_pedal(channel, cc, false);
                 }
-                self.sustained_notes.clear();
             }
         }
-        
-        for voice in self.voices.iter_mut() {
-            let parameters = voice.get_parameters_mut();
-            
-            for parameter in parameters {
-                if parameter.accepts_cc(cc) {
-                    parameter.set_value(value as f32 / 127.0);
+
+        self.update_pitch(None);
+    }
+
+    pub fn get_mpe_zone(&self) -> Option<MpeZone> {
+        self.mpe
+    }
+
+    // Overrides what controllers set over RPN, except for the member channels of the MPE zone
+    pub fn set_bend_range(&mut self, semitones: f32) {
+        self.bend_range = semitones;
+
+        for channel in 0..16 {
+            if !self.is_mpe_member(channel) {
+                self.channels[channel as usize].bend_range = semitones;
+            }
+        }
+
+        self.update_pitch(None);
+    }
+
+    pub fn get_mod_matrix(&self) -> &ModMatrix {
+        &self.mod_matrix
+    }
+
+    pub fn set_mod_matrix(&mut self, mod_matrix: ModMatrix) {
+        self.mod_matrix = mod_matrix;
+        self.update_modulation(None);
+    }
+
+    pub fn set_velocity_curve(&mut self, curve: VelocityCurve) {
+        self.velocity_curve = curve;
+    }
+
+    pub fn get_tuning(&self) -> &Tuning {
+        &self.tuning
+    }
+
+    pub fn set_tuning(&mut self, tuning: Tuning) {
+        self.tuning = Arc::new(tuning);
+        for voice in &mut self.voices {
+            voice.set_tuning(self.tuning.clone());
+        }
+    }
+
+    fn is_mpe_member(&self, channel: u8) -> bool {
+        self.mpe.map(|zone| zone.is_member(channel)).unwrap_or(false)
+    }
+
+    fn is_mpe_master(&self, channel: u8) -> bool {
+        self.mpe.map(|zone| zone.is_master(channel)).unwrap_or(false)
+    }
+
+    // Member channels are bent by their own channel and the zone's master channel
+    fn pitch_offset(&self, channel: u8) -> f32 {
+        let mut offset = self.channels[channel as usize].bend_semitones();
+        if let Some(zone) = self.mpe.filter(|zone| zone.is_member(channel)) {
+            offset += self.channels[zone.master as usize].bend_semitones();
+        }
+
+        offset
+    }
+
+    // Member channels also follow the controllers of the zone's master channel, whichever is further up wins
+    fn mod_values(&self, channel: u8) -> ModValues {
+        let mut state = self.channels[channel as usize];
+        if let Some(zone) = self.mpe.filter(|zone| zone.is_member(channel)) {
+            let master = &self.channels[zone.master as usize];
+            state.pressure = state.pressure.max(master.pressure);
+            state.timbre = state.timbre.max(master.timbre);
+            state.mod_wheel = state.mod_wheel.max(master.mod_wheel);
+        }
+
+        let mut values = ModValues::default();
+        values.set(ModSource::Pressure, state.pressure);
+        values.set(ModSource::Timbre, state.timbre);
+        values.set(ModSource::ModWheel, state.mod_wheel);
+        values.set(ModSource::SoftPedal, if self.soft_pedal(channel) { 1.0 } else { 0.0 });
+
+        values
+    }
+
+    // A message on the master channel affects the whole zone, otherwise only the voices on that channel
+    fn affects(&self, channel: Option<u8>, voice: &Voice) -> bool {
+        match channel {
+            None => true,
+            Some(channel) if self.is_mpe_master(channel) => voice.get_channel() == channel || self.is_mpe_member(voice.get_channel()),
+            Some(channel) => voice.get_channel() == channel
+        }
+    }
+
+    fn update_pitch(&mut self, channel: Option<u8>) {
+        for i in 0..self.voices.len() {
+            if self.affects(channel, &self.voices[i]) {
+                let offset = self.pitch_offset(self.voices[i].get_channel());
+                self.voices[i].set_pitch_offset(offset);
+            }
+        }
+    }
+
+    fn update_modulation(&mut self, channel: Option<u8>) {
+        for i in 0..self.voices.len() {
+            if self.affects(channel, &self.voices[i]) {
+                let values = self.mod_values(self.voices[i].get_channel());
+                self.voices[i].set_modulation(values, &self.mod_matrix);
+            }
+        }
+    }
+
+    fn handle_rpn(&mut self, channel: u8, change: RpnChange) {
+        match change {
+            RpnChange::BendRange(_) => self.update_pitch(Some(channel)),
+            // The MPE Configuration Message is only valid on the first and last channel
+            RpnChange::MpeConfiguration(0) => {
+                if self.is_mpe_master(channel) {
+                    self.set_mpe_zone(None);
                 }
+            },
+            RpnChange::MpeConfiguration(members) => match channel {
+                0 => self.set_mpe_zone(Some(MpeZone::lower(members))),
+                15 => self.set_mpe_zone(Some(MpeZone::upper(members))),
+                _ => {}
             }
         }
     }
 
-    pub fn toggle_playback(&mut self) -> bool {
-        self.clock.toggle_play();
-        self.clock.is_playing
+    pub fn handle_message(&mut self, message: &MidiMessage) {
+        match *message {
+            MidiMessage::NoteOn(channel, note, velocity) => {
+                self.note_on(channel, note, velocity);
+            },
+            MidiMessage::NoteOff(channel, note, _) => {
+                self.note_off(channel, note);
+            },
+            MidiMessage::MidiCC(channel, cc, value) => {
+                if ChannelState::is_rpn(cc) {
+                    if let Some(change) = self.channels[channel as usize].handle_rpn(cc, value) {
+                        self.handle_rpn(channel, change);
+                    }
+                    return;
+                }
+
+                // Controllers on member channels belong to the note playing there
+                if self.is_mpe_member(channel) {
+                    match cc {
+                        74 => {
+                            self.channels[channel as usize].timbre = value as f32 / 127.0;
+                            self.update_modulation(Some(channel));
+                        },
+                        CC_SUSTAIN | CC_SOSTENUTO | CC_SOFT_PEDAL => self.handle_channel_pedal(channel, cc, value >= PEDAL_THRESHOLD),
+                        _ => {}
+                    }
+                    return;
+                }
+
+                self.handle_cc(channel, cc, value);
+            },
+            // The mod wheel is CC 1, so besides being a modulation source it can be mapped like any other controller
+            MidiMessage::ModWheel(channel, value) => {
+                self.channels[channel as usize].mod_wheel = value as f32 / 127.0;
+                self.update_modulation(Some(channel));
+
+                if !self.is_mpe_member(channel) {
+                    self.handle_cc(channel, 1, value);
+                }
+            },
+            MidiMessage::PitchBend(channel, value) => {
+                self.channels[channel as usize].set_bend(value);
+                self.update_pitch(Some(channel));
+            },
+            MidiMessage::ChannelPressure(channel, value) => {
+                self.channels[channel as usize].pressure = value as f32 / 127.0;
+                self.update_modulation(Some(channel));
+            },
+            MidiMessage::PolyPressure(channel, note, value) => {
+                let matrix = &self.mod_matrix;
+                for voice in self.voices.iter_mut().filter(|v| v.is_busy() && v.get_channel() == channel && v.get_midi_note() == note) {
+                    let mut values = voice.get_mod_values();
+                    values.set(ModSource::Pressure, value as f32 / 127.0);
+                    voice.set_modulation(values, matrix);
+                }
+            },
+            // MIDI Tuning Standard
+            MidiMessage::SysEx(ref data) => {
+                let mut tuning = (*self.tuning).clone();
+                if tuning.apply_mts(data) {
+                    self.set_tuning(tuning);
+                }
+            },
+            // Runs on the audio thread, messages the synth has no use for are ignored
+            _ => {}
+        }
     }
-    
+
+    // Sets the parameter in the patch of both halves
     pub fn set_parameter(&mut self, parameter: ParameterID, value: f32) {
+        self.notes.half_mut(Half::Lower).set_parameter(parameter, value);
+        self.notes.half_mut(Half::Upper).set_parameter(parameter, value);
+
         for voice in self.voices.iter_mut() {
             let parameters = voice.get_parameters_mut();
             
@@ -154,12 +722,265 @@ impl Synth {
         }
     }
 
+    // Normalized values of the lower half's patch, which is the whole patch outside split and layer
+    pub fn get_parameters(&self) -> &[(ParameterID, f32)] {
+        self.notes.half(Half::Lower).get_parameters()
+    }
+
+    pub fn set_half_parameter(&mut self, half: Half, parameter: ParameterID, value: f32) {
+        self.notes.half_mut(half).set_parameter(parameter, value);
+
+        let pool = self.notes.half(half);
+        for index in pool.get_voices() {
+            Self::apply_parameters(&mut self.voices[*index], &[(parameter, value)]);
+        }
+    }
+
     pub fn set_block_size(&mut self, block_size: usize) {
         self.block_size = block_size;
-        self.clock.set_block_size(block_size);
+        self.output = Buffer::new(block_size, "Synth".to_string());
 
         for voice in &mut self.voices {
             voice.set_block_size(block_size);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modulators::matrix::ModRoute;
+
+    fn voice_for(synth: &Synth, channel: u8, note: u8) -> &Voice {
+        synth.voices.iter().find(|v| v.is_busy() && v.get_channel() == channel && v.get_midi_note() == note).unwrap()
+    }
+
+    #[test]
+    fn test_mpe_routing() {
+        let mut synth = Synth::new(48000.0, 64);
+
+        // MPE Configuration Message, lower zone with 15 member channels
+        for cc in [(101, 0), (100, 6), (6, 15)] {
+            synth.handle_message(&MidiMessage::MidiCC(0, cc.0, cc.1));
+        }
+        assert_eq!(synth.get_mpe_zone(), Some(MpeZone::lower(15)));
+
+        synth.handle_message(&MidiMessage::NoteOn(1, 60, 100));
+        synth.handle_message(&MidiMessage::NoteOn(2, 64, 100));
+
+        // Half of the 48 semitone member range, only for the note on channel 2
+        synth.handle_message(&MidiMessage::PitchBend(1, 4096));
+        assert_eq!(voice_for(&synth, 1, 60).get_pitch_offset(), 24.0);
+        assert_eq!(voice_for(&synth, 2, 64).get_pitch_offset(), 0.0);
+
+        // The master channel bends the whole zone by its own 2 semitone range
+        synth.handle_message(&MidiMessage::PitchBend(0, -8192));
+        assert_eq!(voice_for(&synth, 1, 60).get_pitch_offset(), 22.0);
+        assert_eq!(voice_for(&synth, 2, 64).get_pitch_offset(), -2.0);
+
+        // The configured range applies to channels outside the member channels
+        synth.set_bend_range(12.0);
+        assert_eq!(voice_for(&synth, 1, 60).get_pitch_offset(), 12.0);
+
+        // So does the mod wheel on the master channel
+        synth.handle_message(&MidiMessage::ModWheel(0, 127));
+        assert_eq!(voice_for(&synth, 1, 60).get_mod_values().get(ModSource::ModWheel), 1.0);
+        synth.handle_message(&MidiMessage::ModWheel(0, 0));
+
+        synth.handle_message(&MidiMessage::ChannelPressure(2, 127));
+        synth.handle_message(&MidiMessage::MidiCC(2, 74, 127));
+        let values = voice_for(&synth, 1, 60).get_mod_values();
+        assert_eq!((values.get(ModSource::Pressure), values.get(ModSource::Timbre)), (0.0, 0.0));
+        let values = voice_for(&synth, 2, 64).get_mod_values();
+        assert_eq!((values.get(ModSource::Pressure), values.get(ModSource::Timbre)), (1.0, 1.0));
+
+        // Same note on another channel is a separate voice
+        synth.handle_message(&MidiMessage::NoteOn(3, 60, 100));
+        synth.handle_message(&MidiMessage::NoteOff(1, 60, 0));
+        assert!(voice_for(&synth, 3, 60).is_busy());
+
+        // Pedals on a member channel only hold the notes on that channel
+        synth.handle_message(&MidiMessage::NoteOn(4, 67, 100));
+        synth.handle_message(&MidiMessage::NoteOn(5, 69, 100));
+        synth.handle_message(&MidiMessage::MidiCC(4, 64, 127));
+        synth.handle_message(&MidiMessage::MidiCC(4, 67, 127));
+        synth.handle_message(&MidiMessage::NoteOff(4, 67, 0));
+        synth.handle_message(&MidiMessage::NoteOff(5, 69, 0));
+        let held = |synth: &Synth, channel: u8, note: u8| synth.voices.iter().any(|v| v.is_busy() && v.get_channel() == channel && v.get_midi_note() == note);
+        assert_eq!((held(&synth, 4, 67), held(&synth, 5, 69)), (true, false));
+        assert_eq!(voice_for(&synth, 4, 67).get_mod_values().get(ModSource::SoftPedal), 1.0);
+        synth.handle_message(&MidiMessage::MidiCC(4, 64, 0));
+        assert!(!held(&synth, 4, 67));
+
+        synth.process();
+    }
+
+    #[test]
+    fn test_mono_and_glide() {
+        let mut synth = Synth::new(48000.0, 64);
+        let busy = |synth: &Synth| synth.voices.iter().filter(|v| v.is_busy()).count();
+
+        synth.set_polyphony(2);
+        for note in [60, 62, 64] {
+            synth.handle_message(&MidiMessage::NoteOn(0, note, 100));
+        }
+        assert_eq!(busy(&synth), 2);
+        synth.set_polyphony(12);
+        synth.all_notes_off();
+
+        // Low note priority, the voice goes back to the lowest note still held
+        synth.set_voice_mode(VoiceMode::Legato, NotePriority::Low);
+        synth.handle_message(&MidiMessage::NoteOn(0, 60, 100));
+        // The first voice was still releasing, it fades out before the note starts
+        synth.process();
+        synth.process();
+        synth.handle_message(&MidiMessage::NoteOn(0, 55, 100));
+        synth.handle_message(&MidiMessage::NoteOn(0, 67, 100));
+        assert_eq!(busy(&synth), 1);
+        assert_eq!(synth.voices[0].get_midi_note(), 55);
+
+        synth.handle_message(&MidiMessage::NoteOff(0, 55, 0));
+        assert_eq!(synth.voices[0].get_midi_note(), 60);
+        assert!(synth.voices[0].is_busy());
+
+        // Overlapping notes glide, 100ms is about 20 blocks
+        synth.set_glide(0.1, GlideMode::Legato);
+        synth.handle_message(&MidiMessage::NoteOn(0, 48, 100));
+        assert_eq!(synth.voices[0].get_pitch(), 60.0);
+        synth.process();
+        let pitch = synth.voices[0].get_pitch();
+        assert!(pitch < 60.0 && pitch > 48.0);
+        for _ in 0..100 {
+            synth.process();
+        }
+        assert_eq!(synth.voices[0].get_pitch(), 48.0);
+
+        // Not after the last note was released
+        for note in [48, 60, 67] {
+            synth.handle_message(&MidiMessage::NoteOff(0, note, 0));
+        }
+        assert_eq!(busy(&synth), 0);
+        synth.handle_message(&MidiMessage::NoteOn(0, 72, 100));
+        assert_eq!(synth.voices[0].get_pitch(), 72.0);
+    }
+
+    #[test]
+    fn test_voice_stealing() {
+        let mut synth = Synth::new(48000.0, 64);
+        synth.set_polyphony(3);
+
+        synth.handle_message(&MidiMessage::NoteOn(0, 60, 100));
+        synth.handle_message(&MidiMessage::NoteOn(0, 62, 30));
+        synth.handle_message(&MidiMessage::NoteOn(0, 64, 100));
+        for _ in 0..20 {
+            synth.process();
+        }
+
+        // A released voice goes first, even though the quiet one is older
+        synth.handle_message(&MidiMessage::NoteOff(0, 64, 0));
+        synth.handle_message(&MidiMessage::NoteOn(0, 65, 100));
+        let notes = |synth: &Synth| synth.voices[..3].iter().map(|v| v.get_midi_note()).collect::<Vec<_>>();
+        assert_eq!(notes(&synth), vec![60, 62, 65]);
+
+        // Then the quietest one
+        synth.handle_message(&MidiMessage::NoteOn(0, 67, 100));
+        assert_eq!(notes(&synth), vec![60, 67, 65]);
+
+        // Releasing the stolen note doesn't touch the voice that took over
+        synth.handle_message(&MidiMessage::NoteOff(0, 62, 0));
+        assert_eq!(synth.voices.iter().filter(|v| v.is_busy()).count(), 3);
+
+        // The fade takes 2ms, after that the new note is playing
+        synth.process();
+        synth.process();
+        assert!(synth.voices[1].is_busy() && synth.voices[1].get_level() > 0.0);
+    }
+
+    #[test]
+    fn test_keyboard_split() {
+        let mut synth = Synth::new(48000.0, 64);
+        synth.set_polyphony(8);
+        synth.set_keyboard_mode(KeyboardMode::Split, 60);
+        synth.set_half_parameter(Half::Upper, ParameterID::WT1Shape, 1.0);
+
+        let shape = |voice: &mut Voice| voice.get_parameters().iter()
+            .find(|p| p.id == ParameterID::WT1Shape).unwrap().get_normalized();
+        assert_eq!(shape(&mut synth.voices[0]), 0.0);
+        assert_eq!(shape(&mut synth.voices[4]), 1.0);
+
+        // Each half plays from its own pool
+        synth.handle_message(&MidiMessage::NoteOn(0, 48, 100));
+        synth.handle_message(&MidiMessage::NoteOn(0, 72, 100));
+        let busy = |synth: &Synth| synth.voices.iter().enumerate().filter(|(_, v)| v.is_busy()).map(|(i, _)| i).collect::<Vec<_>>();
+        assert_eq!(busy(&synth), vec![0, 4]);
+
+        // Layer mode stacks both patches on every note
+        synth.all_notes_off();
+        synth.set_keyboard_mode(KeyboardMode::Layer, 60);
+        synth.handle_message(&MidiMessage::NoteOn(0, 48, 100));
+        let layered = busy(&synth);
+        assert_eq!(layered.len(), 2);
+        assert!(layered[0] < 4 && layered[1] >= 4);
+    }
+
+    #[test]
+    fn test_pedals() {
+        let mut synth = Synth::new(48000.0, 64);
+        let busy = |synth: &Synth, note: u8| synth.voices.iter().filter(|v| v.is_busy() && v.get_midi_note() == note).count();
+
+        // Half-pedal counts as down
+        synth.handle_message(&MidiMessage::MidiCC(0, 64, 70));
+        synth.handle_message(&MidiMessage::NoteOn(0, 60, 100));
+        synth.handle_message(&MidiMessage::NoteOff(0, 60, 0));
+        assert_eq!(busy(&synth, 60), 1);
+
+        // A re-struck note gets a new voice while the old one releases
+        synth.handle_message(&MidiMessage::NoteOn(0, 60, 100));
+        synth.handle_message(&MidiMessage::NoteOff(0, 60, 0));
+        synth.handle_message(&MidiMessage::NoteOn(0, 60, 100));
+        assert_eq!(busy(&synth, 60), 1);
+        assert_eq!(synth.voices.iter().filter(|v| v.is_sounding()).count(), 3);
+
+        // Lifting the pedal keeps notes whose key is still down
+        synth.handle_message(&MidiMessage::MidiCC(0, 64, 20));
+        assert_eq!(busy(&synth, 60), 1);
+        synth.handle_message(&MidiMessage::NoteOff(0, 60, 0));
+        assert_eq!(busy(&synth, 60), 0);
+
+        // Sostenuto only holds the notes that were down when it was pressed
+        synth.handle_message(&MidiMessage::NoteOn(0, 48, 100));
+        synth.handle_message(&MidiMessage::MidiCC(0, 66, 127));
+        synth.handle_message(&MidiMessage::NoteOn(0, 52, 100));
+        synth.handle_message(&MidiMessage::NoteOff(0, 48, 0));
+        synth.handle_message(&MidiMessage::NoteOff(0, 52, 0));
+        assert_eq!((busy(&synth, 48), busy(&synth, 52)), (1, 0));
+        synth.handle_message(&MidiMessage::MidiCC(0, 66, 0));
+        assert_eq!(busy(&synth, 48), 0);
+
+        synth.handle_message(&MidiMessage::MidiCC(0, 67, 127));
+        synth.handle_message(&MidiMessage::NoteOn(0, 55, 100));
+        assert_eq!(voice_for(&synth, 0, 55).get_mod_values().get(ModSource::SoftPedal), 1.0);
+    }
+
+    #[test]
+    fn test_velocity() {
+        let mut synth = Synth::new(48000.0, 64);
+        synth.set_velocity_curve(VelocityCurve::Breakpoints(vec![(0.0, 0.0), (1.0, 0.5)]));
+        synth.set_mod_matrix(ModMatrix { routes: vec![ModRoute { source: ModSource::Velocity, destination: ParameterID::WT1Shape, amount: 1.0 }] });
+
+        synth.handle_message(&MidiMessage::NoteOn(0, 60, 127));
+        synth.handle_message(&MidiMessage::NoteOn(0, 64, 0x20));
+        synth.process();
+
+        let shape = |synth: &mut Synth, note: u8| {
+            let index = synth.voices.iter().position(|v| v.is_busy() && v.get_midi_note() == note).unwrap();
+            synth.voices[index].get_parameters().iter().find(|p| p.id == ParameterID::WT1Shape).unwrap().get_value()
+        };
+        assert_eq!(voice_for(&synth, 0, 60).get_mod_values().get(ModSource::Velocity), 0.5);
+        assert!(voice_for(&synth, 0, 60).get_level() > voice_for(&synth, 0, 64).get_level());
+
+        // Harder notes get a brighter shape, and keep it when the channel's controllers move
+        synth.handle_message(&MidiMessage::ModWheel(0, 100));
+        assert!(shape(&mut synth, 60) > shape(&mut synth, 64));
+    }
+}
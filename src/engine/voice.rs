@@ -1,17 +1,37 @@
+use std::sync::Arc;
 use std::time::Instant;
 use smallvec::{SmallVec, smallvec};
 use uuid::Uuid;
 use crate::dsp::biquad::{Biquad, BiquadShape};
 use crate::dsp::buffer::Buffer;
 use crate::modulators::adsr::ADSR;
+use crate::modulators::matrix::{ModMatrix, ModSource, ModValues};
 use crate::modulators::Modulator;
 use crate::sources::AudioSource;
 use crate::sources::tensions::Tensions;
 use crate::sources::waveshaper::WaveShaper;
 use crate::sources::wavetable::WaveTable;
 use crate::system::parameter::Parameter;
+use crate::system::tuning::Tuning;
 use crate::system::parameter::ParameterID::{KSAmount, WS1Amount, WT1Amount};
 
+// Pitch changes are applied in steps of this many samples
+const PITCH_STEP: usize = 32;
+// Portion of the remaining distance to the target pitch covered each step
+const PITCH_SMOOTHING: f32 = 0.25;
+// Fade-out of a voice that is still sounding when it gets a new note, in seconds
+const KILL_RAMP: f32 = 0.002;
+
+// A note waiting for the voice to fade out its previous one
+struct PendingNote {
+    channel: u8,
+    midi_note: u8,
+    velocity: f32,
+    pitch_offset: f32,
+    // Pitch and time to glide from once the note starts
+    glide: Option<(f32, f32)>
+}
+
 #[derive(Debug, PartialEq, Clone, Default)]
 pub struct VoiceData {
     pub sample_rate: f32,
@@ -30,10 +50,29 @@ pub struct Voice {
     data: VoiceData,
 
     levels: SmallVec<[Parameter; 16]>,
+    output: Buffer,
 
     midi_note: u8,
+    channel: u8,
+    // Semitones added to the note, from pitch bend. Moves towards the target while rendering.
+    pitch_offset: f32,
+    pitch_target: f32,
+    // Semitones from portamento, counts down to 0 by glide_step every pitch step
+    glide_offset: f32,
+    glide_step: f32,
+    mod_values: ModValues,
+    // Velocity after the curve, 0..1
+    velocity: f32,
+    tuning: Arc<Tuning>,
     is_busy: bool,
-    last_used: Instant
+    // Whether the key is still down, and whether the sostenuto pedal caught the note
+    key_down: bool,
+    sostenuto: bool,
+    last_used: Instant,
+
+    pending: Option<PendingNote>,
+    kill_length: usize,
+    kill_remaining: usize
 }
 
 impl Voice {
@@ -41,6 +80,7 @@ impl Voice {
         println!("Creating voice {} with sr {}, buffer size {}", id, data.sample_rate, data.block_size);
 
         let module_id = Uuid::new_v4();
+        let kill_length = ((KILL_RAMP * data.sample_rate) as usize).max(1);
 
         let sources: Vec<Box<dyn AudioSource + Send + Sync>> = vec![
             Box::new(WaveShaper::new(data.sample_rate, data.block_size, id)),
@@ -48,16 +88,12 @@ impl Voice {
             Box::new(WaveTable::new(data.sample_rate, data.block_size, id))
         ];
 
-        let mut levels: SmallVec<[Parameter; 16]> = smallvec![
+        let levels: SmallVec<[Parameter; 16]> = smallvec![
             Parameter::from_id(WS1Amount, module_id, id, data.sample_rate),
             Parameter::from_id(KSAmount, module_id, id, data.sample_rate),
             Parameter::from_id(WT1Amount, module_id, id, data.sample_rate)
         ];
 
-        for (i, p) in levels.iter_mut().enumerate() {
-            p.assign_cc(41 + i as u8);
-        }
-
         Voice {
             module_id,
 
@@ -67,72 +103,324 @@ impl Voice {
             envelope: ADSR::new(data.sample_rate, data.block_size, id),
 
             levels,
+            output: Buffer::new(data.block_size, "Voice".to_string()),
             
             id,
             data,
 
             midi_note: 0,
+            channel: 0,
+            pitch_offset: 0.0,
+            pitch_target: 0.0,
+            glide_offset: 0.0,
+            glide_step: 0.0,
+            mod_values: ModValues::default(),
+            velocity: 0.0,
+            tuning: Arc::new(Tuning::equal()),
             is_busy: false,
-            last_used: Instant::now()
+            key_down: false,
+            sostenuto: false,
+            last_used: Instant::now(),
+
+            pending: None,
+            kill_length,
+            kill_remaining: 0
         }
     }
 
-    pub fn process(&mut self) -> Buffer {
-        for source in &mut self.sources {
-            source.process();
+    pub fn process(&mut self) -> &Buffer {
+        self.render(0, self.data.block_size);
+        self.finish()
+    }
+
+    // Renders samples [start, end) of the current block. Call finish() once the whole block is rendered.
+    pub fn render(&mut self, start: usize, end: usize) {
+        let mut start = start;
+
+        if self.kill_remaining > 0 {
+            let ramp_end = (start + self.kill_remaining).min(end);
+            self.render_range(start, ramp_end);
+
+            for n in start..ramp_end {
+                self.output[n] *= self.kill_remaining as f32 / self.kill_length as f32;
+                self.kill_remaining -= 1;
+            }
+
+            // Faded out, the waiting note starts on the next sample
+            if self.kill_remaining == 0 {
+                self.envelope.silence();
+                if let Some(note) = self.pending.take() {
+                    self.start_note(note);
+                }
+            }
+            start = ramp_end;
+        }
+
+        if start < end {
+            self.render_range(start, end);
+        }
+    }
+
+    fn render_range(&mut self, start: usize, end: usize) {
+        let mut n = start;
+        while n < end {
+            let step_end = if self.pitch_offset == self.pitch_target && self.glide_offset == 0.0 {
+                end
+            } else {
+                self.step_pitch();
+                (n + PITCH_STEP).min(end)
+            };
+
+            for source in &mut self.sources {
+                source.process_range(n, step_end);
+            }
+            n = step_end;
         }
 
         for effect in &mut self.effects {
-            effect.process();
+            effect.process_range(start, end);
         }
 
-        self.envelope.process();
+        self.envelope.process_range(start, end);
 
-        let mut output = Buffer::new(self.data.block_size, "Voice".to_string());
         let envelope_buffer = self.envelope.get_buffer();
 
-        for (i, source) in self.sources.iter_mut().enumerate() {
+        for n in start..end {
+            self.output[n] = 0.0;
+        }
+
+        for (i, source) in self.sources.iter().enumerate() {
             let source_buffer = source.get_buffer();
-            output += envelope_buffer * source_buffer * self.levels[i].get_value();
+            let level = self.levels[i].get_value();
+
+            for n in start..end {
+                self.output[n] += envelope_buffer[n] * source_buffer[n] * level;
+            }
         }
-        
-        self.lpf.process_buffer(&mut output).unwrap();
+    }
+
+    pub fn finish(&mut self) -> &Buffer {
+        self.lpf.process_buffer(&mut self.output).unwrap();
 
-        output
+        &self.output
     }
 
-    pub fn note_on(&mut self, midi_note: u8, velocity: u8) {
-        // println!("[{}] NoteOn: {} {}", self.id, midi_note, velocity);
+    pub fn get_output(&self) -> &Buffer {
+        &self.output
+    }
 
-        self.envelope.reset();
-        self.envelope.start((velocity as f32 / 127.0).sqrt());
+    // A voice that is still sounding fades out first, so the new note starts without a click
+    pub fn note_on(&mut self, channel: u8, midi_note: u8, velocity: f32, pitch_offset: f32) {
+        // println!("[{}] NoteOn: {} {}", self.id, midi_note, velocity);
 
         self.last_used = Instant::now();
         self.is_busy = true;
-        self.midi_note = midi_note;
+        self.key_down = true;
+        self.sostenuto = false;
+        self.velocity = velocity;
+
+        let note = PendingNote { channel, midi_note, velocity, pitch_offset, glide: None };
+        if self.envelope.is_active() {
+            if self.kill_remaining == 0 {
+                self.kill_remaining = self.kill_length;
+            }
+            self.pending = Some(note);
+        } else {
+            self.start_note(note);
+        }
+    }
+
+    fn start_note(&mut self, note: PendingNote) {
+        self.envelope.reset();
+        self.envelope.start(note.velocity);
+
+        self.midi_note = note.midi_note;
+        self.channel = note.channel;
         
         // self.lpf.set_cutoff(mtof(midi_note as f32) * 2.0);
 
         for source in &mut self.sources {
-            source.set_pitch(midi_note);
+            source.set_pitch(note.midi_note, &self.tuning);
+        }
+
+        // A new note starts at its pitch right away
+        self.pitch_offset = note.pitch_offset;
+        self.pitch_target = note.pitch_offset;
+        self.glide_offset = 0.0;
+        if let Some((pitch, time)) = note.glide {
+            self.glide_from(pitch, time);
+        } else if note.pitch_offset != 0.0 {
+            self.apply_pitch();
+        }
+    }
+
+    // Moves a sounding voice to another note without retriggering the envelope
+    pub fn legato(&mut self, channel: u8, midi_note: u8, pitch_offset: f32) {
+        if let Some(note) = self.pending.as_mut() {
+            note.channel = channel;
+            note.midi_note = midi_note;
+            note.pitch_offset = pitch_offset;
+            return;
+        }
+
+        self.last_used = Instant::now();
+        self.midi_note = midi_note;
+        self.channel = channel;
+
+        self.pitch_offset = pitch_offset;
+        self.pitch_target = pitch_offset;
+        self.glide_offset = 0.0;
+        self.apply_pitch();
+    }
+
+    // Slides from the given pitch to the current note in a fixed time, however far apart they are
+    pub fn glide_from(&mut self, pitch: f32, time: f32) {
+        if let Some(note) = self.pending.as_mut() {
+            note.glide = Some((pitch, time));
+            return;
+        }
+
+        self.glide_offset = pitch - (self.midi_note as f32 + self.pitch_offset);
+
+        let steps = (time * self.data.sample_rate / PITCH_STEP as f32).max(1.0);
+        self.glide_step = self.glide_offset.abs() / steps;
+        self.apply_pitch();
+    }
+
+    // Current pitch as a fractional MIDI note
+    pub fn get_pitch(&self) -> f32 {
+        if let Some(note) = self.pending.as_ref() {
+            return note.glide.map(|g| g.0).unwrap_or(note.midi_note as f32 + note.pitch_offset);
+        }
+
+        self.midi_note as f32 + self.pitch_offset + self.glide_offset
+    }
+
+    // Glides to the new offset over the next few milliseconds, so bends don't zipper
+    pub fn set_pitch_offset(&mut self, semitones: f32) {
+        match self.pending.as_mut() {
+            Some(note) => note.pitch_offset = semitones,
+            None => self.pitch_target = semitones
+        }
+    }
+
+    fn step_pitch(&mut self) {
+        self.pitch_offset += (self.pitch_target - self.pitch_offset) * PITCH_SMOOTHING;
+        if (self.pitch_target - self.pitch_offset).abs() < 0.001 {
+            self.pitch_offset = self.pitch_target;
+        }
+
+        if self.glide_offset.abs() <= self.glide_step {
+            self.glide_offset = 0.0;
+        } else {
+            self.glide_offset -= self.glide_step * self.glide_offset.signum();
+        }
+
+        self.apply_pitch();
+    }
+
+    fn apply_pitch(&mut self) {
+        let note = self.get_pitch();
+        for source in &mut self.sources {
+            let frequency = source.note_frequency(note, &self.tuning);
+            source.set_frequency(frequency);
+        }
+    }
+
+    // A sounding note moves to its pitch in the new tuning straight away
+    pub fn set_tuning(&mut self, tuning: Arc<Tuning>) {
+        self.tuning = tuning;
+        if self.is_sounding() && self.pending.is_none() {
+            self.apply_pitch();
+        }
+    }
+
+    // Velocity comes from the voice itself, whatever the channel's values say
+    pub fn set_modulation(&mut self, mut values: ModValues, matrix: &ModMatrix) {
+        values.set(ModSource::Velocity, self.velocity);
+        self.mod_values = values;
+
+        for parameter in self.get_parameters_mut() {
+            parameter.set_modulation(matrix.amount_for(parameter.id, &values));
+        }
+        for source in &mut self.sources {
+            source.refresh();
         }
     }
 
     pub fn note_off(&mut self) {
         // println!("Voice {} received NoteOff for midi note {}", self.id, self.midi_note);
 
-        self.envelope.stop();
+        // A note released before it could start is dropped, the fade-out carries on
+        if self.pending.take().is_none() {
+            self.envelope.stop();
+        }
+        self.is_busy = false;
+        self.key_down = false;
+        self.sostenuto = false;
+    }
+
+    // The note keeps playing after the key goes up while a pedal holds it
+    pub fn key_up(&mut self, sustain: bool) {
+        self.key_down = false;
+        self.pedal_up(sustain);
+    }
+
+    // Releases the note if neither its key nor a pedal holds it any more
+    pub fn pedal_up(&mut self, sustain: bool) {
+        if self.is_busy && !self.key_down && !self.sostenuto && !sustain {
+            self.note_off();
+        }
+    }
+
+    // Pressing sostenuto catches the notes whose key is down at that moment
+    pub fn set_sostenuto(&mut self, sostenuto: bool, sustain: bool) {
+        if sostenuto {
+            self.sostenuto = self.is_busy && self.key_down;
+        } else {
+            self.sostenuto = false;
+            self.pedal_up(sustain);
+        }
+    }
+
+    // Cuts the voice off with a short fade
+    pub fn kill(&mut self) {
+        self.pending = None;
         self.is_busy = false;
+        self.key_down = false;
+        self.sostenuto = false;
+        if self.envelope.is_active() && self.kill_remaining == 0 {
+            self.kill_remaining = self.kill_length;
+        }
     }
 
     pub fn is_busy(&self) -> bool {
         self.is_busy
     }
+    pub fn is_key_down(&self) -> bool {
+        self.key_down
+    }
+    // Held, or still releasing
+    pub fn is_sounding(&self) -> bool {
+        self.envelope.is_active()
+    }
+    pub fn get_level(&self) -> f32 {
+        self.envelope.get()
+    }
     pub fn last_used(&self) -> Instant {
         self.last_used
     }
     pub fn get_midi_note(&self) -> u8 {
-        self.midi_note
+        self.pending.as_ref().map(|n| n.midi_note).unwrap_or(self.midi_note)
+    }
+    pub fn get_channel(&self) -> u8 {
+        self.pending.as_ref().map(|n| n.channel).unwrap_or(self.channel)
+    }
+    pub fn get_pitch_offset(&self) -> f32 {
+        self.pending.as_ref().map(|n| n.pitch_offset).unwrap_or(self.pitch_target)
+    }
+    pub fn get_mod_values(&self) -> ModValues {
+        self.mod_values
     }
 
     pub fn set_block_size(&mut self, block_size: usize) {
@@ -142,6 +430,7 @@ impl Voice {
         }
 
         self.envelope.set_block_size(block_size);
+        self.output = Buffer::new(block_size, "Voice".to_string());
     }
 
     pub fn get_parameters(&mut self) -> SmallVec<[&Parameter; 64]> {
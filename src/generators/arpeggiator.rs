@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+use crate::engine::midi::MidiMessage;
+use super::{Generator, Note, Rate};
+
+pub const MAX_OCTAVES: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ArpPattern {
+    Up,
+    Down,
+    UpDown,
+    Random,
+    AsPlayed
+}
+
+impl ArpPattern {
+    pub fn all() -> &'static [ArpPattern] {
+        &[ArpPattern::Up, ArpPattern::Down, ArpPattern::UpDown, ArpPattern::Random, ArpPattern::AsPlayed]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ArpSettings {
+    pub enabled: bool,
+    pub pattern: ArpPattern,
+    // How many octaves the held notes are repeated over, 1 plays them as held
+    pub octaves: u8,
+    // Portion of a step the note sounds for
+    pub gate: f32,
+    pub rate: Rate
+}
+
+impl Default for ArpSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            pattern: ArpPattern::Up,
+            octaves: 1,
+            gate: 0.5,
+            rate: Rate::Eighth
+        }
+    }
+}
+
+pub struct Arpeggiator {
+    settings: ArpSettings,
+    // Channel, pitch and velocity of the keys being held, in the order they were pressed
+    held: Vec<(u8, u8, f32)>,
+    step: usize,
+    // Clock position the next step plays at
+    next_step: usize,
+    last_position: usize,
+    // Channel and pitch of the note that is playing and the position it ends at
+    sounding: Option<(u8, u8, usize)>,
+    note_offs: Vec<(u8, u8)>,
+    seed: u32
+}
+
+impl Arpeggiator {
+    pub fn new() -> Self {
+        Self {
+            settings: ArpSettings::default(),
+            held: vec![],
+            step: 0,
+            next_step: 0,
+            last_position: 0,
+            sounding: None,
+            note_offs: vec![],
+            seed: 0x2545_f491
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: ArpSettings) {
+        if !settings.enabled {
+            self.held.clear();
+            self.release();
+        }
+
+        self.settings = ArpSettings {
+            octaves: settings.octaves.clamp(1, MAX_OCTAVES),
+            gate: settings.gate.clamp(0.05, 1.0),
+            ..settings
+        };
+    }
+
+    fn release(&mut self) {
+        if let Some((channel, pitch, _)) = self.sounding.take() {
+            self.note_offs.push((channel, pitch));
+        }
+    }
+
+    // The held notes laid out over the octave range, in the order the pattern walks them.
+    // Each note stays on the channel its key was played on.
+    fn sequence(&self) -> Vec<(u8, u8, f32)> {
+        let mut notes = self.held.clone();
+        if self.settings.pattern != ArpPattern::AsPlayed {
+            notes.sort_by_key(|(_, pitch, _)| *pitch);
+        }
+
+        let mut sequence: Vec<(u8, u8, f32)> = (0..self.settings.octaves)
+            .flat_map(|octave| notes.iter().map(move |(channel, pitch, velocity)| (*channel, pitch + 12 * octave, *velocity)))
+            .filter(|(_, pitch, _)| *pitch < 128)
+            .collect();
+
+        match self.settings.pattern {
+            ArpPattern::Down => sequence.reverse(),
+            // Don't repeat the top and bottom notes on the turn
+            ArpPattern::UpDown if sequence.len() > 2 => {
+                let down: Vec<_> = sequence[1..sequence.len() - 1].iter().rev().cloned().collect();
+                sequence.extend(down);
+            },
+            _ => {}
+        }
+
+        sequence
+    }
+
+    fn random(&mut self) -> u32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed
+    }
+}
+
+impl Generator for Arpeggiator {
+    fn clear(&mut self) {
+        self.held.clear();
+        self.step = 0;
+        self.release();
+    }
+
+    // Keys still held go on playing from the first step when the clock starts again
+    fn stop(&mut self) {
+        self.step = 0;
+        self.next_step = 0;
+        self.release();
+    }
+
+    fn handle_message(&mut self, message: &MidiMessage) -> bool {
+        if !self.settings.enabled {
+            return false;
+        }
+
+        match *message {
+            MidiMessage::NoteOn(channel, pitch, velocity) if velocity > 0 => {
+                if self.held.is_empty() {
+                    // Start over from the first step, right away
+                    self.step = 0;
+                    self.next_step = 0;
+                }
+                self.held.retain(|(c, p, _)| (*c, *p) != (channel, pitch));
+                self.held.push((channel, pitch, velocity as f32 / 127.0));
+                true
+            },
+            // Keys pressed before the arpeggiator took over still reach the synth
+            MidiMessage::NoteOn(channel, pitch, _) | MidiMessage::NoteOff(channel, pitch, _) => {
+                let held = self.held.len();
+                self.held.retain(|(c, p, _)| (*c, *p) != (channel, pitch));
+                self.held.len() != held
+            },
+            _ => false
+        }
+    }
+
+    fn get_note_offs(&mut self) -> Vec<(u8, u8)> {
+        std::mem::take(&mut self.note_offs)
+    }
+
+    fn pulse(&mut self, position: usize) -> Option<Note> {
+        // The clock looped or was moved back
+        if position < self.last_position {
+            self.next_step = 0;
+            self.release();
+        }
+        self.last_position = position;
+
+        if let Some((_, _, end)) = self.sounding {
+            if position >= end {
+                self.release();
+            }
+        }
+
+        if !self.settings.enabled || self.held.is_empty() || position < self.next_step {
+            return None;
+        }
+
+        let ticks = self.settings.rate.ticks();
+        self.next_step = (position / ticks + 1) * ticks;
+
+        let sequence = self.sequence();
+        let index = match self.settings.pattern {
+            ArpPattern::Random => self.random() as usize % sequence.len(),
+            _ => self.step % sequence.len()
+        };
+        self.step = index + 1;
+
+        let (channel, pitch, velocity) = sequence[index];
+        let duration = ((ticks as f32 * self.settings.gate) as usize).max(1);
+
+        // A gate that runs into this step ends here
+        self.release();
+        self.sounding = Some((channel, pitch, position + duration));
+
+        Some(Note {
+            channel,
+            pitch,
+            velocity,
+            start: position,
+            duration
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(arp: &mut Arpeggiator, positions: std::ops::Range<usize>) -> Vec<u8> {
+        positions.filter_map(|position| arp.pulse(position)).map(|note| note.pitch).collect()
+    }
+
+    #[test]
+    fn test_arpeggiator() {
+        let mut arp = Arpeggiator::new();
+        assert!(!arp.handle_message(&MidiMessage::NoteOn(0, 60, 100)));
+
+        let settings = ArpSettings { enabled: true, pattern: ArpPattern::UpDown, octaves: 2, rate: Rate::Sixteenth, ..Default::default() };
+        arp.set_settings(settings);
+        for pitch in [64, 60, 67] {
+            assert!(arp.handle_message(&MidiMessage::NoteOn(0, pitch, 100)));
+        }
+
+        // One note every twelve ticks, up over two octaves and back down
+        let step = Rate::Sixteenth.ticks();
+        assert_eq!(play(&mut arp, 0..step * 8 + 1), vec![60, 64, 67, 72, 76, 79, 76, 72, 67]);
+
+        // Half gate, the last note is released halfway through its step
+        assert_eq!(arp.get_note_offs().len(), 8);
+        arp.pulse(step * 8 + step / 2);
+        assert_eq!(arp.get_note_offs(), vec![(0, 67)]);
+
+        arp.set_settings(ArpSettings { pattern: ArpPattern::AsPlayed, octaves: 1, rate: Rate::EighthTriplet, ..settings });
+        assert!(arp.handle_message(&MidiMessage::NoteOff(0, 60, 0)));
+        assert!(!arp.handle_message(&MidiMessage::NoteOff(0, 48, 0)));
+        let start = step * 12;
+        let notes = play(&mut arp, start..start + Rate::Quarter.ticks());
+        assert_eq!(notes.len(), 3);
+        assert!(notes.iter().all(|pitch| [64, 67].contains(pitch)));
+
+        // Notes go out on the channel their key came in on
+        arp.clear();
+        assert!(arp.handle_message(&MidiMessage::NoteOn(5, 62, 100)));
+        let note = arp.pulse(start + Rate::Quarter.ticks()).unwrap();
+        assert_eq!((note.channel, note.pitch), (5, 62));
+
+        arp.stop();
+        assert_eq!(arp.get_note_offs().last(), Some(&(5, 62)));
+        assert_eq!(arp.pulse(0).map(|note| note.pitch), Some(62));
+        arp.clear();
+        assert_eq!(arp.get_note_offs().last(), Some(&(5, 62)));
+    }
+}
@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use super::{Generator, GeneratorSettings, Note, Rate};
+
+pub const MAX_STEPS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EuclideanSettings {
+    pub steps: usize,
+    // Hits spread as evenly as possible over the steps
+    pub pulses: usize,
+    // Steps the pattern is shifted to the right
+    pub rotation: usize,
+    pub rate: Rate,
+    // Each hit plays the next pitch, the list can be of a different length than the pattern
+    pub pitches: Vec<u8>,
+    pub velocity: f32,
+    pub gate: f32
+}
+
+impl Default for EuclideanSettings {
+    fn default() -> Self {
+        Self {
+            steps: 8,
+            pulses: 3,
+            rotation: 0,
+            rate: Rate::Sixteenth,
+            pitches: vec![36],
+            velocity: 1.0,
+            gate: 0.5
+        }
+    }
+}
+
+impl EuclideanSettings {
+    pub fn rhythm(&self) -> Vec<bool> {
+        let steps = self.steps.clamp(1, MAX_STEPS);
+        let pulses = self.pulses.min(steps);
+
+        // Step i is a hit when the running total of pulses/steps crosses a whole number
+        let hits: Vec<bool> = (0..steps).map(|i| (i * pulses) % steps < pulses).collect();
+        (0..steps).map(|i| hits[(i + steps - self.rotation % steps) % steps]).collect()
+    }
+}
+
+pub struct Euclidean {
+    settings: EuclideanSettings,
+    rhythm: Vec<bool>,
+    pitch_index: usize,
+
+    last_step: Option<usize>,
+    last_position: usize,
+    // Pitch of the note that is playing and the position it ends at
+    sounding: Option<(u8, usize)>,
+    note_offs: Vec<(u8, u8)>
+}
+
+impl Euclidean {
+    pub fn new(settings: EuclideanSettings) -> Self {
+        Self {
+            rhythm: settings.rhythm(),
+            settings,
+            pitch_index: 0,
+            last_step: None,
+            last_position: 0,
+            sounding: None,
+            note_offs: vec![]
+        }
+    }
+
+    fn release(&mut self) {
+        if let Some((pitch, _)) = self.sounding.take() {
+            self.note_offs.push((0, pitch));
+        }
+    }
+}
+
+impl Generator for Euclidean {
+    fn clear(&mut self) {
+        self.pitch_index = 0;
+        self.last_step = None;
+        self.release();
+    }
+
+    fn stop(&mut self) {
+        self.clear();
+    }
+
+    fn configure(&mut self, settings: &GeneratorSettings) -> bool {
+        let GeneratorSettings::Euclidean(settings) = settings else {
+            return false;
+        };
+        self.rhythm = settings.rhythm();
+        self.settings = settings.clone();
+        true
+    }
+
+    fn get_note_offs(&mut self) -> Vec<(u8, u8)> {
+        std::mem::take(&mut self.note_offs)
+    }
+
+    fn pulse(&mut self, position: usize) -> Option<Note> {
+        // The clock looped or was moved back
+        if position < self.last_position {
+            self.last_step = None;
+        }
+        self.last_position = position;
+
+        if let Some((_, end)) = self.sounding {
+            if position >= end {
+                self.release();
+            }
+        }
+
+        // Counted from the start of the clock, so generators of different lengths stay in phase
+        let ticks = self.settings.rate.ticks();
+        let step_count = position / ticks;
+        if self.last_step == Some(step_count) {
+            return None;
+        }
+        self.last_step = Some(step_count);
+
+        if !self.rhythm[step_count % self.rhythm.len()] || self.settings.pitches.is_empty() {
+            return None;
+        }
+
+        let pitch = self.settings.pitches[self.pitch_index % self.settings.pitches.len()].min(127);
+        self.pitch_index += 1;
+        let duration = ((ticks as f32 * self.settings.gate) as usize).max(1);
+
+        self.release();
+        self.sounding = Some((pitch, position + duration));
+
+        Some(Note {
+            channel: 0,
+            pitch,
+            velocity: self.settings.velocity,
+            start: step_count * ticks,
+            duration
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(steps: usize, pulses: usize, rotation: usize) -> String {
+        EuclideanSettings { steps, pulses, rotation, ..Default::default() }.rhythm().iter()
+            .map(|hit| if *hit { 'x' } else { '.' })
+            .collect()
+    }
+
+    #[test]
+    fn test_euclidean() {
+        assert_eq!(pattern(8, 3, 0), "x..x..x.");
+        assert_eq!(pattern(8, 3, 2), "x.x..x..");
+        assert_eq!(pattern(5, 2, 0), "x..x.");
+        assert_eq!(pattern(4, 0, 0), "....");
+        assert_eq!(pattern(4, 9, 0), "xxxx");
+
+        // Two pitches over three hits, the melody shifts against the rhythm
+        let settings = EuclideanSettings { pitches: vec![36, 38], ..Default::default() };
+        let ticks = settings.rate.ticks();
+        let mut euclidean = Euclidean::new(settings);
+        let notes: Vec<(usize, u8)> = (0..ticks * 16)
+            .filter_map(|position| euclidean.pulse(position))
+            .map(|note| (note.start / ticks, note.pitch))
+            .collect();
+        assert_eq!(notes, vec![(0, 36), (3, 38), (6, 36), (8, 38), (11, 36), (14, 38)]);
+    }
+}
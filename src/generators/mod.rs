@@ -1,30 +1,120 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
 use crate::engine::clock::PPQ;
+use crate::engine::midi::MidiMessage;
+use crate::engine::midi_file::MidiFile;
 
 pub mod sequencer;
+pub mod arpeggiator;
+pub mod euclidean;
+pub mod player;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Note {
+    pub channel: u8,
     pub pitch: u8,
     pub velocity: f32,
     pub start: usize,
     pub duration: usize,
 }
 
-impl Note {
-    pub fn quarter(pitch: u8) -> Self {
-        Note {
-            pitch,
-            velocity: 1.0,
-            start: 0,
-            duration: PPQ
+// Note length generators step at
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Rate {
+    Quarter,
+    Eighth,
+    Sixteenth,
+    QuarterTriplet,
+    EighthTriplet,
+    SixteenthTriplet
+}
+
+impl Rate {
+    pub fn all() -> &'static [Rate] {
+        &[Rate::Quarter, Rate::Eighth, Rate::Sixteenth, Rate::QuarterTriplet, Rate::EighthTriplet, Rate::SixteenthTriplet]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rate::Quarter => "1/4",
+            Rate::Eighth => "1/8",
+            Rate::Sixteenth => "1/16",
+            Rate::QuarterTriplet => "1/4T",
+            Rate::EighthTriplet => "1/8T",
+            Rate::SixteenthTriplet => "1/16T"
+        }
+    }
+
+    // Clock ticks between two steps
+    pub fn ticks(&self) -> usize {
+        match self {
+            Rate::Quarter => PPQ,
+            Rate::Eighth => PPQ / 2,
+            Rate::Sixteenth => PPQ / 4,
+            Rate::QuarterTriplet => PPQ * 2 / 3,
+            Rate::EighthTriplet => PPQ / 3,
+            Rate::SixteenthTriplet => PPQ / 6
         }
     }
 }
 
+// Generators that can be added to the clock while it runs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum GeneratorSettings {
+    Euclidean(euclidean::EuclideanSettings),
+    // Path to a Standard MIDI File
+    MidiFile(String)
+}
+
+impl GeneratorSettings {
+    // Reads the file the generator plays, so the engine can build it without going to disk
+    pub fn load(&self) -> Result<Option<MidiFile>> {
+        Ok(match self {
+            GeneratorSettings::MidiFile(path) => Some(MidiFile::load(path)?),
+            _ => None
+        })
+    }
+
+    pub fn build(&self, file: Option<&MidiFile>) -> Result<Box<dyn Generator + Send + Sync>> {
+        Ok(match (self, file) {
+            (GeneratorSettings::Euclidean(settings), _) => Box::new(euclidean::Euclidean::new(settings.clone())),
+            (GeneratorSettings::MidiFile(_), Some(file)) => Box::new(player::MidiFilePlayer::new(file)),
+            (GeneratorSettings::MidiFile(path), None) => bail!("{} wasn't loaded", path)
+        })
+    }
+}
+
 pub trait Generator {
     fn clear(&mut self);
 
-    fn get_note_offs(&self) -> Vec<u8> {
+    // Playback stopped, whatever is still sounding goes out with the next note offs
+    fn stop(&mut self) {}
+
+    // Takes new settings without starting over, false when they're meant for another kind of generator
+    fn configure(&mut self, _settings: &GeneratorSettings) -> bool {
+        false
+    }
+
+    // The clock jumped, e.g. back to the start of the loop. The next pulse continues from here.
+    fn locate(&mut self, _position: usize) {}
+
+    // Messages to send besides the notes, on their own channels
+    fn get_messages(&mut self) -> Vec<MidiMessage> {
+        vec![]
+    }
+
+    // A tempo for the clock to follow, each change is only handed out once
+    fn get_tempo(&mut self) -> Option<f32> {
+        None
+    }
+
+    // Gets to see the notes being played, returns true when it takes the message away from the synth
+    fn handle_message(&mut self, _message: &MidiMessage) -> bool {
+        false
+    }
+
+    // Channel and pitch of the notes to release, each one is only handed out once
+    fn get_note_offs(&mut self) -> Vec<(u8, u8)> {
         vec![]
     }
 
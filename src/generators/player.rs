@@ -0,0 +1,165 @@
+use crate::engine::clock::PPQ;
+use crate::engine::midi::MidiMessage;
+use crate::engine::midi_file::{MidiFile, MidiFileEvent, DEFAULT_TEMPO};
+use super::{Generator, Note};
+
+// Plays a Standard MIDI File against the clock, on the channels it was written for
+pub struct MidiFilePlayer {
+    // Messages and tempo changes on the clock's ticks, in order
+    events: Vec<(usize, MidiFileEvent)>,
+    next: usize,
+    last_position: usize,
+    // Channel and pitch of the notes that were started and not stopped yet
+    sounding: Vec<(u8, u8)>,
+    messages: Vec<MidiMessage>,
+    tempo: Option<f32>
+}
+
+impl MidiFilePlayer {
+    pub fn new(file: &MidiFile) -> Self {
+        let ppq = file.ppq.max(1) as u64;
+        let events = file.merged().into_iter()
+            .filter(|e| matches!(e.event, MidiFileEvent::Message(_) | MidiFileEvent::Tempo(_)))
+            .map(|e| (((e.tick * PPQ as u64 + ppq / 2) / ppq) as usize, e.event))
+            .collect();
+
+        Self {
+            events,
+            next: 0,
+            last_position: 0,
+            sounding: vec![],
+            messages: vec![],
+            tempo: None
+        }
+    }
+
+    // Tempo in effect at the given position, in bpm
+    fn tempo_at(&self, position: usize) -> f32 {
+        let tempo = self.events.iter()
+            .take_while(|(tick, _)| *tick <= position)
+            .filter_map(|(_, event)| match event {
+                MidiFileEvent::Tempo(tempo) => Some(*tempo),
+                _ => None
+            })
+            .last()
+            .unwrap_or(DEFAULT_TEMPO);
+
+        60_000_000.0 / tempo.max(1) as f32
+    }
+
+    fn release(&mut self) {
+        for (channel, pitch) in self.sounding.drain(..) {
+            self.messages.push(MidiMessage::NoteOff(channel, pitch, 0));
+        }
+    }
+}
+
+impl Generator for MidiFilePlayer {
+    fn clear(&mut self) {
+        self.locate(0);
+    }
+
+    fn stop(&mut self) {
+        self.release();
+    }
+
+    fn locate(&mut self, position: usize) {
+        self.release();
+        self.next = self.events.partition_point(|(tick, _)| *tick < position);
+        self.last_position = position;
+        self.tempo = Some(self.tempo_at(position));
+    }
+
+    fn get_messages(&mut self) -> Vec<MidiMessage> {
+        std::mem::take(&mut self.messages)
+    }
+
+    fn get_tempo(&mut self) -> Option<f32> {
+        self.tempo.take()
+    }
+
+    fn pulse(&mut self, position: usize) -> Option<Note> {
+        // Moved back without the clock telling us
+        if position < self.last_position {
+            self.locate(position);
+        }
+        self.last_position = position;
+
+        while self.next < self.events.len() && self.events[self.next].0 <= position {
+            match self.events[self.next].1.clone() {
+                MidiFileEvent::Tempo(tempo) => {
+                    self.tempo = Some(60_000_000.0 / tempo.max(1) as f32);
+                },
+                MidiFileEvent::Message(message) => {
+                    match message {
+                        MidiMessage::NoteOn(channel, pitch, velocity) if velocity > 0 => {
+                            self.sounding.push((channel, pitch));
+                        },
+                        MidiMessage::NoteOn(channel, pitch, _) | MidiMessage::NoteOff(channel, pitch, _) => {
+                            self.sounding.retain(|note| *note != (channel, pitch));
+                        },
+                        _ => {}
+                    }
+                    self.messages.push(message);
+                },
+                _ => {}
+            }
+            self.next += 1;
+        }
+
+        // Everything goes out as messages, so channels and controllers survive
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::midi_file::{MidiFileTrack, TrackEvent};
+
+    #[test]
+    fn test_midi_file_player() {
+        let event = |tick: u64, event: MidiFileEvent| TrackEvent { tick, event };
+        let file = MidiFile {
+            format: 1,
+            ppq: 96,
+            tracks: vec![
+                MidiFileTrack { events: vec![
+                    event(0, MidiFileEvent::Tempo(600_000)),
+                    event(192, MidiFileEvent::Tempo(400_000))
+                ] },
+                MidiFileTrack { events: vec![
+                    event(0, MidiFileEvent::Message(MidiMessage::NoteOn(2, 60, 100))),
+                    event(48, MidiFileEvent::Message(MidiMessage::MidiCC(2, 7, 90))),
+                    event(96, MidiFileEvent::Message(MidiMessage::NoteOff(2, 60, 0))),
+                    event(192, MidiFileEvent::Message(MidiMessage::NoteOn(2, 64, 100)))
+                ] }
+            ]
+        };
+
+        let mut player = MidiFilePlayer::new(&file);
+        player.locate(0);
+        assert_eq!(player.get_tempo(), Some(100.0));
+
+        // 96 ticks per quarter in the file, PPQ on the clock
+        let mut messages = vec![];
+        for position in 0..PPQ * 2 + 1 {
+            player.pulse(position);
+            messages.extend(player.get_messages().into_iter().map(|m| (position, m)));
+        }
+        assert_eq!(messages, vec![
+            (0, MidiMessage::NoteOn(2, 60, 100)),
+            (PPQ / 2, MidiMessage::MidiCC(2, 7, 90)),
+            (PPQ, MidiMessage::NoteOff(2, 60, 0)),
+            (PPQ * 2, MidiMessage::NoteOn(2, 64, 100))
+        ]);
+        assert_eq!(player.get_tempo(), Some(150.0));
+
+        // Back to the loop start, the held note is let go and the tempo is back to the first one
+        player.locate(0);
+        assert_eq!(player.get_messages(), vec![MidiMessage::NoteOff(2, 64, 0)]);
+        assert_eq!(player.get_tempo(), Some(100.0));
+        player.pulse(0);
+        assert_eq!(player.get_messages(), vec![MidiMessage::NoteOn(2, 60, 100)]);
+    }
+}
@@ -1,54 +1,248 @@
-use super::{Generator, Note};
+use serde::{Deserialize, Serialize};
+use super::{Generator, Note, Rate};
 
-pub struct Sequencer {
-    pub is_playing: bool,
-    pub position: usize,
-    pub index: usize,
+pub const MAX_STEPS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    pub pitch: u8,
+    pub velocity: f32,
+    // Portion of the step the note sounds for
+    pub gate: f32,
+    // Keeps the previous note sounding through this step instead of playing a new one
+    pub tie: bool,
+    pub rest: bool
+}
+
+impl Step {
+    pub fn new(pitch: u8) -> Self {
+        Self {
+            pitch,
+            velocity: 1.0,
+            gate: 1.0,
+            tie: false,
+            rest: false
+        }
+    }
+
+    pub fn rest() -> Self {
+        Self {
+            rest: true,
+            ..Self::new(60)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pattern {
+    pub steps: Vec<Step>,
+    pub rate: Rate
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Self {
+            steps: vec![Step::new(60), Step::new(62), Step::new(64), Step::new(65)],
+            rate: Rate::Quarter
+        }
+    }
+}
 
-    pub pattern: Vec<Note>,
+impl Pattern {
+    // New steps are rests
+    pub fn set_length(&mut self, length: usize) {
+        self.steps.resize(length.clamp(1, MAX_STEPS), Step::rest());
+    }
+
+    pub fn set_step(&mut self, index: usize, step: Step) {
+        if let Some(s) = self.steps.get_mut(index) {
+            *s = Step {
+                pitch: step.pitch.min(127),
+                velocity: step.velocity.clamp(0.0, 1.0),
+                gate: step.gate.clamp(0.05, 1.0),
+                ..step
+            };
+        }
+    }
+}
+
+pub struct Sequencer {
+    pub enabled: bool,
+    pattern: Pattern,
 
-    pub held_notes: Vec<u8>
+    // Steps counted from the start of the clock, so a change in rate or length picks up in time
+    last_step: Option<usize>,
+    last_position: usize,
+    // Notes that are playing and the position they end at
+    sounding: Vec<(u8, usize)>,
+    note_offs: Vec<(u8, u8)>
 }
 
 impl Sequencer {
     pub fn new() -> Self {
         Sequencer {
-            is_playing: false,
-            position: 0,
-            index: 0,
-            pattern: vec![
-                Note::quarter(60),
-                Note::quarter(62),
-                Note::quarter(64),
-                Note::quarter(65),
-            ],
-            held_notes: vec![]
+            enabled: true,
+            pattern: Pattern::default(),
+            last_step: None,
+            last_position: 0,
+            sounding: vec![],
+            note_offs: vec![]
         }
     }
+
+    pub fn get_pattern(&self) -> &Pattern {
+        &self.pattern
+    }
+
+    pub fn set_pattern(&mut self, pattern: Pattern) {
+        self.release(usize::MAX);
+        self.pattern = pattern;
+        self.last_step = None;
+    }
+
+    pub fn set_step(&mut self, index: usize, step: Step) {
+        self.pattern.set_step(index, step);
+    }
+
+    pub fn set_length(&mut self, length: usize) {
+        self.pattern.set_length(length);
+    }
+
+    pub fn set_rate(&mut self, rate: Rate) {
+        self.pattern.rate = rate;
+        self.last_step = None;
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.release(usize::MAX);
+        }
+        self.enabled = enabled;
+    }
+
+    fn play_step(&mut self, step_count: usize, ticks: usize) -> Option<Note> {
+        let steps = &self.pattern.steps;
+        let step = steps[step_count % steps.len()];
+        let next = steps[(step_count + 1) % steps.len()];
+
+        // A tie on the next step holds the note for the whole step
+        let gate = if next.tie && !next.rest { 1.0 } else { step.gate };
+        let start = step_count * ticks;
+        let duration = ((ticks as f32 * gate) as usize).max(1);
+
+        if step.rest {
+            return None;
+        }
+
+        // Without a note to hold on to, a tie plays its own pitch
+        if step.tie {
+            if let Some((_, end)) = self.sounding.last_mut() {
+                *end = start + duration;
+                return None;
+            }
+        }
+
+        // Whatever is left of the previous note ends where this one starts
+        self.release(usize::MAX);
+
+        let note = Note {
+            channel: 0,
+            pitch: step.pitch,
+            velocity: step.velocity,
+            start,
+            duration
+        };
+        self.sounding.push((note.pitch, note.start + note.duration));
+
+        Some(note)
+    }
+
+    // Lets go of the notes that have ended by the given position
+    fn release(&mut self, position: usize) {
+        let note_offs = &mut self.note_offs;
+        self.sounding.retain(|&(pitch, end)| {
+            if end <= position {
+                note_offs.push((0, pitch));
+            }
+            end > position
+        });
+    }
 }
 
 impl Generator for Sequencer {
-    fn quarter(&mut self) -> Option<Note> {
-        // println!("Sequencer quarter note");
-        
-        let old_idx = self.index;
-        self.index = (self.index + 1) % self.pattern.len();
+    fn clear(&mut self) {
+        self.pattern = Pattern::default();
+        self.last_step = None;
+        self.release(usize::MAX);
+    }
 
-        self.held_notes.push(self.pattern[old_idx].pitch);
+    fn stop(&mut self) {
+        self.release(usize::MAX);
+        self.last_step = None;
+    }
 
-        Some(self.pattern[self.index])
+    fn get_note_offs(&mut self) -> Vec<(u8, u8)> {
+        std::mem::take(&mut self.note_offs)
     }
 
-    fn get_note_offs(&self) -> Vec<u8> {
-        self.held_notes.clone()
+    fn pulse(&mut self, position: usize) -> Option<Note> {
+        // The clock looped or was moved back
+        if position < self.last_position {
+            self.release(usize::MAX);
+            self.last_step = None;
+        }
+        self.last_position = position;
+
+        let ticks = self.pattern.rate.ticks();
+        let step_count = position / ticks;
+        let note = if self.enabled && self.last_step != Some(step_count) {
+            self.play_step(step_count, ticks)
+        } else {
+            None
+        };
+        self.last_step = Some(step_count);
+
+        self.release(position);
+        note
     }
+}
 
-    fn clear(&mut self) {
-        self.pattern = vec![
-            Note::quarter(60),
-            Note::quarter(62),
-            Note::quarter(64),
-            Note::quarter(65),
-        ];
-    }
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequencer() {
+        let mut sequencer = Sequencer::new();
+        sequencer.set_rate(Rate::Sixteenth);
+        sequencer.set_length(4);
+        sequencer.set_step(0, Step { gate: 0.5, ..Step::new(48) });
+        sequencer.set_step(1, Step { gate: 0.25, ..Step::new(50) });
+        sequencer.set_step(2, Step { tie: true, gate: 0.5, ..Step::new(0) });
+        sequencer.set_step(3, Step::rest());
+
+        let ticks = Rate::Sixteenth.ticks();
+        let mut events = vec![];
+        for position in 0..ticks * 4 + 1 {
+            if let Some(note) = sequencer.pulse(position) {
+                events.push((position, format!("on {}", note.pitch)));
+            }
+            for (_, pitch) in sequencer.get_note_offs() {
+                events.push((position, format!("off {}", pitch)));
+            }
+        }
+
+        // Half a step, then a quarter step stretched by the tie, nothing on the rest
+        assert_eq!(events, vec![
+            (0, "on 48".to_string()),
+            (ticks / 2, "off 48".to_string()),
+            (ticks, "on 50".to_string()),
+            (ticks * 2 + ticks / 2, "off 50".to_string()),
+            (ticks * 4, "on 48".to_string())
+        ]);
+
+        sequencer.set_enabled(false);
+        assert_eq!(sequencer.get_note_offs(), vec![(0, 48)]);
+        assert!(sequencer.pulse(ticks * 5).is_none());
+    }
+}
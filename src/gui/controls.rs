@@ -1,7 +1,11 @@
 use std::sync::{Arc, Mutex};
 use imgui::{ChildWindow, Slider, Ui, VerticalSlider};
 use crate::engine::audio::EngineManager;
+use crate::engine::note_handler::{Half, KeyboardMode};
+use crate::engine::synthesis::{GlideMode, NotePriority, VoiceMode, MAX_VOICES};
+use crate::system::midi_map::ValueCurve;
 use crate::system::parameter::Parameter;
+use crate::system::velocity::VelocityCurve;
 use crate::system::parameter::ParameterID::{KSAmount, WS1Amount, WT1Amount};
 
 use super::WindowContext;
@@ -13,23 +17,179 @@ impl ControlsWindow {
         let fwy = context.height;
         let fwx = context.width;
 
+        let midi_map;
+        let midi_learn;
+        let mut polyphony;
+        let (mut voice_mode, mut note_priority);
+        let (mut glide_time, glide_mode);
+        let (mut keyboard_mode, split_point);
+        let tuning_name;
+        let velocity_curve;
+        {
+            let e = context.engine.lock().unwrap();
+            midi_map = e.get_midi_map().clone();
+            midi_learn = e.get_midi_learn();
+            polyphony = e.get_polyphony() as i32;
+            (voice_mode, note_priority) = e.get_voice_mode();
+            (glide_time, glide_mode) = e.get_glide();
+            (keyboard_mode, split_point) = e.get_keyboard_mode();
+            tuning_name = e.get_tuning_name().to_string();
+            velocity_curve = e.get_velocity_curve().clone();
+        }
+
+        // Scala files to load, kept across frames
+        let mut scale_path = state["tuning"]["scale"].as_str().unwrap_or("").to_string();
+        let mut mapping_path = state["tuning"]["mapping"].as_str().unwrap_or("").to_string();
+
+        // Which half the sliders below edit, both when unset
+        let mut edit_half: Option<Half> = serde_json::from_value(state["edit_half"].clone()).unwrap_or(None);
+        let mut split_point = split_point as i32;
+
         ui.window("Voice Controls")
             .size([270.0, 720.0-45.0], imgui::Condition::FirstUseEver)
             .position([1010.0, 45.0], imgui::Condition::FirstUseEver)
             .build(|| {
+                if let Some(_combo) = ui.begin_combo("Mode", format!("{:?}", voice_mode)) {
+                    for mode in VoiceMode::all() {
+                        if ui.selectable_config(format!("{:?}", mode)).selected(*mode == voice_mode).build() {
+                            voice_mode = *mode;
+                            context.engine.lock().unwrap().set_voice_mode(voice_mode, note_priority);
+                        }
+                    }
+                }
+
+                if voice_mode == VoiceMode::Poly {
+                    if ui.slider("Voices", 1, MAX_VOICES as i32, &mut polyphony) {
+                        context.engine.lock().unwrap().set_polyphony(polyphony as usize);
+                    }
+                } else if let Some(_combo) = ui.begin_combo("Priority", format!("{:?}", note_priority)) {
+                    for priority in NotePriority::all() {
+                        if ui.selectable_config(format!("{:?}", priority)).selected(*priority == note_priority).build() {
+                            note_priority = *priority;
+                            context.engine.lock().unwrap().set_voice_mode(voice_mode, note_priority);
+                        }
+                    }
+                }
+
+                let mut legato_glide = glide_mode == GlideMode::Legato;
+                let mut changed = ui.slider_config("Glide", 0.0, 2.0).display_format("%.2f s").build(&mut glide_time);
+                changed |= ui.checkbox("Only when legato", &mut legato_glide);
+                if changed {
+                    let mode = if legato_glide { GlideMode::Legato } else { GlideMode::Always };
+                    context.engine.lock().unwrap().set_glide(glide_time, mode);
+                }
+                ui.separator();
+
+                if let Some(_combo) = ui.begin_combo("Keyboard", format!("{:?}", keyboard_mode)) {
+                    for mode in KeyboardMode::all() {
+                        if ui.selectable_config(format!("{:?}", mode)).selected(*mode == keyboard_mode).build() {
+                            keyboard_mode = *mode;
+                            context.engine.lock().unwrap().set_keyboard_mode(keyboard_mode, split_point as u8);
+                        }
+                    }
+                }
+
+                if keyboard_mode == KeyboardMode::Split && ui.slider("Split at", 0, 127, &mut split_point) {
+                    context.engine.lock().unwrap().set_keyboard_mode(keyboard_mode, split_point as u8);
+                }
+
+                if keyboard_mode != KeyboardMode::Whole {
+                    let label = |half: Option<Half>| half.map_or("Both".to_string(), |h| format!("{:?}", h));
+                    if let Some(_combo) = ui.begin_combo("Edit", label(edit_half)) {
+                        for half in [None, Some(Half::Lower), Some(Half::Upper)] {
+                            if ui.selectable_config(label(half)).selected(half == edit_half).build() {
+                                edit_half = half;
+                            }
+                        }
+                    }
+                }
+                ui.separator();
+
+                let curve_label = match &velocity_curve {
+                    VelocityCurve::Shape(curve) => format!("{:?}", curve),
+                    VelocityCurve::Breakpoints(_) => "Custom".to_string()
+                };
+                if let Some(_combo) = ui.begin_combo("Velocity", &curve_label) {
+                    for curve in ValueCurve::all() {
+                        if ui.selectable_config(format!("{:?}", curve)).selected(velocity_curve == VelocityCurve::Shape(*curve)).build() {
+                            context.engine.lock().unwrap().set_velocity_curve(VelocityCurve::Shape(*curve));
+                        }
+                    }
+                    // Starts out as the current curve
+                    if ui.selectable_config("Custom").selected(curve_label == "Custom").build() {
+                        let points = velocity_curve.to_breakpoints(5);
+                        context.engine.lock().unwrap().set_velocity_curve(VelocityCurve::Breakpoints(points));
+                    }
+                }
+
+                if let VelocityCurve::Breakpoints(points) = &velocity_curve {
+                    let mut points = points.clone();
+                    let mut changed = false;
+                    for (i, point) in points.iter_mut().enumerate() {
+                        let label = format!("At {:.0}##velocity-{}", point.0 * 127.0, i);
+                        changed |= ui.slider(label, 0.0, 1.0, &mut point.1);
+                    }
+                    if changed {
+                        context.engine.lock().unwrap().set_velocity_curve(VelocityCurve::Breakpoints(points));
+                    }
+                }
+                ui.separator();
+
+                ui.text(format!("Tuning: {}", tuning_name));
+                ui.input_text("Scale (.scl)", &mut scale_path).build();
+                ui.input_text("Mapping (.kbm)", &mut mapping_path).build();
+                if ui.button("Load tuning") && !scale_path.is_empty() {
+                    let mapping = Some(mapping_path.clone()).filter(|m| !m.is_empty());
+                    context.engine.lock().unwrap().load_tuning(scale_path.clone(), mapping);
+                }
+                ui.same_line();
+                if ui.button("12-TET") {
+                    context.engine.lock().unwrap().reset_tuning();
+                }
+                ui.separator();
+
                 for (i, p) in params.iter().enumerate() {
                     let n = format!("{:?}", p);
 
                     ui.text(&n);
-                    let mut value = state["parameters"][&n].as_f64().unwrap();
+
+                    // Click, then move a knob on the controller to assign it
+                    ui.same_line();
+                    let learning = midi_learn == Some(*p);
+                    let label = match (learning, midi_map.get(*p)) {
+                        (true, _) => "Move a control...".to_string(),
+                        (false, Some(mapping)) => format!("CC {}", mapping.cc),
+                        (false, None) => "Learn".to_string()
+                    };
+                    if ui.small_button(format!("{}##learn-{}", label, i)) {
+                        context.engine.lock().unwrap().learn_midi(if learning { None } else { Some(*p) });
+                    }
+
+                    // The selected half's own value, if it has one
+                    let half = edit_half.filter(|_| keyboard_mode != KeyboardMode::Whole).map(|half| format!("{:?}", half));
+                    let mut value = half.as_ref()
+                        .and_then(|half| state["half_parameters"][half][&n].as_f64())
+                        .unwrap_or_else(|| state["parameters"][&n].as_f64().unwrap());
                     let edited = ui.slider_config(format!("##slider-{}", i), 0.0, 1.0)
                         .build(&mut value);
 
                     if edited {
-                        state["parameters"][&n] = serde_json::Value::Number(serde_json::Number::from_f64(value).unwrap());
-                        context.engine.lock().unwrap().set_parameter(*p, value as f32);
+                        let number = serde_json::Value::Number(serde_json::Number::from_f64(value).unwrap());
+                        match (edit_half, half) {
+                            (Some(edit_half), Some(half)) => {
+                                state["half_parameters"][&half][&n] = number;
+                                context.engine.lock().unwrap().set_half_parameter(edit_half, *p, value as f32);
+                            },
+                            _ => {
+                                state["parameters"][&n] = number;
+                                context.engine.lock().unwrap().set_parameter(*p, value as f32);
+                            }
+                        }
                     }
                 }
             });
+
+        state["edit_half"] = serde_json::to_value(edit_half).unwrap();
+        state["tuning"] = serde_json::json!({ "scale": scale_path, "mapping": mapping_path });
     }
 }
\ No newline at end of file
@@ -8,10 +8,11 @@ pub struct DevToolsWindow;
 impl DevToolsWindow {
     pub fn build(ui: &Ui, context: WindowContext, state: &mut serde_json::Value) {
         let m: DevInfo;
+        let underruns: usize;
         {
-            let mut e = context.engine.lock().unwrap();
-            e.run();
+            let e = context.engine.lock().unwrap();
             m = e.get_latest_debug_info();
+            underruns = e.get_underruns();
         }
 
         ui.window("DevTools")
@@ -23,6 +24,7 @@ impl DevToolsWindow {
                 ui.text(format!("Sample Rate: {} Hz", m.sample_rate));
                 ui.text(format!("Average cycle time: {:?}", m.avg_cycle_time));
                 ui.text(format!("Max cycle time: {:?}", m.max_cycle_time));
+                ui.text(format!("Buffer underruns: {}", underruns));
             });
     }
 }
\ No newline at end of file
@@ -1,6 +1,12 @@
 use std::sync::{Arc, Mutex};
-use imgui::{Condition, ListBox, Ui};
+use imgui::{Condition, ListBox, TreeNodeFlags, Ui};
 use crate::engine::audio::EngineManager;
+use crate::engine::clock::ClockSync;
+use crate::engine::midi::ALL_CHANNELS;
+use crate::engine::mpe::MpeZone;
+use crate::generators::arpeggiator::{ArpPattern, MAX_OCTAVES};
+use crate::generators::Rate;
+use crate::system::midi_map::ValueCurve;
 
 use super::WindowContext;
 
@@ -9,17 +15,39 @@ pub struct MidiWindow;
 impl MidiWindow {
     pub fn build(ui: &Ui, context: WindowContext) {
         let inputs;
+        let outputs;
+        let mut midi_out_selector;
         let mut midi_in_selector;
+        let mut channels;
+        let mut external_clock;
+        let mut arpeggiator;
+        let mpe_zone;
+        let midi_map;
+        let profiles;
+        let active_profile;
         
         {
             let e = context.engine.lock().unwrap();
             inputs = e.get_midi_ports().clone();
             midi_in_selector = e.get_selected_midi_port();
+            outputs = e.get_midi_outputs().clone();
+            midi_out_selector = e.get_selected_midi_output().cloned();
+            channels = e.get_midi_channels();
+            external_clock = e.get_clock_sync() == ClockSync::External;
+            arpeggiator = e.get_arpeggiator();
+            mpe_zone = e.get_mpe_zone();
+            midi_map = e.get_midi_map().clone();
+            profiles = e.get_controller_profiles().clone();
+            active_profile = e.get_active_controller_profile().cloned();
         }
 
         ui.window("MIDI")
-            .size([350.0, 200.0], Condition::FirstUseEver)
-            .build(|| {                
+            .size([350.0, 600.0], Condition::FirstUseEver)
+            .build(|| {
+                if ui.button("Refresh") {
+                    context.engine.lock().unwrap().refresh_device_list();
+                }
+
                 ListBox::new("MIDI Inputs")
                     .build(ui, || {
                         for (name, i) in inputs.iter() {
@@ -30,6 +58,141 @@ impl MidiWindow {
                             }
                         }
                     });
+
+                ListBox::new("MIDI Outputs")
+                    .build(ui, || {
+                        for name in outputs.iter() {
+                            let selected = midi_out_selector.as_ref() == Some(name);
+                            if ui.selectable_config(name).selected(selected).build() {
+                                midi_out_selector = Some(name.clone());
+                                context.engine.lock().unwrap().set_midi_output(name.clone());
+                            }
+                        }
+                    });
+
+                ui.text("Channels");
+                let mut changed = false;
+                for channel in 0..16 {
+                    let mut enabled = channels & (1 << channel) != 0;
+                    if channel % 8 != 0 {
+                        ui.same_line();
+                    }
+                    if ui.checkbox(format!("{}", channel + 1), &mut enabled) {
+                        channels ^= 1 << channel;
+                        changed = true;
+                    }
+                }
+
+                if ui.button("All") {
+                    channels = ALL_CHANNELS;
+                    changed = true;
+                }
+                ui.same_line();
+                if ui.button("None") {
+                    channels = 0;
+                    changed = true;
+                }
+
+                if changed {
+                    context.engine.lock().unwrap().set_midi_channels(channels);
+                }
+
+                if ui.checkbox("Follow external clock", &mut external_clock) {
+                    let sync = if external_clock { ClockSync::External } else { ClockSync::Internal };
+                    context.engine.lock().unwrap().set_clock_sync(sync);
+                }
+
+                // Plays the held notes while playback runs
+                if ui.collapsing_header("Arpeggiator", TreeNodeFlags::empty()) {
+                    let mut changed = ui.checkbox("Enabled##arp", &mut arpeggiator.enabled);
+
+                    if let Some(_combo) = ui.begin_combo("Pattern##arp", format!("{:?}", arpeggiator.pattern)) {
+                        for pattern in ArpPattern::all() {
+                            if ui.selectable_config(format!("{:?}", pattern)).selected(*pattern == arpeggiator.pattern).build() {
+                                arpeggiator.pattern = *pattern;
+                                changed = true;
+                            }
+                        }
+                    }
+
+                    if let Some(_combo) = ui.begin_combo("Rate##arp", arpeggiator.rate.name()) {
+                        for rate in Rate::all() {
+                            if ui.selectable_config(rate.name()).selected(*rate == arpeggiator.rate).build() {
+                                arpeggiator.rate = *rate;
+                                changed = true;
+                            }
+                        }
+                    }
+
+                    changed |= ui.slider("Octaves##arp", 1, MAX_OCTAVES, &mut arpeggiator.octaves);
+                    changed |= ui.slider_config("Gate##arp", 0.05, 1.0).display_format("%.2f").build(&mut arpeggiator.gate);
+
+                    if changed {
+                        context.engine.lock().unwrap().set_arpeggiator(arpeggiator);
+                    }
+                }
+
+                // Controllers that send an MPE Configuration Message set this up themselves
+                let mut mpe = mpe_zone.is_some();
+                if ui.checkbox("MPE", &mut mpe) {
+                    let zone = if mpe { Some(MpeZone::lower(15)) } else { None };
+                    context.engine.lock().unwrap().set_mpe_zone(zone);
+                }
+                if let Some(zone) = mpe_zone {
+                    ui.same_line();
+                    let name = if zone.master == 0 { "Lower" } else { "Upper" };
+                    ui.text(format!("{} zone, {} member channels", name, zone.members));
+                }
+
+                // Picked automatically from the input's port name, can be overridden here
+                let preview = active_profile.clone().unwrap_or("None".to_string());
+                if let Some(_combo) = ui.begin_combo("Controller", &preview) {
+                    if ui.selectable_config("None").selected(active_profile.is_none()).build() {
+                        context.engine.lock().unwrap().set_controller_profile(None);
+                    }
+
+                    for profile in profiles.iter() {
+                        let selected = active_profile.as_ref() == Some(&profile.name);
+                        if ui.selectable_config(&profile.name).selected(selected).build() {
+                            context.engine.lock().unwrap().set_controller_profile(Some(profile.clone()));
+                        }
+                    }
+                }
+
+                if ui.collapsing_header("Mappings", TreeNodeFlags::DEFAULT_OPEN) {
+                    for (i, mapping) in midi_map.mappings.iter().enumerate() {
+                        let channel = match mapping.channel {
+                            Some(channel) => format!("ch {}", channel + 1),
+                            None => "any ch".to_string()
+                        };
+                        ui.text(format!("{:?} <- CC {} ({})", mapping.parameter, mapping.cc, channel));
+
+                        let mut edited = mapping.clone();
+                        let mut changed = ui.slider_config(format!("Min##map-min-{}", i), 0.0, 1.0).build(&mut edited.min);
+                        changed |= ui.slider_config(format!("Max##map-max-{}", i), 0.0, 1.0).build(&mut edited.max);
+                        changed |= ui.checkbox(format!("Invert##map-invert-{}", i), &mut edited.inverted);
+
+                        ui.same_line();
+                        if let Some(_combo) = ui.begin_combo(format!("##map-curve-{}", i), format!("{:?}", edited.curve)) {
+                            for curve in ValueCurve::all() {
+                                if ui.selectable_config(format!("{:?}", curve)).selected(*curve == edited.curve).build() {
+                                    edited.curve = *curve;
+                                    changed = true;
+                                }
+                            }
+                        }
+
+                        if changed {
+                            context.engine.lock().unwrap().set_midi_mapping(edited);
+                        }
+
+                        ui.same_line();
+                        if ui.small_button(format!("Remove##map-remove-{}", i)) {
+                            context.engine.lock().unwrap().remove_midi_mapping(mapping.parameter);
+                        }
+                        ui.separator();
+                    }
+                }
             });
     }
 }
\ No newline at end of file
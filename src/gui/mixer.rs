@@ -11,6 +11,14 @@ impl MixerWindow {
     pub fn build(ui: &Ui, context: WindowContext, state: &mut serde_json::Value) {
         let params = &[WS1Amount, WT1Amount, KSAmount];
 
+        let parts;
+        let active_part;
+        {
+            let e = context.engine.lock().unwrap();
+            parts = e.get_parts().iter().map(|p| p.settings).collect::<Vec<_>>();
+            active_part = e.get_active_part();
+        }
+
         ui.window("Mixer")
             .size([800.0, 240.0], imgui::Condition::FirstUseEver)
             .build(|| {
@@ -37,6 +45,53 @@ impl MixerWindow {
                     });
                     token.end();
                 }
+
+                // One strip per part, the selected part is the one the other windows edit
+                for (i, part) in parts.iter().enumerate() {
+                    ui.same_line();
+                    let token = ui.begin_group();
+                    ui.child_window(format!("part-{}", i)).size([110.0, 200.0]).build(|| {
+                        if ui.radio_button_bool(format!("Part {}", i + 1), i == active_part) {
+                            context.engine.lock().unwrap().select_part(i);
+                        }
+
+                        let channel_label = |channel: Option<u8>| channel.map_or("Omni".to_string(), |c| format!("Ch {}", c + 1));
+                        ui.set_next_item_width(90.0);
+                        if let Some(_combo) = ui.begin_combo("##part-channel", channel_label(part.channel)) {
+                            for channel in std::iter::once(None).chain((0..16).map(Some)) {
+                                if ui.selectable_config(channel_label(channel)).selected(channel == part.channel).build() {
+                                    context.engine.lock().unwrap().set_part_channel(i, channel);
+                                }
+                            }
+                        }
+
+                        let mut pan = part.pan;
+                        ui.set_next_item_width(90.0);
+                        if ui.slider_config("##part-pan", -1.0, 1.0).display_format("Pan %.2f").build(&mut pan) {
+                            context.engine.lock().unwrap().set_part_pan(i, pan);
+                        }
+
+                        let mut level = part.level;
+                        if VerticalSlider::new("##part-level", [18.0, 90.0], 0.0, 1.0).build(ui, &mut level) {
+                            context.engine.lock().unwrap().set_part_level(i, level);
+                        }
+
+                        if parts.len() > 1 {
+                            ui.same_line();
+                            if ui.small_button("Remove") {
+                                context.engine.lock().unwrap().remove_part(i);
+                            }
+                        }
+                    });
+                    token.end();
+                }
+
+                ui.same_line();
+                if ui.button("Add part") {
+                    // Lowest channel no other part is bound to
+                    let channel = (0..16).find(|c| !parts.iter().any(|p| p.channel == Some(*c)));
+                    context.engine.lock().unwrap().add_part(channel);
+                }
             });
     }
 }
\ No newline at end of file
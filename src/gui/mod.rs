@@ -4,6 +4,9 @@ mod mixer;
 mod status;
 mod controls;
 mod devtools;
+mod modulation;
+mod sequencer;
+mod transport;
 
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
@@ -34,6 +37,8 @@ pub enum Window {
     Controls,
     Modulation,
     Mixer,
+    Sequencer,
+    Transport,
     Devtools
 }
 
@@ -52,6 +57,8 @@ pub fn run_gui(engine: Arc<Mutex<EngineManager>>) {
         (Window::Controls, true),
         (Window::Modulation, false),
         (Window::Mixer, true),
+        (Window::Sequencer, false),
+        (Window::Transport, false),
         (Window::Devtools, true)
     ].iter().cloned().collect();
 
@@ -76,6 +83,11 @@ pub fn run_gui(engine: Arc<Mutex<EngineManager>>) {
             "WT1Amount": 0.0,
             "WS1Amount": 0.0,
             "KSAmount": 1.0
+        },
+        // Values a keyboard half has of its own, the rest follow the parameters above
+        "half_parameters": {
+            "Lower": {},
+            "Upper": {}
         }
     });
 
@@ -87,6 +99,34 @@ pub fn run_gui(engine: Arc<Mutex<EngineManager>>) {
         // let t5 = ui.push_style_var(StyleVar::PopupRounding(7.0));
         // let t6 = ui.push_style_var(StyleVar::ScrollbarRounding(7.0));
 
+        // Drain engine feedback every frame, regardless of which windows are open
+        let parameter_changes = {
+            let mut e = engine.lock().unwrap();
+            e.run();
+            e.take_parameter_changes()
+        };
+
+        // Keep the sliders in sync with changes made elsewhere, e.g. over OSC.
+        // A change to the whole patch overrides what either half had.
+        for (half, id, value) in parameter_changes {
+            let Some(number) = serde_json::Number::from_f64(value as f64) else {
+                continue;
+            };
+            let name = format!("{:?}", id);
+
+            match half {
+                Some(half) => state["half_parameters"][format!("{:?}", half)][&name] = serde_json::Value::Number(number),
+                None => {
+                    for half in ["Lower", "Upper"] {
+                        if let Some(values) = state["half_parameters"][half].as_object_mut() {
+                            values.remove(&name);
+                        }
+                    }
+                    state["parameters"][&name] = serde_json::Value::Number(number);
+                }
+            }
+        }
+
         let ctx: WindowContext;
         unsafe {
             ctx = WindowContext {
@@ -110,6 +150,18 @@ pub fn run_gui(engine: Arc<Mutex<EngineManager>>) {
             ControlsWindow::build(ui, ctx.clone(), &mut state);
         }
 
+        if windows[&Window::Modulation] {
+            modulation::ModulationWindow::build(ui, ctx.clone(), &mut state);
+        }
+
+        if windows[&Window::Sequencer] {
+            sequencer::SequencerWindow::build(ui, ctx.clone(), &mut state);
+        }
+
+        if windows[&Window::Transport] {
+            transport::TransportWindow::build(ui, ctx.clone(), &mut state);
+        }
+
         if windows[&Window::Devtools] {
             devtools::DevToolsWindow::build(ui, ctx.clone(), &mut state);
         }
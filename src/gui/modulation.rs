@@ -0,0 +1,77 @@
+use imgui::{Condition, Ui};
+use serde_json::json;
+use crate::modulators::matrix::{ModRoute, ModSource};
+use crate::system::parameter::{Parameter, ParameterID};
+
+use super::WindowContext;
+
+pub struct ModulationWindow;
+
+impl ModulationWindow {
+    pub fn build(ui: &Ui, context: WindowContext, state: &mut serde_json::Value) {
+        let mod_matrix;
+        let mut bend_range;
+        {
+            let e = context.engine.lock().unwrap();
+            mod_matrix = e.get_mod_matrix().clone();
+            bend_range = e.get_bend_range();
+        }
+
+        // The route being put together, kept across frames
+        if state.get("new_route").is_none() {
+            state["new_route"] = json!({ "source": "ModWheel", "destination": "WS1Harmonics" });
+        }
+        let mut source: ModSource = serde_json::from_value(state["new_route"]["source"].clone()).unwrap();
+        let mut destination: ParameterID = serde_json::from_value(state["new_route"]["destination"].clone()).unwrap();
+
+        let destinations: Vec<ParameterID> = Parameter::supported().iter().cloned()
+            .chain([ParameterID::WS1Amount, ParameterID::KSAmount, ParameterID::WT1Amount])
+            .collect();
+
+        ui.window("Modulation")
+            .size([350.0, 400.0], Condition::FirstUseEver)
+            .build(|| {
+                if ui.slider_config("Bend range", 0.0, 48.0).display_format("%.0f st").build(&mut bend_range) {
+                    context.engine.lock().unwrap().set_bend_range(bend_range.round());
+                }
+                ui.separator();
+
+                for (i, route) in mod_matrix.routes.iter().enumerate() {
+                    ui.text(format!("{:?} -> {:?}", route.source, route.destination));
+
+                    let mut amount = route.amount;
+                    if ui.slider_config(format!("##mod-amount-{}", i), -1.0, 1.0).build(&mut amount) {
+                        context.engine.lock().unwrap().set_mod_route(ModRoute { amount, ..route.clone() });
+                    }
+
+                    ui.same_line();
+                    if ui.small_button(format!("Remove##mod-remove-{}", i)) {
+                        context.engine.lock().unwrap().remove_mod_route(route.source, route.destination);
+                    }
+                }
+                ui.separator();
+
+                if let Some(_combo) = ui.begin_combo("Source", format!("{:?}", source)) {
+                    for s in ModSource::all() {
+                        if ui.selectable_config(format!("{:?}", s)).selected(*s == source).build() {
+                            source = *s;
+                        }
+                    }
+                }
+
+                if let Some(_combo) = ui.begin_combo("Destination", format!("{:?}", destination)) {
+                    for d in destinations.iter() {
+                        if ui.selectable_config(format!("{:?}", d)).selected(*d == destination).build() {
+                            destination = *d;
+                        }
+                    }
+                }
+
+                if ui.button("Add") {
+                    context.engine.lock().unwrap().set_mod_route(ModRoute { source, destination, amount: 0.5 });
+                }
+            });
+
+        state["new_route"] = json!({ "source": source, "destination": destination });
+    }
+}
@@ -0,0 +1,199 @@
+use imgui::{Condition, TreeNodeFlags, Ui};
+use crate::engine::clock::MAX_GENERATORS;
+use crate::generators::euclidean::{EuclideanSettings, MAX_STEPS as MAX_EUCLIDEAN_STEPS};
+use crate::generators::sequencer::MAX_STEPS;
+use crate::generators::{GeneratorSettings, Rate};
+
+use super::WindowContext;
+
+pub struct SequencerWindow;
+
+impl SequencerWindow {
+    pub fn build(ui: &Ui, context: WindowContext, state: &mut serde_json::Value) {
+        let pattern;
+        let mut enabled;
+        let generators;
+        let recording;
+        let last_take;
+        {
+            let e = context.engine.lock().unwrap();
+            pattern = e.get_sequencer().clone();
+            enabled = e.get_sequencer_enabled();
+            generators = e.get_generators().clone();
+            recording = e.get_recording();
+            last_take = e.get_last_take().cloned();
+        }
+
+        // Pitch lists as typed, they're only sent on enter
+        let mut midi_file_path = state["midi_file_path"].as_str().unwrap_or("").to_string();
+        let mut take_name = state["take_name"].as_str().unwrap_or("").to_string();
+        let mut pitches: Vec<String> = generators.iter().enumerate()
+            .map(|(i, generator)| match state["generator_pitches"][i].as_str() {
+                Some(text) => text.to_string(),
+                None => match generator {
+                    GeneratorSettings::Euclidean(settings) => settings.pitches.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(" "),
+                    GeneratorSettings::MidiFile(_) => String::new()
+                }
+            })
+            .collect();
+
+        ui.window("Sequencer")
+            .size([520.0, 400.0], Condition::FirstUseEver)
+            .build(|| {
+                if ui.checkbox("Enabled", &mut enabled) {
+                    context.engine.lock().unwrap().set_sequencer_enabled(enabled);
+                }
+
+                ui.same_line();
+                ui.set_next_item_width(80.0);
+                if let Some(_combo) = ui.begin_combo("Rate", pattern.rate.name()) {
+                    for rate in Rate::all() {
+                        if ui.selectable_config(rate.name()).selected(*rate == pattern.rate).build() {
+                            context.engine.lock().unwrap().set_sequencer_rate(*rate);
+                        }
+                    }
+                }
+
+                ui.same_line();
+                ui.set_next_item_width(120.0);
+                let mut length = pattern.steps.len();
+                if ui.slider("Steps", 1, MAX_STEPS, &mut length) {
+                    context.engine.lock().unwrap().set_sequencer_length(length);
+                }
+                ui.separator();
+
+                if ui.collapsing_header("Generators", TreeNodeFlags::empty()) {
+                    for (i, generator) in generators.iter().enumerate() {
+                        let settings = match generator {
+                            GeneratorSettings::Euclidean(settings) => settings,
+                            GeneratorSettings::MidiFile(path) => {
+                                ui.text(format!("{} {}", i + 1, path));
+                                ui.same_line();
+                                if ui.small_button(format!("Remove##file-remove-{}", i)) {
+                                    context.engine.lock().unwrap().remove_generator(i);
+                                    pitches.remove(i);
+                                    break;
+                                }
+                                ui.separator();
+                                continue;
+                            }
+                        };
+                        let mut edited = settings.clone();
+
+                        let rhythm: String = settings.rhythm().iter().map(|hit| if *hit { 'x' } else { '.' }).collect();
+                        ui.text(format!("{} {}", i + 1, rhythm));
+
+                        ui.set_next_item_width(120.0);
+                        let mut changed = ui.slider(format!("Steps##euclid-steps-{}", i), 1, MAX_EUCLIDEAN_STEPS, &mut edited.steps);
+                        ui.same_line();
+                        ui.set_next_item_width(120.0);
+                        changed |= ui.slider(format!("Pulses##euclid-pulses-{}", i), 0, edited.steps, &mut edited.pulses);
+                        ui.same_line();
+                        ui.set_next_item_width(120.0);
+                        changed |= ui.slider(format!("Rotate##euclid-rotation-{}", i), 0, edited.steps - 1, &mut edited.rotation);
+
+                        ui.set_next_item_width(80.0);
+                        if let Some(_combo) = ui.begin_combo(format!("Rate##euclid-rate-{}", i), edited.rate.name()) {
+                            for rate in Rate::all() {
+                                if ui.selectable_config(rate.name()).selected(*rate == edited.rate).build() {
+                                    edited.rate = *rate;
+                                    changed = true;
+                                }
+                            }
+                        }
+                        ui.same_line();
+                        ui.set_next_item_width(100.0);
+                        changed |= ui.slider_config(format!("##euclid-gate-{}", i), 0.05, 1.0).display_format("Gate %.2f").build(&mut edited.gate);
+                        ui.same_line();
+                        ui.set_next_item_width(160.0);
+                        if ui.input_text(format!("Pitches##euclid-pitches-{}", i), &mut pitches[i]).enter_returns_true(true).build() {
+                            edited.pitches = pitches[i].split(|c: char| c == ',' || c.is_whitespace())
+                                .filter_map(|p| p.parse::<u8>().ok())
+                                .map(|p| p.min(127))
+                                .collect();
+                            changed = true;
+                        }
+
+                        edited.pulses = edited.pulses.min(edited.steps);
+                        edited.rotation = edited.rotation.min(edited.steps - 1);
+                        if changed {
+                            context.engine.lock().unwrap().set_generator(i, GeneratorSettings::Euclidean(edited));
+                        }
+
+                        ui.same_line();
+                        if ui.small_button(format!("Remove##euclid-remove-{}", i)) {
+                            context.engine.lock().unwrap().remove_generator(i);
+                            pitches.remove(i);
+                            break;
+                        }
+                        ui.separator();
+                    }
+
+                    if generators.len() < MAX_GENERATORS {
+                        if ui.button("Add Euclidean") {
+                            context.engine.lock().unwrap().add_generator(GeneratorSettings::Euclidean(EuclideanSettings::default()));
+                        }
+
+                        // Follows the file's tempo map while it plays
+                        ui.input_text("##midi-file-path", &mut midi_file_path).build();
+                        ui.same_line();
+                        if ui.button("Add MIDI file") && !midi_file_path.is_empty() {
+                            context.engine.lock().unwrap().add_generator(GeneratorSettings::MidiFile(midi_file_path.clone()));
+                        }
+                    }
+                    ui.separator();
+                }
+
+                if ui.collapsing_header("Recording", TreeNodeFlags::empty()) {
+                    if ui.button(if recording { "Stop recording" } else { "Record" }) {
+                        context.engine.lock().unwrap().set_recording(!recording);
+                    }
+
+                    // Saved under the Donut folder, named after the time when left empty
+                    ui.set_next_item_width(160.0);
+                    ui.input_text("##take-name", &mut take_name).hint("Take name").build();
+                    ui.same_line();
+                    if ui.button("Save .mid") {
+                        let name = if take_name.is_empty() { None } else { Some(take_name.clone()) };
+                        context.engine.lock().unwrap().save_take(name);
+                    }
+                    ui.same_line();
+                    if ui.button("Load into sequencer") {
+                        context.engine.lock().unwrap().load_take_into_sequencer(pattern.rate);
+                    }
+
+                    if let Some(path) = last_take.as_ref() {
+                        ui.text(format!("Saved to {}", path));
+                    }
+                    ui.separator();
+                }
+
+                for (i, step) in pattern.steps.iter().enumerate() {
+                    let mut edited = *step;
+
+                    ui.text(format!("{:>2}", i + 1));
+                    ui.same_line();
+                    ui.set_next_item_width(100.0);
+                    let mut changed = ui.slider(format!("##seq-pitch-{}", i), 0, 127, &mut edited.pitch);
+                    ui.same_line();
+                    ui.set_next_item_width(100.0);
+                    changed |= ui.slider_config(format!("##seq-velocity-{}", i), 0.0, 1.0).display_format("Vel %.2f").build(&mut edited.velocity);
+                    ui.same_line();
+                    ui.set_next_item_width(100.0);
+                    changed |= ui.slider_config(format!("##seq-gate-{}", i), 0.05, 1.0).display_format("Gate %.2f").build(&mut edited.gate);
+                    ui.same_line();
+                    changed |= ui.checkbox(format!("Tie##seq-tie-{}", i), &mut edited.tie);
+                    ui.same_line();
+                    changed |= ui.checkbox(format!("Rest##seq-rest-{}", i), &mut edited.rest);
+
+                    if changed {
+                        context.engine.lock().unwrap().set_sequencer_step(i, edited);
+                    }
+                }
+            });
+
+        state["generator_pitches"] = serde_json::json!(pitches);
+        state["midi_file_path"] = serde_json::json!(midi_file_path);
+        state["take_name"] = serde_json::json!(take_name);
+    }
+}
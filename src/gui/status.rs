@@ -18,8 +18,15 @@ impl StatusBar {
         let flags = WindowFlags::from_bits(topbar_flags).unwrap();
 
         let is_playing: bool;
+        let is_recording: bool;
+        let position: (usize, usize, usize);
+        let errors: Vec<String>;
         {
-            is_playing = context.engine.lock().unwrap().get_playback_status().clone();
+            let e = context.engine.lock().unwrap();
+            is_playing = e.get_playback_status().clone();
+            is_recording = e.get_recording();
+            position = e.get_position();
+            errors = e.get_midi_error().into_iter().chain(e.get_audio_error()).cloned().collect();
         }
         
         ui.window("Application Controls")
@@ -45,6 +52,24 @@ impl StatusBar {
                 if play_button {
                     context.engine.lock().unwrap().toggle_playback();
                 }
+
+                ui.same_line();
+                if ui.button("Stop") {
+                    context.engine.lock().unwrap().stop_playback();
+                }
+
+                ui.same_line();
+                if ui.button(if is_recording { "Stop recording" } else { "Rec" }) {
+                    context.engine.lock().unwrap().set_recording(!is_recording);
+                }
+
+                ui.same_line();
+                ui.text(format!("{:>3}.{}.{:02}", position.0, position.1, position.2));
+
+                for error in errors.iter() {
+                    ui.same_line();
+                    ui.text_colored([1.0, 0.35, 0.35, 1.0], error);
+                }
             });
     }
 }
\ No newline at end of file
@@ -0,0 +1,101 @@
+use imgui::{Condition, Ui};
+use crate::engine::clock::TimeSignature;
+
+use super::WindowContext;
+
+pub struct TransportWindow;
+
+impl TransportWindow {
+    pub fn build(ui: &Ui, context: WindowContext, state: &mut serde_json::Value) {
+        let is_playing;
+        let position;
+        let loop_range;
+        let signature;
+        {
+            let e = context.engine.lock().unwrap();
+            is_playing = e.get_playback_status();
+            position = e.get_position();
+            loop_range = e.get_loop();
+            signature = e.get_time_signature();
+        }
+
+        // Bars are counted from 1, the engine works in ticks from the start
+        let bar_ticks = signature.bar_ticks();
+        let mut locate_bar = state["locate_bar"].as_i64().unwrap_or(1) as i32;
+        let mut looping = loop_range.is_some();
+        let (mut loop_start, mut loop_end) = match loop_range {
+            Some((start, end)) => ((start / bar_ticks + 1) as i32, (end / bar_ticks + 1) as i32),
+            None => (state["loop_start"].as_i64().unwrap_or(1) as i32, state["loop_end"].as_i64().unwrap_or(5) as i32)
+        };
+
+        ui.window("Transport")
+            .size([360.0, 180.0], Condition::FirstUseEver)
+            .build(|| {
+                ui.text(format!("Bar {} Beat {} Tick {}", position.0, position.1, position.2));
+
+                if ui.button(if is_playing { "Pause" } else { "Play" }) {
+                    context.engine.lock().unwrap().toggle_playback();
+                }
+                ui.same_line();
+                if ui.button("Stop") {
+                    context.engine.lock().unwrap().stop_playback();
+                }
+                ui.same_line();
+                if ui.button("Rewind") {
+                    context.engine.lock().unwrap().reset_playback();
+                }
+
+                ui.set_next_item_width(100.0);
+                ui.input_int("##locate-bar", &mut locate_bar).build();
+                locate_bar = locate_bar.max(1);
+                ui.same_line();
+                if ui.button("Locate to bar") {
+                    context.engine.lock().unwrap().locate((locate_bar as usize - 1) * bar_ticks);
+                }
+                ui.separator();
+
+                let mut numerator = signature.numerator;
+                ui.set_next_item_width(100.0);
+                let mut changed = ui.slider("##time-numerator", 1, 32, &mut numerator);
+                ui.same_line();
+                ui.set_next_item_width(60.0);
+                let mut denominator = signature.denominator;
+                if let Some(_combo) = ui.begin_combo("Time signature", format!("/{}", denominator)) {
+                    for value in [1, 2, 4, 8, 16, 32] {
+                        if ui.selectable_config(format!("/{}", value)).selected(value == denominator).build() {
+                            denominator = value;
+                            changed = true;
+                        }
+                    }
+                }
+                if changed {
+                    context.engine.lock().unwrap().set_time_signature(TimeSignature::new(numerator, denominator));
+                }
+                ui.separator();
+
+                // The end bar is where the loop stops, it isn't played
+                let mut loop_changed = ui.checkbox("Loop", &mut looping);
+                ui.same_line();
+                ui.set_next_item_width(80.0);
+                loop_changed |= ui.input_int("From##loop-start", &mut loop_start).build();
+                ui.same_line();
+                ui.set_next_item_width(80.0);
+                loop_changed |= ui.input_int("To##loop-end", &mut loop_end).build();
+
+                loop_start = loop_start.max(1);
+                loop_end = loop_end.max(loop_start + 1);
+                if loop_changed {
+                    let range = if looping {
+                        Some(((loop_start as usize - 1) * bar_ticks, (loop_end as usize - 1) * bar_ticks))
+                    } else {
+                        None
+                    };
+                    context.engine.lock().unwrap().set_loop(range);
+                }
+            });
+
+        state["locate_bar"] = serde_json::json!(locate_bar);
+        state["loop_start"] = serde_json::json!(loop_start);
+        state["loop_end"] = serde_json::json!(loop_end);
+    }
+}
@@ -14,6 +14,15 @@ mod models;
 mod generators;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(|a| a == "render").unwrap_or(false) {
+        if let Err(e) = engine::offline::run_cli(&args[1..]) {
+            eprintln!("Render failed: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Start the audio thread
     let mut engine = Arc::new(Mutex::new(EngineManager::new()));
 
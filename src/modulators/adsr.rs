@@ -35,6 +35,8 @@ pub struct ADSR {
     d_step: f32,
     r_step: f32,
     value: f32,
+    // Level the release started from
+    release_level: f32,
     position: usize
 }
 
@@ -58,11 +60,6 @@ impl ADSR {
         res.decay = Parameter::from_id(ADSR1Decay, res.module_id, voice_id, sample_rate);
         res.sustain = Parameter::from_id(ADSR1Sustain, res.module_id, voice_id, sample_rate);
         res.release = Parameter::from_id(ADSR1Release, res.module_id, voice_id, sample_rate);
-        
-        res.attack.assign_cc(25);
-        res.decay.assign_cc(26);
-        res.sustain.assign_cc(27);
-        res.release.assign_cc(28);
 
         res.buffer = Buffer::new(block_size, "ADSR".to_string());
         res.value = 0.0;
@@ -70,6 +67,16 @@ impl ADSR {
         res
     }
 
+    pub fn is_active(&self) -> bool {
+        self.state != ADSRState::Silence
+    }
+
+    // Cuts the envelope off without a release
+    pub fn silence(&mut self) {
+        self.state = ADSRState::Silence;
+        self.value = 0.0;
+    }
+
     // pub fn set_attack(&mut self, attack: f32) {
     //     self.attack = ((attack / 1000.0) * self.sample_rate) as usize;
     //     self.a_step = 1.0 / self.attack as f32;
@@ -94,12 +101,12 @@ impl ADSR {
 }
 
 impl Modulator for ADSR {
-    fn process(&mut self) {
+    fn process_range(&mut self, start: usize, end: usize) {
         let a_step = 1.0 / self.attack.get_value();
         let d_step = (1.0 - self.sustain.get_value()) / self.decay.get_value();
-        let r_step = self.sustain.get_value() / self.release.get_value();
+        let r_step = self.release_level / self.release.get_value();
         
-        for i in 0..self.block_size {
+        for i in start..end {
             match self.state {
                 ADSRState::Attack => {
                     self.value += a_step;
@@ -125,13 +132,12 @@ impl Modulator for ADSR {
                     self.value -= r_step;
                     self.position += 1;
 
-                    if self.position >= self.release.get_value() as usize {
+                    if self.position >= self.release.get_value() as usize || self.value <= 0.0 {
                         self.state = ADSRState::Silence;
+                        self.value = 0.0;
                     }
                 }
-                ADSRState::Silence => {
-                    self.buffer.write(0.0);
-                }
+                ADSRState::Silence => {}
             }
 
             self.buffer[i] = self.value * self.velocity;
@@ -148,12 +154,17 @@ impl Modulator for ADSR {
     }
 
     fn stop(&mut self) {
+        if self.state == ADSRState::Silence {
+            return;
+        }
+
         self.state = ADSRState::Release;
+        self.release_level = self.value;
         self.position = 0;
     }
 
     fn get(&self) -> f32 {
-        todo!()
+        self.value * self.velocity
     }
 
     fn get_buffer(&self) -> &Buffer {
@@ -0,0 +1,109 @@
+// ModMatrix
+// Routes modulation sources, like the mod wheel or MPE pressure and timbre, to parameters of each voice
+
+use serde::{Deserialize, Serialize};
+use crate::system::parameter::ParameterID;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModSource {
+    // Channel or polyphonic aftertouch
+    Pressure,
+    // CC 74 on an MPE member channel
+    Timbre,
+    ModWheel,
+    // CC 67, 1 while the pedal is down
+    SoftPedal,
+    // Note-on velocity after the velocity curve
+    Velocity
+}
+
+impl ModSource {
+    pub const COUNT: usize = 5;
+
+    pub fn all() -> &'static [ModSource] {
+        &[ModSource::Pressure, ModSource::Timbre, ModSource::ModWheel, ModSource::SoftPedal, ModSource::Velocity]
+    }
+}
+
+// Current value of every source for one voice, 0..1
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ModValues([f32; ModSource::COUNT]);
+
+impl ModValues {
+    pub fn get(&self, source: ModSource) -> f32 {
+        self.0[source as usize]
+    }
+
+    pub fn set(&mut self, source: ModSource, value: f32) {
+        self.0[source as usize] = value;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModRoute {
+    pub source: ModSource,
+    pub destination: ParameterID,
+    // Normalized offset at full modulation, negative values modulate downwards
+    pub amount: f32
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModMatrix {
+    pub routes: Vec<ModRoute>
+}
+
+impl ModMatrix {
+    pub fn default_routes() -> Self {
+        Self {
+            routes: vec![
+                ModRoute { source: ModSource::Pressure, destination: ParameterID::WS1Harmonics, amount: 0.5 },
+                ModRoute { source: ModSource::Timbre, destination: ParameterID::WT1Shape, amount: 1.0 },
+                ModRoute { source: ModSource::ModWheel, destination: ParameterID::WS1Harmonics, amount: 0.5 },
+                ModRoute { source: ModSource::SoftPedal, destination: ParameterID::KSCutoff, amount: -0.25 }
+            ]
+        }
+    }
+
+    // A source drives a destination only once, setting a route replaces its amount
+    pub fn set(&mut self, route: ModRoute) {
+        match self.routes.iter_mut().find(|r| r.source == route.source && r.destination == route.destination) {
+            Some(existing) => *existing = route,
+            None => self.routes.push(route)
+        }
+    }
+
+    pub fn remove(&mut self, source: ModSource, destination: ParameterID) {
+        self.routes.retain(|r| r.source != source || r.destination != destination);
+    }
+
+    // Summed modulation for a destination
+    pub fn amount_for(&self, destination: ParameterID, values: &ModValues) -> f32 {
+        self.routes.iter()
+            .filter(|r| r.destination == destination)
+            .map(|r| r.amount * values.get(r.source))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mod_matrix() {
+        let mut matrix = ModMatrix::default();
+        matrix.set(ModRoute { source: ModSource::ModWheel, destination: ParameterID::KSCutoff, amount: 0.5 });
+        matrix.set(ModRoute { source: ModSource::Pressure, destination: ParameterID::KSCutoff, amount: -0.25 });
+        matrix.set(ModRoute { source: ModSource::ModWheel, destination: ParameterID::KSCutoff, amount: 0.75 });
+        assert_eq!(matrix.routes.len(), 2);
+
+        let mut values = ModValues::default();
+        values.set(ModSource::ModWheel, 1.0);
+        values.set(ModSource::Pressure, 0.5);
+        assert_eq!(matrix.amount_for(ParameterID::KSCutoff, &values), 0.625);
+        assert_eq!(matrix.amount_for(ParameterID::WS1Harmonics, &values), 0.0);
+
+        matrix.remove(ModSource::ModWheel, ParameterID::KSCutoff);
+        assert_eq!(matrix.amount_for(ParameterID::KSCutoff, &values), -0.125);
+    }
+}
@@ -4,9 +4,14 @@ use crate::dsp::buffer::Buffer;
 use crate::system::parameter::Parameter;
 
 pub mod adsr;
+pub mod matrix;
 
 pub trait Modulator {
-    fn process(&mut self);
+    fn process(&mut self) {
+        let block_size = self.get_buffer().get_size();
+        self.process_range(0, block_size);
+    }
+    fn process_range(&mut self, start: usize, end: usize);
     fn refresh(&mut self) {}
     fn tick(&mut self) {}
     fn set(&mut self, value: f32);
@@ -4,6 +4,7 @@ use smallvec::SmallVec;
 use uuid::Uuid;
 use crate::dsp::buffer::Buffer;
 use crate::system::parameter::Parameter;
+use crate::system::tuning::Tuning;
 
 pub mod sine;
 pub mod wavetable;
@@ -15,11 +16,21 @@ mod sampler;
 pub trait AudioSource {
     fn get_id(&self) -> Uuid;
     
-    fn process(&mut self);
+    fn process(&mut self) {
+        let block_size = self.get_buffer().get_size();
+        self.process_range(0, block_size);
+    }
+    // Renders samples [start, end) of the current block, so a block can be split at event boundaries
+    fn process_range(&mut self, start: usize, end: usize);
     fn tick(&mut self) {}
     fn refresh(&mut self) {}
-    fn set_pitch(&mut self, midi_note: u8);
+    fn set_pitch(&mut self, midi_note: u8, tuning: &Tuning);
+    // Changes the pitch of a sounding note, without retriggering it
     fn set_frequency(&mut self, frequency: f32);
+    // Frequency this source plays a (fractional) note at, including its own tuning
+    fn note_frequency(&self, midi_note: f32, tuning: &Tuning) -> f32 {
+        tuning.frequency(midi_note)
+    }
     fn fm(&mut self, frequency: f32, amount: f32) {}
     fn set_block_size(&mut self, block_size: usize) {}
 
@@ -2,9 +2,9 @@ use std::f32::consts::PI;
 use smallvec::{SmallVec, smallvec};
 use uuid::Uuid;
 use crate::dsp::buffer::Buffer;
-use crate::dsp::util::mtof;
 use crate::sources::AudioSource;
 use crate::system::parameter::Parameter;
+use crate::system::tuning::Tuning;
 
 // #[derive(Send, Sync)]
 pub struct Sine {
@@ -36,8 +36,8 @@ impl AudioSource for Sine {
         self.id
     }
 
-    fn process(&mut self) {
-        for i in 0..self.block_size {
+    fn process_range(&mut self, start: usize, end: usize) {
+        for i in start..end {
             self.buffer[i] = (PI * 2.0 * self.phase).sin() * 0.5;
             self.phase += self.phase_step;
 
@@ -47,8 +47,8 @@ impl AudioSource for Sine {
         }
     }
 
-    fn set_pitch(&mut self, midi_note: u8) {
-        self.set_frequency(mtof(midi_note as f32));
+    fn set_pitch(&mut self, midi_note: u8, tuning: &Tuning) {
+        self.set_frequency(self.note_frequency(midi_note as f32, tuning));
     }
 
     fn set_frequency(&mut self, frequency: f32) {
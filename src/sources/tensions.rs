@@ -2,10 +2,10 @@ use smallvec::{SmallVec, smallvec};
 use uuid::Uuid;
 use crate::dsp::buffer::Buffer;
 use crate::dsp::filter_delay_line::FilterDelayLine;
-use crate::dsp::util::mtof;
 use crate::sources::AudioSource;
 use crate::system::parameter::Parameter;
 use crate::system::parameter::ParameterID::{KSCutoff, KSDelay, KSFeedback};
+use crate::system::tuning::Tuning;
 
 const TRIGGER_TIME: u8 = 10;
 
@@ -33,11 +33,8 @@ pub struct Tensions {
 impl Tensions {
     pub fn new(sample_rate: f32, block_size: usize, voice_id: usize) -> Self {
         let module_id = Uuid::new_v4();
-        let mut feedback = Parameter::from_id(KSFeedback, module_id, voice_id, sample_rate);
-        let mut dampening = Parameter::from_id(KSCutoff, module_id, voice_id, sample_rate);
-
-        feedback.assign_cc(21);
-        dampening.assign_cc(22);
+        let feedback = Parameter::from_id(KSFeedback, module_id, voice_id, sample_rate);
+        let dampening = Parameter::from_id(KSCutoff, module_id, voice_id, sample_rate);
 
         Self {
             module_id,
@@ -53,6 +50,8 @@ impl Tensions {
             block_size,
 
             frequency: 440.0,
+            // Matches the delay line, a refresh before the first note would otherwise divide by zero
+            delay_time: 440.0,
             ..Default::default()
         }
     }
@@ -77,19 +76,14 @@ impl AudioSource for Tensions {
         self.module_id
     }
 
-    fn process(&mut self) {
-        let mut smp = vec![];
-        let mut smp_in = vec![];
-        for i in 0..self.block_size {
+    fn process_range(&mut self, start: usize, end: usize) {
+        for i in start..end {
             let x = self.dl.process_sample(self.dl_in.read());
-            smp.push(x);
-            smp_in.push(self.dl_in.read());
 
-            self.buffer.write(x);
+            self.buffer[i] = x;
 
             self.dl_in.tick();
             self.dl.tick();
-            self.buffer.tick();
 
             if self.trigger_time > 0 {
                 self.trigger_time -= 1;
@@ -101,18 +95,18 @@ impl AudioSource for Tensions {
         // self.dl.process(&mut self.buffer);
     }
 
-    fn set_pitch(&mut self, midi_note: u8) {
-        self.delay_time = mtof(midi_note as f32);
+    fn refresh(&mut self) {
         self.sync();
+    }
 
+    fn set_pitch(&mut self, midi_note: u8, tuning: &Tuning) {
+        self.set_frequency(self.note_frequency(midi_note as f32, tuning));
         self.excite();
     }
 
     fn set_frequency(&mut self, frequency: f32) {
         self.delay_time = frequency;
         self.sync();
-
-        self.excite();
     }
 
     fn set_block_size(&mut self, block_size: usize) {
@@ -4,10 +4,10 @@ use std::sync::Arc;
 use smallvec::{SmallVec, smallvec};
 use uuid::Uuid;
 use crate::dsp::buffer::Buffer;
-use crate::dsp::util::mtof_detune;
 use crate::sources::AudioSource;
 use crate::system::parameter::Parameter;
 use crate::system::parameter::ParameterID::{WS1Detune, WS1Harmonics};
+use crate::system::tuning::Tuning;
 
 const TWO_PI: f32 = PI * 2.0;
 
@@ -39,11 +39,8 @@ impl WaveShaper {
 
         let module_id = Uuid::new_v4();
 
-        let mut harmonics = Parameter::from_id(WS1Harmonics, module_id, voice_id, sample_rate);
-        let mut detune = Parameter::from_id(WS1Detune, module_id, voice_id, sample_rate);
-        
-        harmonics.assign_cc(21);
-        detune.assign_cc(22);
+        let harmonics = Parameter::from_id(WS1Harmonics, module_id, voice_id, sample_rate);
+        let detune = Parameter::from_id(WS1Detune, module_id, voice_id, sample_rate);
 
         Self {
             module_id,
@@ -73,34 +70,34 @@ impl AudioSource for WaveShaper {
         self.module_id
     }
 
-    fn process(&mut self) {
-        self.buffer.wipe();
-        
-        for _ in 0..self.buffer_size {
+    fn process_range(&mut self, start: usize, end: usize) {
+        for n in start..end {
+            let mut sample = 0.0;
+
             if self.get_harmonics() < 0 {
                 for i in 0..-self.get_harmonics() as usize {
-                    self.buffer.write_addition((TWO_PI * self.n[i] * self.phase).sin() / self.n[i].max(1.0));
+                    sample += (TWO_PI * self.n[i] * self.phase).sin() / self.n[i].max(1.0);
                 }
             } else if self.get_harmonics() > 0 {
                 for i in 0..self.get_harmonics() {
                     let x = i as f32;
-                    self.buffer.write_addition((TWO_PI * x * self.phase).sin() / x.max(1.0));
+                    sample += (TWO_PI * x * self.phase).sin() / x.max(1.0);
                 }
             } else {
-                self.buffer.write((TWO_PI * self.phase).sin());
+                sample = (TWO_PI * self.phase).sin();
             }
 
+            self.buffer[n] = sample;
+
             self.phase += self.phase_step;
             if self.phase > 1.0 {
                 self.phase -= 1.0;
             }
-            
-            self.buffer.tick();
         }
     }
 
-    fn set_pitch(&mut self, midi_note: u8) {
-        self.frequency = mtof_detune(midi_note as f32, 440.0 + self.detune.get_value());
+    fn set_pitch(&mut self, midi_note: u8, tuning: &Tuning) {
+        self.frequency = self.note_frequency(midi_note as f32, tuning);
         self.phase_step = self.frequency / self.sample_rate;
         self.base_frequency = self.frequency;
     }
@@ -110,6 +107,10 @@ impl AudioSource for WaveShaper {
         self.phase_step = self.frequency / self.sample_rate;
     }
 
+    fn note_frequency(&self, midi_note: f32, tuning: &Tuning) -> f32 {
+        tuning.detuned(midi_note, 440.0 + self.detune.get_value())
+    }
+
     fn fm(&mut self, frequency: f32, amount: f32) {
         self.set_frequency((self.base_frequency * 2.0 * frequency.abs() * amount) + self.base_frequency);
     }
@@ -4,10 +4,10 @@ use std::path::PathBuf;
 use smallvec::{SmallVec, smallvec};
 use uuid::Uuid;
 use crate::dsp::buffer::Buffer;
-use crate::dsp::util::mtof_detune;
 use crate::sources::AudioSource;
 use crate::system::parameter::{Parameter, ParameterID};
 use crate::system::parameter::ParameterID::{WT1Detune, WT1Shape, WT1Transpose};
+use crate::system::tuning::Tuning;
 
 const TABLE_FREQUENCY: f32 = 1.0;
 const BIT_DIV: f32 = 32768.0;
@@ -45,13 +45,11 @@ pub struct WaveTable {
 impl WaveTable {
     pub fn new(sample_rate: f32, block_size: usize, voice_id: usize) -> Self {
         let id = Uuid::new_v4();
-        let mut shape = Parameter::from_id(WT1Shape, id, voice_id, sample_rate);
-        let mut detune = Parameter::from_id(WT1Detune, id, voice_id, sample_rate);
+        let shape = Parameter::from_id(WT1Shape, id, voice_id, sample_rate);
+        let detune = Parameter::from_id(WT1Detune, id, voice_id, sample_rate);
         let transpose = Parameter::from_id(WT1Transpose, id, voice_id, sample_rate);
 
-        shape.assign_cc(23);
-        detune.assign_cc(24);
-        
+
         let data_path = Self::get_data_path();
         if !data_path.exists() {
             std::fs::create_dir_all(&data_path).unwrap();
@@ -124,14 +122,13 @@ impl AudioSource for WaveTable {
         self.module_id
     }
 
-    fn process(&mut self) {
-        
+    fn process_range(&mut self, start: usize, end: usize) {
         self.mixer = 0.8 * self.mixer + 0.2 * self.shape.get_value();
         let mix_square = 1.0 - self.mixer.clamp(0.0, 1.0);
         let mix_sine = self.mixer.clamp(0.0, 1.0) - (self.mixer - 1.0).clamp(0.0, 1.0);
         let mix_triangle = (self.mixer - 1.0).clamp(0.0, 1.0);
 
-        for i in 0..self.block_size {
+        for i in start..end {
             let p = self.position.floor() as usize;
             self.buffer[i] = mix_square * (0.3 * self.square[p] + 0.7 * self.prev_square) +
                 mix_triangle * (0.3 * self.triangle[p] + 0.7 * self.prev_triangle) +
@@ -142,18 +139,25 @@ impl AudioSource for WaveTable {
             self.prev_sine = self.sine[p];
 
             self.position += self.frequency;
-            if self.position > self.square.get_size() as f32 {
+            if self.position >= self.square.get_size() as f32 {
                 self.position -= self.square.get_size() as f32;
             }
         }
     }
 
-    fn set_pitch(&mut self, midi_note: u8) {
-        self.frequency = (self.sample_rate / TABLE_FREQUENCY) / mtof_detune(127.0-(midi_note as f32 + 2.0 + self.transpose.get_value()), self.detune.get_value());
+    fn set_pitch(&mut self, midi_note: u8, tuning: &Tuning) {
+        self.set_frequency(self.note_frequency(midi_note as f32, tuning));
     }
 
+    // The tables hold one cycle at TABLE_FREQUENCY, so the read position moves by the ratio of the two.
+    // This retuned existing patches, see the changelog.
     fn set_frequency(&mut self, frequency: f32) {
-        self.frequency = (self.sample_rate / TABLE_FREQUENCY) / frequency;
+        let table_rate = self.sine.get_size() as f32 * TABLE_FREQUENCY;
+        self.frequency = frequency * table_rate / self.sample_rate;
+    }
+
+    fn note_frequency(&self, midi_note: f32, tuning: &Tuning) -> f32 {
+        tuning.detuned(midi_note + self.transpose.get_value(), self.detune.get_value())
     }
 
     fn set_block_size(&mut self, block_size: usize) {
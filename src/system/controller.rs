@@ -0,0 +1,105 @@
+// ControllerProfile
+// Named CC maps for specific hardware, stored as JSON files in the controllers folder
+// and picked automatically by MIDI port name
+
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::system::midi_map::{MidiMap, MidiMapping};
+use crate::system::util::default_path;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControllerProfile {
+    pub name: String,
+    // Parts of the MIDI port names this profile is used for, matched case-insensitively
+    #[serde(default)]
+    pub ports: Vec<String>,
+    pub mappings: Vec<MidiMapping>
+}
+
+impl ControllerProfile {
+    pub fn path() -> PathBuf {
+        default_path().join("controllers")
+    }
+
+    pub fn load<T: AsRef<Path>>(path: T) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path).with_context(|| format!("Failed to open controller profile {}", path.display()))?;
+        serde_json::from_reader(file).with_context(|| format!("Failed to parse controller profile {}", path.display()))
+    }
+
+    pub fn load_all() -> Vec<Self> {
+        Self::load_dir(Self::path())
+    }
+
+    // Loads every profile in the folder, a broken file doesn't keep the others from loading
+    pub fn load_dir<T: AsRef<Path>>(path: T) -> Vec<Self> {
+        let Ok(entries) = std::fs::read_dir(path) else {
+            return vec![];
+        };
+
+        let mut profiles: Vec<Self> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().map(|e| e == "json").unwrap_or(false))
+            .filter_map(|path| match Self::load(&path) {
+                Ok(profile) => Some(profile),
+                Err(e) => {
+                    println!("{:#}", e);
+                    None
+                }
+            })
+            .collect();
+
+        profiles.sort_by(|a, b| a.name.cmp(&b.name));
+        profiles
+    }
+
+    pub fn matches(&self, port_name: &str) -> bool {
+        let port_name = port_name.to_lowercase();
+        self.ports.iter().any(|p| !p.is_empty() && port_name.contains(&p.to_lowercase()))
+    }
+
+    pub fn find<'a>(profiles: &'a [Self], port_name: &str) -> Option<&'a Self> {
+        profiles.iter().find(|p| p.matches(port_name))
+    }
+
+    pub fn midi_map(&self) -> MidiMap {
+        MidiMap { mappings: self.mappings.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::midi_map::ValueCurve;
+    use crate::system::parameter::ParameterID;
+
+    #[test]
+    fn test_controller_profiles() {
+        let dir = std::env::temp_dir().join("donut_test_controller_profiles");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        std::fs::write(dir.join("launch_control.json"), r#"{
+            "name": "Launch Control XL",
+            "ports": ["Launch Control XL"],
+            "mappings": [
+                { "parameter": "WT1Shape", "cc": 13, "channel": 8, "curve": "Exponential" },
+                { "parameter": "KSCutoff", "cc": 29, "channel": 8, "min": 0.25, "max": 0.5 }
+            ]
+        }"#).unwrap();
+        std::fs::write(dir.join("beatstep.json"), r#"{ "name": "BeatStep", "ports": ["Arturia BeatStep"], "mappings": [] }"#).unwrap();
+        std::fs::write(dir.join("broken.json"), "{ not json").unwrap();
+
+        let profiles = ControllerProfile::load_dir(&dir);
+        assert_eq!(profiles.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), vec!["BeatStep", "Launch Control XL"]);
+
+        let profile = ControllerProfile::find(&profiles, "Launch Control XL:Launch Control XL MIDI 1 20:0").unwrap();
+        assert_eq!(profile.name, "Launch Control XL");
+        assert_eq!(profile.mappings[0].curve, ValueCurve::Exponential);
+        assert_eq!(profile.midi_map().resolve(8, 29, 127), vec![(ParameterID::KSCutoff, 0.5)]);
+
+        assert!(ControllerProfile::find(&profiles, "IAC Driver Bus 1").is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
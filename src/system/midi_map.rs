@@ -0,0 +1,228 @@
+// MidiMap
+// User assignments of MIDI CCs to parameters, stored as midi_map.json in the Donut folder
+
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::system::parameter::ParameterID;
+use crate::system::util::default_path;
+
+fn default_max() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum ValueCurve {
+    #[default]
+    Linear,
+    // Finer control at the low end
+    Exponential,
+    // Finer control at the high end
+    Logarithmic
+}
+
+impl ValueCurve {
+    pub fn all() -> &'static [ValueCurve] {
+        &[ValueCurve::Linear, ValueCurve::Exponential, ValueCurve::Logarithmic]
+    }
+
+    pub fn apply(&self, value: f32) -> f32 {
+        match self {
+            ValueCurve::Linear => value,
+            ValueCurve::Exponential => value * value,
+            ValueCurve::Logarithmic => value.sqrt()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MidiMapping {
+    pub parameter: ParameterID,
+    pub cc: u8,
+    // None listens on every channel
+    #[serde(default)]
+    pub channel: Option<u8>,
+    // Normalized range the CC sweeps over
+    #[serde(default)]
+    pub min: f32,
+    #[serde(default = "default_max")]
+    pub max: f32,
+    #[serde(default)]
+    pub inverted: bool,
+    #[serde(default)]
+    pub curve: ValueCurve
+}
+
+impl MidiMapping {
+    pub fn new(parameter: ParameterID, cc: u8) -> Self {
+        Self {
+            parameter,
+            cc,
+            channel: None,
+            min: 0.0,
+            max: 1.0,
+            inverted: false,
+            curve: ValueCurve::Linear
+        }
+    }
+
+    pub fn accepts(&self, channel: u8, cc: u8) -> bool {
+        self.cc == cc && self.channel.map(|c| c == channel).unwrap_or(true)
+    }
+
+    // Whether both mappings would respond to the same controller
+    pub fn overlaps(&self, other: &MidiMapping) -> bool {
+        self.cc == other.cc && match (self.channel, other.channel) {
+            (Some(a), Some(b)) => a == b,
+            _ => true
+        }
+    }
+
+    pub fn value(&self, cc_value: u8) -> f32 {
+        let mut value = cc_value.min(127) as f32 / 127.0;
+        if self.inverted {
+            value = 1.0 - value;
+        }
+        value = self.curve.apply(value);
+
+        self.min + (self.max - self.min) * value
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MidiMap {
+    pub mappings: Vec<MidiMapping>
+}
+
+impl MidiMap {
+    // The assignments the modules used to hard-code
+    pub fn default_mappings() -> Self {
+        use ParameterID::*;
+
+        let assignments = [
+            (WS1Harmonics, 21), (WS1Detune, 22),
+            (WT1Shape, 23), (WT1Detune, 24),
+            (ADSR1Attack, 25), (ADSR1Decay, 26), (ADSR1Sustain, 27), (ADSR1Release, 28),
+            (KSFeedback, 29), (KSCutoff, 30),
+            (WS1Amount, 41), (KSAmount, 42), (WT1Amount, 43)
+        ];
+
+        Self {
+            mappings: assignments.iter().map(|(id, cc)| MidiMapping::new(*id, *cc)).collect()
+        }
+    }
+
+    pub fn path() -> PathBuf {
+        default_path().join("midi_map.json")
+    }
+
+    // Falls back to the default mappings when there is no map on disk yet
+    pub fn load() -> Result<Self> {
+        let path = Self::path();
+        if !path.exists() {
+            return Ok(Self::default_mappings());
+        }
+
+        let file = std::fs::File::open(path).context("Failed to open MIDI map")?;
+        serde_json::from_reader(file).context("Failed to parse MIDI map")
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context("Failed to create the Donut folder")?;
+        }
+
+        let file = std::fs::File::create(path).context("Failed to create MIDI map")?;
+        serde_json::to_writer_pretty(file, self).context("Failed to write MIDI map")
+    }
+
+    pub fn get(&self, parameter: ParameterID) -> Option<&MidiMapping> {
+        self.mappings.iter().find(|m| m.parameter == parameter)
+    }
+
+    // Each parameter listens to one CC and each CC drives one parameter, setting a mapping
+    // replaces the one the parameter had and any other mapping on the same controller
+    pub fn set(&mut self, mapping: MidiMapping) {
+        self.mappings.retain(|m| m.parameter == mapping.parameter || !m.overlaps(&mapping));
+
+        match self.mappings.iter_mut().find(|m| m.parameter == mapping.parameter) {
+            Some(existing) => *existing = mapping,
+            None => self.mappings.push(mapping)
+        }
+    }
+
+    pub fn learn(&mut self, parameter: ParameterID, channel: u8, cc: u8) {
+        let mut mapping = self.get(parameter).cloned().unwrap_or(MidiMapping::new(parameter, cc));
+        mapping.cc = cc;
+        mapping.channel = Some(channel);
+
+        self.set(mapping);
+    }
+
+    pub fn remove(&mut self, parameter: ParameterID) {
+        self.mappings.retain(|m| m.parameter != parameter);
+    }
+
+    // Combines two maps. The other map wins for parameters and controllers both of them assign,
+    // so one knob never ends up driving mappings from both.
+    pub fn merged(&self, overrides: &MidiMap) -> MidiMap {
+        let mut mappings: Vec<MidiMapping> = self.mappings.iter()
+            .filter(|m| !overrides.mappings.iter().any(|o| o.parameter == m.parameter || o.overlaps(m)))
+            .cloned()
+            .collect();
+        mappings.extend(overrides.mappings.iter().cloned());
+
+        MidiMap { mappings }
+    }
+
+    pub fn resolve(&self, channel: u8, cc: u8, value: u8) -> Vec<(ParameterID, f32)> {
+        self.mappings.iter()
+            .filter(|m| m.accepts(channel, cc))
+            .map(|m| (m.parameter, m.value(value)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_midi_map() {
+        let mut map = MidiMap::default_mappings();
+        assert_eq!(map.resolve(0, 21, 127), vec![(ParameterID::WS1Harmonics, 1.0)]);
+        assert!(map.mappings.iter().enumerate().all(|(i, a)| map.mappings[i + 1..].iter().all(|b| !a.overlaps(b))));
+
+        map.learn(ParameterID::KSFeedback, 2, 70);
+        map.set(MidiMapping { min: 0.25, max: 0.75, inverted: true, ..map.get(ParameterID::KSFeedback).unwrap().clone() });
+
+        assert_eq!(map.resolve(0, 21, 127), vec![(ParameterID::WS1Harmonics, 1.0)]);
+        // Learned on channel 3 only
+        assert!(map.resolve(0, 70, 0).is_empty());
+        assert_eq!(map.resolve(2, 70, 0), vec![(ParameterID::KSFeedback, 0.75)]);
+        assert_eq!(map.resolve(2, 70, 127), vec![(ParameterID::KSFeedback, 0.25)]);
+
+        let json = serde_json::to_string(&map).unwrap();
+        assert_eq!(serde_json::from_str::<MidiMap>(&json).unwrap(), map);
+
+        let curved = MidiMapping { curve: ValueCurve::Exponential, ..MidiMapping::new(ParameterID::WT1Shape, 74) };
+        assert!((curved.value(64) - (64.0f32 / 127.0).powi(2)).abs() < 1e-6);
+
+        let profile = MidiMap { mappings: vec![MidiMapping::new(ParameterID::WT1Shape, 21)] };
+        let merged = map.merged(&profile);
+        assert_eq!(merged.get(ParameterID::KSFeedback).unwrap().cc, 70);
+        assert_eq!(merged.get(ParameterID::WT1Shape).unwrap().cc, 21);
+        // CC 21 belongs to the profile now
+        assert_eq!(merged.get(ParameterID::WS1Harmonics), None);
+
+        // Learning a controller takes it away from the parameter that had it
+        map.learn(ParameterID::WT1Detune, 2, 70);
+        assert_eq!(map.resolve(2, 70, 127), vec![(ParameterID::WT1Detune, 1.0)]);
+        assert_eq!(map.get(ParameterID::KSFeedback), None);
+
+        // Hand written maps only need the essentials
+        let map: MidiMap = serde_json::from_str(r#"{ "mappings": [{ "parameter": "WT1Shape", "cc": 74 }] }"#).unwrap();
+        assert_eq!(map.resolve(9, 74, 127), vec![(ParameterID::WT1Shape, 1.0)]);
+    }
+}
@@ -2,4 +2,8 @@ pub mod parameter;
 pub mod library;
 pub mod util;
 pub mod dev;
-pub mod preset;
\ No newline at end of file
+pub mod preset;
+pub mod midi_map;
+pub mod controller;
+pub mod tuning;
+pub mod velocity;
\ No newline at end of file
@@ -92,12 +92,15 @@ pub enum ParameterID {
 pub struct Parameter {
     pub(crate) id: ParameterID,
     module_id: Uuid,
-    midi_id: u8,
     voice_id: usize,
     value: f32,
     base_value: f32,
     min: f32,
     max: f32,
+
+    // Normalized value as set by the user, and the per-voice modulation on top of it
+    normalized: f32,
+    modulation: f32
 }
 
 impl Parameter {
@@ -136,10 +139,12 @@ impl Parameter {
             module_id,
             value,
             base_value,
-            midi_id: 0,
             voice_id,
             min: range.0,
-            max: range.1
+            max: range.1,
+
+            normalized: ((value - range.0) / (range.1 - range.0)).clamp(0.0, 1.0),
+            modulation: 0.0
         }
     }
 
@@ -147,19 +152,36 @@ impl Parameter {
         id.eq(&self.id)
     }
 
-    pub fn accepts_cc(&self, cc: u8) -> bool {
-        cc.eq(&self.midi_id)
-    }
-    
-    pub fn assign_cc(&mut self, cc: u8) {
-        self.midi_id = cc;
-    }
-    
     pub fn get_value(&self) -> f32 {
         self.value
     }
+
+    // The value that was set, without modulation
+    pub fn get_normalized(&self) -> f32 {
+        self.normalized
+    }
     
     pub fn set_value(&mut self, value: f32) {
+        self.normalized = value;
+        self.update();
+    }
+
+    // Offset in normalized units, added to the value that was set
+    pub fn set_modulation(&mut self, modulation: f32) {
+        if modulation == self.modulation {
+            return;
+        }
+
+        self.modulation = modulation;
+        self.update();
+    }
+
+    fn update(&mut self) {
+        let mut value = self.normalized;
+        if self.modulation != 0.0 {
+            value = (value + self.modulation).clamp(0.0, 1.0);
+        }
+
         self.value = (value * (self.max - self.min)) + self.min;
     }
 }
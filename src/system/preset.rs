@@ -1,8 +1,11 @@
+use std::path::Path;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use crate::system::velocity::VelocityCurve;
 
 const DONUT_VERSION: usize = 100;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PresetModLink {
     pub amount: f32,
     pub destination: String,
@@ -10,7 +13,7 @@ pub struct PresetModLink {
     pub voice: usize
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PresetParameter {
     pub base_value: f32,
     pub key: String,
@@ -18,12 +21,12 @@ pub struct PresetParameter {
     pub voice: usize
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PresetSample {
     pub name: String
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PresetSamplerRegion {
     pub key_end: u8,
     pub key_start: u8,
@@ -34,18 +37,32 @@ pub struct PresetSamplerRegion {
     pub smp_start: usize
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// Scala files, paths are relative to the preset
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PresetTuning {
+    pub scale: String,
+    #[serde(default)]
+    pub mapping: Option<String>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DonutVersion {
     pub value: usize
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Preset {
     pub donut_version: DonutVersion,
     pub mod_links: Vec<PresetModLink>,
     pub parameters: Vec<PresetParameter>,
     pub sample_lib: Vec<PresetSample>,
-    pub sampler_regions: Vec<PresetSamplerRegion>
+    pub sampler_regions: Vec<PresetSamplerRegion>,
+    // 12-TET when there is none
+    #[serde(default)]
+    pub tuning: Option<PresetTuning>,
+    // The global curve when there is none
+    #[serde(default)]
+    pub velocity_curve: Option<VelocityCurve>
 }
 
 impl Preset {
@@ -55,10 +72,17 @@ impl Preset {
             mod_links: vec![],
             parameters: vec![],
             sample_lib: vec![],
-            sampler_regions: vec![]
+            sampler_regions: vec![],
+            tuning: None,
+            velocity_curve: None
         }
     }
 
+    pub fn load<T: AsRef<Path>>(path: T) -> Result<Self> {
+        let file = std::fs::File::open(path).context("Failed to open preset")?;
+        serde_json::from_reader(file).context("Failed to parse preset")
+    }
+
     pub fn from_state(state: &serde_json::Value) -> Self {
         let mut preset = Preset::new();
 
@@ -108,6 +132,18 @@ impl Preset {
             }
         }
 
+        // An empty scale is 12-TET
+        if let Some(tuning) = state.get("tuning").filter(|t| !t["scale"].as_str().unwrap_or("").is_empty()) {
+            let scale = tuning.get("scale").unwrap().as_str().unwrap().to_string();
+            let mapping = tuning.get("mapping").and_then(|m| m.as_str()).filter(|m| !m.is_empty()).map(|m| m.to_string());
+
+            preset.tuning = Some(PresetTuning { scale, mapping });
+        }
+
+        if let Some(curve) = state.get("velocity_curve") {
+            preset.velocity_curve = serde_json::from_value(curve.clone()).ok();
+        }
+
         preset
     }
 }
\ No newline at end of file
@@ -0,0 +1,345 @@
+// Tuning
+// Frequency tables for every MIDI note, built from Scala scale and keyboard mapping files
+// or retuned over MIDI with the MIDI Tuning Standard
+
+use std::path::Path;
+use anyhow::{bail, Context, Result};
+use crate::dsp::util::mtof;
+
+const NOTES: usize = 128;
+// MTS frequency data that leaves a note alone
+const MTS_NO_CHANGE: [u8; 3] = [0x7F, 0x7F, 0x7F];
+
+// A Scala scale: the pitch of every degree above the 1/1 in cents, the last one is the period
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scale {
+    pub description: String,
+    pub cents: Vec<f64>
+}
+
+impl Scale {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut lines = text.lines().map(|l| l.trim_end()).filter(|l| !l.starts_with('!'));
+
+        let description = lines.next().context("Scale has no description")?.trim().to_string();
+        let count: usize = lines.next().context("Scale has no note count")?
+            .split_whitespace().next().unwrap_or("")
+            .parse().context("Invalid note count")?;
+
+        let mut cents = vec![];
+        for line in lines.filter(|l| !l.trim().is_empty()).take(count) {
+            let pitch = line.split_whitespace().next().unwrap_or("");
+            cents.push(Self::parse_pitch(pitch).with_context(|| format!("Invalid pitch: {}", pitch))?);
+        }
+
+        if cents.len() != count || count == 0 {
+            bail!("Scale should have {} notes, found {}", count, cents.len());
+        }
+
+        Ok(Self { description, cents })
+    }
+
+    // Cents have a period in them, anything else is a ratio or a whole number
+    fn parse_pitch(pitch: &str) -> Result<f64> {
+        if pitch.contains('.') {
+            return Ok(pitch.parse()?);
+        }
+
+        let (numerator, denominator) = pitch.split_once('/').unwrap_or((pitch, "1"));
+        let ratio = numerator.parse::<f64>()? / denominator.parse::<f64>()?;
+        if ratio <= 0.0 || !ratio.is_finite() {
+            bail!("Ratio should be positive");
+        }
+
+        Ok(1200.0 * ratio.log2())
+    }
+
+    // Pitch of any degree, counting from the 1/1 and repeating every period
+    pub fn degree_cents(&self, degree: i32) -> f64 {
+        let size = self.cents.len() as i32;
+        let period = self.cents[self.cents.len() - 1];
+        let step = degree.rem_euclid(size);
+
+        let base = if step == 0 { 0.0 } else { self.cents[step as usize - 1] };
+        degree.div_euclid(size) as f64 * period + base
+    }
+}
+
+// A Scala keyboard mapping: which scale degree every key plays and where the reference pitch is
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyboardMapping {
+    pub first_note: u8,
+    pub last_note: u8,
+    // Key the 1/1 of the scale is on
+    pub middle_note: u8,
+    pub reference_note: u8,
+    pub reference_frequency: f64,
+    // Degree the mapping repeats at, 0 is the period of the scale
+    pub octave_degree: usize,
+    // Degree of every key in one repetition of the mapping, None for unmapped keys.
+    // Empty maps the keys one to one onto the degrees.
+    pub mapping: Vec<Option<i32>>
+}
+
+impl Default for KeyboardMapping {
+    fn default() -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_frequency: 440.0,
+            octave_degree: 0,
+            mapping: vec![]
+        }
+    }
+}
+
+impl KeyboardMapping {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut values = text.lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.starts_with('!') && !l.is_empty())
+            .map(|l| l.split_whitespace().next().unwrap_or(""));
+
+        let mut next = |name: &str| values.next().with_context(|| format!("Keyboard mapping has no {}", name));
+
+        let size: usize = next("map size")?.parse().context("Invalid map size")?;
+        let first_note = next("first note")?.parse().context("Invalid first note")?;
+        let last_note = next("last note")?.parse().context("Invalid last note")?;
+        let middle_note = next("middle note")?.parse().context("Invalid middle note")?;
+        let reference_note = next("reference note")?.parse().context("Invalid reference note")?;
+        let reference_frequency = next("reference frequency")?.parse().context("Invalid reference frequency")?;
+        let octave_degree = next("octave degree")?.parse().context("Invalid octave degree")?;
+
+        let mut mapping = vec![];
+        for _ in 0..size {
+            // Files may leave out the unmapped keys at the end
+            let key = values.next().unwrap_or("x");
+            mapping.push(match key {
+                "x" | "X" => None,
+                degree => Some(degree.parse().with_context(|| format!("Invalid mapping entry: {}", degree))?)
+            });
+        }
+
+        Ok(Self { first_note, last_note, middle_note, reference_note, reference_frequency, octave_degree, mapping })
+    }
+
+    // Scale degree a key plays, None if it isn't mapped
+    fn degree(&self, key: u8, scale: &Scale) -> Option<i32> {
+        let offset = key as i32 - self.middle_note as i32;
+        if self.mapping.is_empty() {
+            return Some(offset);
+        }
+
+        let size = self.mapping.len() as i32;
+        let octave_degree = if self.octave_degree == 0 { scale.cents.len() } else { self.octave_degree } as i32;
+        let degree = self.mapping[offset.rem_euclid(size) as usize]?;
+
+        Some(offset.div_euclid(size) * octave_degree + degree)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    pub name: String,
+    frequencies: [f32; NOTES]
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::equal()
+    }
+}
+
+impl Tuning {
+    // 12-tone equal temperament at A4 = 440Hz
+    pub fn equal() -> Self {
+        let mut frequencies = [0.0; NOTES];
+        for (note, frequency) in frequencies.iter_mut().enumerate() {
+            *frequency = mtof(note as f32);
+        }
+
+        Self { name: "12-TET".to_string(), frequencies }
+    }
+
+    // Unmapped keys keep their 12-TET pitch
+    pub fn from_scale(scale: &Scale, mapping: &KeyboardMapping) -> Self {
+        let mut tuning = Self::equal();
+        tuning.name = scale.description.clone();
+
+        let reference_degree = mapping.degree(mapping.reference_note, scale)
+            .unwrap_or(mapping.reference_note as i32 - mapping.middle_note as i32);
+        let reference_cents = scale.degree_cents(reference_degree);
+
+        for key in mapping.first_note..=mapping.last_note.min(NOTES as u8 - 1) {
+            if let Some(degree) = mapping.degree(key, scale) {
+                let cents = scale.degree_cents(degree) - reference_cents;
+                tuning.frequencies[key as usize] = (mapping.reference_frequency * 2.0f64.powf(cents / 1200.0)) as f32;
+            }
+        }
+
+        tuning
+    }
+
+    pub fn load<T: AsRef<Path>>(scale: T, mapping: Option<T>) -> Result<Self> {
+        let scale = scale.as_ref();
+        let text = std::fs::read_to_string(scale).with_context(|| format!("Failed to open scale {}", scale.display()))?;
+        let scale = Scale::parse(&text).with_context(|| format!("Failed to parse scale {}", scale.display()))?;
+
+        let mapping = match mapping {
+            Some(path) => {
+                let path = path.as_ref();
+                let text = std::fs::read_to_string(path).with_context(|| format!("Failed to open keyboard mapping {}", path.display()))?;
+                KeyboardMapping::parse(&text).with_context(|| format!("Failed to parse keyboard mapping {}", path.display()))?
+            },
+            None => KeyboardMapping::default()
+        };
+
+        Ok(Self::from_scale(&scale, &mapping))
+    }
+
+    // Frequency of a fractional note, interpolated between the keys around it.
+    // Outside the keyboard the steps are 12-TET.
+    pub fn frequency(&self, midi_note: f32) -> f32 {
+        let note = midi_note.clamp(0.0, (NOTES - 1) as f32);
+        let low = note.floor() as usize;
+        let high = (low + 1).min(NOTES - 1);
+
+        let (from, to) = (self.frequencies[low], self.frequencies[high]);
+        from * (to / from).powf(note - low as f32) * 2.0f32.powf((midi_note - note) / 12.0)
+    }
+
+    // Same as frequency, with the reference pitch moved away from 440Hz
+    pub fn detuned(&self, midi_note: f32, reference: f32) -> f32 {
+        self.frequency(midi_note) * reference / 440.0
+    }
+
+    // Applies a MIDI Tuning Standard message, given the SysEx payload without F0 and F7.
+    // Tuning programs and banks are ignored, every change goes to the table in use.
+    // Returns whether the message was a tuning change.
+    pub fn apply_mts(&mut self, data: &[u8]) -> bool {
+        let (Some(0x7E | 0x7F), Some(0x08), Some(&format)) = (data.first(), data.get(2), data.get(3)) else {
+            return false;
+        };
+
+        match format {
+            // Bulk dump: program, 16 character name, then every key
+            0x01 if data.len() >= 22 + NOTES * 3 => {
+                self.name = String::from_utf8_lossy(&data[5..21]).trim().to_string();
+                for (key, frequency) in data[21..21 + NOTES * 3].chunks(3).enumerate() {
+                    self.set_mts_frequency(key as u8, frequency);
+                }
+            },
+            // Single note tuning change, the second form also names a bank
+            0x02 | 0x07 => {
+                let start = if format == 0x02 { 5 } else { 6 };
+                let Some(&count) = data.get(start) else {
+                    return false;
+                };
+
+                for change in data[start + 1..].chunks_exact(4).take(count as usize) {
+                    self.set_mts_frequency(change[0], &change[1..]);
+                }
+                self.name = "MTS".to_string();
+            },
+            // Scale/octave tuning, an offset for every pitch class in 1 or 2 byte resolution
+            0x08 if data.len() >= 7 + 12 => {
+                let offsets: Vec<f32> = data[7..19].iter().map(|v| *v as f32 - 64.0).collect();
+                self.set_octave_offsets(&offsets);
+            },
+            0x09 if data.len() >= 7 + 24 => {
+                let offsets: Vec<f32> = data[7..31].chunks(2)
+                    .map(|v| (((v[0] as u16) << 7 | v[1] as u16) as f32 - 8192.0) * 100.0 / 8192.0)
+                    .collect();
+                self.set_octave_offsets(&offsets);
+            },
+            _ => return false
+        }
+
+        true
+    }
+
+    // Semitone, then the fraction of the semitone above it in 14 bits
+    fn set_mts_frequency(&mut self, key: u8, data: &[u8]) {
+        if key as usize >= NOTES || data == MTS_NO_CHANGE {
+            return;
+        }
+
+        let fraction = ((data[1] as u16) << 7 | data[2] as u16) as f32 / 16384.0;
+        self.frequencies[key as usize] = mtof(data[0] as f32 + fraction);
+    }
+
+    // Cents away from 12-TET for C up to B, in every octave
+    fn set_octave_offsets(&mut self, offsets: &[f32]) {
+        for (note, frequency) in self.frequencies.iter_mut().enumerate() {
+            *frequency = mtof(note as f32 + offsets[note % 12] / 100.0);
+        }
+        self.name = "MTS".to_string();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCALE: &str = "! just.scl\n!\n5-limit just intonation\n 12\n!\n16/15\n9/8\n6/5\n5/4\n4/3\n45/32\n3/2\n8/5\n5/3\n9/5\n15/8\n2/1\n";
+
+    #[test]
+    fn test_scala() {
+        let equal = Tuning::equal();
+        assert!((equal.frequency(69.0) - 440.0).abs() < 1e-3);
+        assert!((equal.frequency(69.5) - mtof(69.5)).abs() < 1e-2);
+
+        let scale = Scale::parse(SCALE).unwrap();
+        assert_eq!(scale.description, "5-limit just intonation");
+        assert_eq!(scale.cents.len(), 12);
+        assert!((scale.degree_cents(7) - 701.955).abs() < 1e-3);
+        assert!((scale.degree_cents(-5) - (701.955 - 1200.0)).abs() < 1e-3);
+
+        // Middle C is the 1/1, A above it is a just major sixth at 440Hz
+        let tuning = Tuning::from_scale(&scale, &KeyboardMapping::default());
+        assert!((tuning.frequency(69.0) - 440.0).abs() < 1e-3);
+        assert!((tuning.frequency(60.0) - 264.0).abs() < 1e-3);
+        assert!((tuning.frequency(67.0) - 396.0).abs() < 1e-3);
+        assert!((tuning.frequency(72.0) - 528.0).abs() < 1e-3);
+
+        // Only the white keys, a 7 note scale over 12 keys
+        let mapping = KeyboardMapping::parse("! white.kbm\n12\n0\n127\n60\n69\n440.0\n7\n0\n x\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n").unwrap();
+        assert_eq!(mapping.mapping[1], None);
+        let diatonic = Scale::parse("Diatonic\n7\n9/8\n5/4\n4/3\n3/2\n5/3\n15/8\n2/1").unwrap();
+        let tuning = Tuning::from_scale(&diatonic, &mapping);
+        assert!((tuning.frequency(64.0) - 330.0).abs() < 1e-3);
+        assert!((tuning.frequency(61.0) - mtof(61.0)).abs() < 1e-3);
+
+        assert!(Scale::parse("Broken\n3\n9/8\n").is_err());
+    }
+
+    #[test]
+    fn test_mts() {
+        let mut tuning = Tuning::equal();
+
+        // Real-time single note change: A4 a quarter tone up
+        assert!(tuning.apply_mts(&[0x7F, 0x7F, 0x08, 0x02, 0, 1, 69, 69, 0x40, 0x00]));
+        assert!((tuning.frequency(69.0) - mtof(69.5)).abs() < 1e-2);
+        assert_eq!(tuning.name, "MTS");
+
+        let mut dump = vec![0x7E, 0x00, 0x08, 0x01, 0];
+        dump.extend_from_slice(b"Quarter sharp   ");
+        for key in 0..128u8 {
+            dump.extend_from_slice(&[key, 0x40, 0x00]);
+        }
+        dump.push(0);
+        assert!(tuning.apply_mts(&dump));
+        assert_eq!(tuning.name, "Quarter sharp");
+        assert!((tuning.frequency(60.0) - mtof(60.5)).abs() < 1e-2);
+
+        // Every E 14 cents flat
+        let mut octave = vec![0x7E, 0x7F, 0x08, 0x08, 0x03, 0x7F, 0x7F];
+        octave.extend((0..12).map(|n| if n == 4 { 64 - 14 } else { 64 }));
+        assert!(tuning.apply_mts(&octave));
+        assert!((tuning.frequency(64.0) - mtof(63.86)).abs() < 1e-2);
+
+        assert!(!tuning.apply_mts(&[0x7E, 0x7F, 0x06, 0x01]));
+    }
+}
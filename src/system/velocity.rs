@@ -0,0 +1,80 @@
+// VelocityCurve
+// Maps how hard a note was played to how much of the envelope, and of the Velocity mod source, it gets
+
+use serde::{Deserialize, Serialize};
+use crate::system::midi_map::ValueCurve;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum VelocityCurve {
+    Shape(ValueCurve),
+    // (velocity, amount) points, both 0..1, with straight lines in between
+    Breakpoints(Vec<(f32, f32)>)
+}
+
+impl Default for VelocityCurve {
+    // The square root the envelope always used
+    fn default() -> Self {
+        VelocityCurve::Shape(ValueCurve::Logarithmic)
+    }
+}
+
+impl VelocityCurve {
+    pub fn apply(&self, velocity: u8) -> f32 {
+        let velocity = velocity.min(127) as f32 / 127.0;
+
+        match self {
+            VelocityCurve::Shape(curve) => curve.apply(velocity),
+            VelocityCurve::Breakpoints(points) => Self::interpolate(points, velocity)
+        }
+    }
+
+    // Flat before the first and after the last point
+    fn interpolate(points: &[(f32, f32)], velocity: f32) -> f32 {
+        let Some(first) = points.first() else {
+            return velocity;
+        };
+        if velocity <= first.0 {
+            return first.1.clamp(0.0, 1.0);
+        }
+
+        for pair in points.windows(2) {
+            let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+            if velocity <= x1 {
+                let position = if x1 > x0 { (velocity - x0) / (x1 - x0) } else { 1.0 };
+                return (y0 + (y1 - y0) * position).clamp(0.0, 1.0);
+            }
+        }
+
+        points[points.len() - 1].1.clamp(0.0, 1.0)
+    }
+
+    // Breakpoints that follow this curve, as a starting point for editing
+    pub fn to_breakpoints(&self, count: usize) -> Vec<(f32, f32)> {
+        (0..count.max(2))
+            .map(|i| i as f32 / (count.max(2) - 1) as f32)
+            .map(|x| (x, self.apply((x * 127.0).round() as u8)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_velocity_curves() {
+        assert!((VelocityCurve::default().apply(64) - (64.0f32 / 127.0).sqrt()).abs() < 1e-6);
+        assert_eq!(VelocityCurve::Shape(ValueCurve::Linear).apply(127), 1.0);
+        assert_eq!(VelocityCurve::Shape(ValueCurve::Exponential).apply(0), 0.0);
+
+        // Nothing below a threshold, then full range above it
+        let curve = VelocityCurve::Breakpoints(vec![(0.2, 0.0), (0.6, 1.0)]);
+        assert_eq!(curve.apply(10), 0.0);
+        assert!((curve.apply(51) - (51.0 / 127.0 - 0.2) / 0.4).abs() < 1e-5);
+        assert_eq!(curve.apply(100), 1.0);
+
+        let points = VelocityCurve::Shape(ValueCurve::Linear).to_breakpoints(5);
+        assert_eq!(points.len(), 5);
+        assert_eq!(points[4], (1.0, 1.0));
+    }
+}